@@ -0,0 +1,642 @@
+use crate::entities::announce_list::AnnounceList;
+use crate::entities::choker::{Choker, DEFAULT_OPTIMISTIC_SLOTS, DEFAULT_UPLOAD_SLOTS};
+use crate::entities::create_config::{CreateConfig, TorrentLayout};
+use crate::entities::download_config::DownloadConfig;
+use crate::entities::magnet::Magnet;
+use crate::entities::peer::{Peer, DEFAULT_PORT};
+use crate::entities::piece_picker::PiecePriority;
+use crate::entities::storage::Storage;
+use crate::entities::torrent::{Torrent, TorrentInfo};
+use crate::entities::transfer_stats::TransferStats;
+use crate::usecases::announcer::Announcer;
+use crate::usecases::create_torrent::{create_torrent, encode_torrent};
+use crate::usecases::dht::{announce_torrent, resolve_nodes, Dht, DEFAULT_BOOTSTRAP_NODES};
+use crate::usecases::download_torrent::download_torrent;
+use crate::usecases::fast_resume::load_verified_pieces;
+use crate::usecases::fetch_metadata::fetch_metadata;
+use crate::usecases::filesystem_storage::FilesystemStorage;
+use crate::usecases::local_discovery::{announce_locally, LocalDiscovery};
+use crate::usecases::parse_torrent_file::{parse_torrent_file, print_torrent_info};
+use crate::usecases::peer_tracker::{discover_peers, discover_peers_for_magnet, scrape_tiers};
+use crate::usecases::perform_handshake::perform_handshake;
+use crate::usecases::seeder::{bind_listener, SeededTorrent, Seeder};
+use crate::usecases::verify_torrent::{print_verify_progress, verify_torrent};
+use crate::utils::errors::{FileError, MetadataError, TorrentError};
+
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const PEER_CHANNEL_SIZE: usize = 16;
+const DHT_STATE_FILE: &str = "dht.dat";
+const CREATED_BY: &str = concat!("BitCrab/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Parser, Debug)]
+#[command(name = "bitcrab", version, about = "A BitTorrent client written in Rust")]
+pub struct Cli
+{
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command
+{
+    /// Print the metadata of a .torrent file
+    Info
+    {
+        torrent: String,
+    },
+    /// Announce to the tracker and list the returned peers
+    Peers
+    {
+        torrent: String,
+    },
+    /// Ask the trackers for seeder, leecher and completed counts
+    Scrape
+    {
+        #[arg(required = true)]
+        torrents: Vec<String>,
+    },
+    /// Perform the peer wire handshake with every peer returned by the tracker
+    Handshake
+    {
+        torrent: String,
+    },
+    /// Download the torrent content into a directory
+    Download
+    {
+        torrent: String,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// Port to accept peer connections on
+        #[arg(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Maximum number of peers to download from at once
+        #[arg(long, default_value_t = 30)]
+        max_peers: usize,
+        /// Maximum number of block requests kept outstanding per peer
+        #[arg(long, default_value_t = 16)]
+        request_queue: usize,
+        /// Number of peers unchoked for their transfer rate
+        #[arg(long, default_value_t = DEFAULT_UPLOAD_SLOTS)]
+        upload_slots: usize,
+        /// Number of peers unchoked at random
+        #[arg(long, default_value_t = DEFAULT_OPTIMISTIC_SLOTS)]
+        optimistic_slots: usize,
+        /// Do not exchange peer lists with connected peers
+        #[arg(long)]
+        no_pex: bool,
+        /// Seconds to wait for a peer while connected to none before giving up
+        #[arg(long, default_value_t = 300)]
+        idle_timeout: u64,
+        /// Priority of a file as PATH=low|normal|high, with the path `info`
+        /// prints; repeat for more files
+        #[arg(long = "priority", value_name = "PATH=LEVEL", value_parser = parse_file_priority)]
+        priorities: Vec<(String, PiecePriority)>,
+        #[command(flatten)]
+        discovery: DiscoveryArgs,
+    },
+    /// Check downloaded data against the piece hashes
+    Verify
+    {
+        torrent: String,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Serve the downloaded data to other peers until interrupted
+    Seed
+    {
+        torrent: String,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// Port to accept peer connections on
+        #[arg(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Number of peers unchoked for their transfer rate
+        #[arg(long, default_value_t = DEFAULT_UPLOAD_SLOTS)]
+        upload_slots: usize,
+        /// Number of peers unchoked at random
+        #[arg(long, default_value_t = DEFAULT_OPTIMISTIC_SLOTS)]
+        optimistic_slots: usize,
+        #[command(flatten)]
+        discovery: DiscoveryArgs,
+    },
+    /// Create a .torrent file for a file or directory
+    Create
+    {
+        path: PathBuf,
+        /// Where to write the torrent [default: <name>.torrent]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Tracker tier as comma-separated announce URLs; repeat for more tiers
+        #[arg(short, long = "announce", required = true)]
+        announce: Vec<String>,
+        /// Piece length in bytes, a power of two of at least 16 KiB
+        /// [default: derived from the content size]
+        #[arg(long)]
+        piece_length: Option<i64>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long, default_value = CREATED_BY)]
+        created_by: String,
+        /// Leave the creation date out, so the same content always gives
+        /// the same torrent
+        #[arg(long)]
+        no_date: bool,
+        /// Only accept peers from the trackers
+        #[arg(long)]
+        private: bool,
+        /// HTTP or FTP URL serving the same content; repeat for more
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<Url>,
+        /// Hashing schemes the torrent carries
+        #[arg(long, value_enum, default_value_t = TorrentLayout::V1)]
+        layout: TorrentLayout,
+    },
+}
+
+/// Peer sources besides the trackers.
+#[derive(Args, Debug)]
+struct DiscoveryArgs
+{
+    /// Do not look for peers on the DHT
+    #[arg(long)]
+    no_dht: bool,
+    /// UDP port of the DHT node
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    dht_port: u16,
+    /// DHT node to join the network through, as host:port
+    #[arg(long = "dht-bootstrap", default_values_t = DEFAULT_BOOTSTRAP_NODES.map(String::from))]
+    dht_bootstrap: Vec<String>,
+    /// Do not look for peers on the local network
+    #[arg(long)]
+    no_lsd: bool,
+}
+
+impl DiscoveryArgs
+{
+    /// BEP 27: the peers of a private torrent may only come from its
+    /// trackers, so the DHT and local discovery stay off for it.
+    fn for_torrent(mut self, torrent: &Torrent) -> Self
+    {
+        if *torrent.info().private()
+        {
+            self.no_dht = true;
+            self.no_lsd = true;
+        }
+        self
+    }
+}
+
+/// A running DHT node and the task announcing a torrent through it.
+struct DhtSession
+{
+    dht: Arc<Dht>,
+    announcer: JoinHandle<()>,
+    state_path: PathBuf,
+}
+
+impl DhtSession
+{
+    /// Starts the node unless disabled. The DHT is a fallback for the
+    /// trackers, so failing to start it is reported and otherwise ignored.
+    async fn start(
+        args: &DiscoveryArgs,
+        output: &Path,
+        info_hash: [u8; 20],
+        port: u16,
+        peer_tx: mpsc::Sender<Vec<Peer>>,
+    ) -> Option<Self>
+    {
+        if args.no_dht
+        {
+            return None;
+        }
+
+        let state_path = output.join(DHT_STATE_FILE);
+        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, args.dht_port);
+        let dht = match Dht::load(addr, &state_path).await
+        {
+            Ok(dht) => Arc::new(dht),
+            Err(e) => {
+                eprintln!("Failed to start the DHT node - Error: {}", e);
+                return None;
+            }
+        };
+
+        let bootstrap = resolve_nodes(&args.dht_bootstrap).await;
+        let announcer =
+            tokio::spawn(announce_torrent(Arc::clone(&dht), bootstrap, info_hash, port, peer_tx));
+        Some(Self { dht, announcer, state_path })
+    }
+
+    fn node(&self) -> Arc<Dht>
+    {
+        Arc::clone(&self.dht)
+    }
+
+    /// Stops announcing and saves the routing table for the next run.
+    async fn stop(self)
+    {
+        self.announcer.abort();
+
+        if let Err(e) = self.dht.save(&self.state_path).await
+        {
+            eprintln!("Failed to save the DHT nodes - Error: {}", e);
+        }
+    }
+}
+
+/// Starts announcing the torrent on the local network unless disabled.
+/// Like the DHT, failing to start it is reported and otherwise ignored.
+/// Local peers connect right after hearing the announce, so something must
+/// already accept connections on `port`.
+async fn start_local_discovery(
+    args: &DiscoveryArgs,
+    info_hash: [u8; 20],
+    port: u16,
+    peer_tx: mpsc::Sender<Vec<Peer>>,
+) -> Option<JoinHandle<()>>
+{
+    if args.no_lsd
+    {
+        return None;
+    }
+
+    match LocalDiscovery::bind().await
+    {
+        Ok(lsd) => Some(tokio::spawn(announce_locally(lsd, info_hash, port, peer_tx))),
+        Err(e) => {
+            eprintln!("Failed to start local service discovery - Error: {}", e);
+            None
+        }
+    }
+}
+
+/// Runs the parsed command, returning the process exit code on success.
+/// Every subcommand accepts either a path to a .torrent file or a magnet link.
+pub async fn run(cli: Cli) -> Result<u8, TorrentError>
+{
+    match cli.command
+    {
+        Command::Info { torrent } => {
+            let torrent = load_torrent(&torrent, None).await?;
+            print_torrent_info(&torrent).await;
+            Ok(0)
+        }
+        Command::Peers { torrent } => {
+            let tracker_response = if torrent.starts_with("magnet:")
+            {
+                discover_peers_for_magnet(&Magnet::parse(&torrent)?).await?
+            }
+            else { discover_peers(&load_torrent(&torrent, None).await?).await? };
+            println!("Interval: {}", tracker_response.interval());
+
+            for peer in tracker_response.peers()
+            {
+                println!("{}", peer);
+            }
+            Ok(0)
+        }
+        Command::Scrape { torrents } => {
+            // Torrents sharing their trackers are scraped together.
+            let mut by_trackers: HashMap<Vec<Vec<Url>>, Vec<[u8; 20]>> = HashMap::new();
+
+            for source in &torrents
+            {
+                let (announce_list, info_hash) = if source.starts_with("magnet:")
+                {
+                    let magnet = Magnet::parse(source)?;
+
+                    if magnet.trackers().is_empty()
+                    {
+                        return Err(MetadataError::FieldError("tr".to_string()).into());
+                    }
+                    (magnet.announce_list(), *magnet.info_hash())
+                }
+                else
+                {
+                    let torrent = parse_torrent_file(source).await?;
+                    (torrent.announce_list().clone(), *torrent.info_hash())
+                };
+                by_trackers.entry(announce_list.tiers().clone()).or_default().push(info_hash);
+            }
+
+            for (tiers, info_hashes) in by_trackers
+            {
+                let mut announce_list = AnnounceList::new(tiers);
+
+                for (tracker_url, responses) in
+                    scrape_tiers(&mut announce_list, &info_hashes).await?
+                {
+                    for response in responses
+                    {
+                        println!(
+                            "{} seeders={} leechers={} completed={} ({})",
+                            hex::encode(response.info_hash()),
+                            response.seeders(),
+                            response.leechers(),
+                            response.completed(),
+                            tracker_url
+                        );
+                    }
+                }
+            }
+            Ok(0)
+        }
+        Command::Handshake { torrent } => {
+            let torrent = load_torrent(&torrent, None).await?;
+            let connected_peers = connect_to_peers(&torrent).await?;
+            Ok(if connected_peers.is_empty() { 1 } else { 0 })
+        }
+        Command::Download {
+            torrent,
+            output,
+            port,
+            max_peers,
+            request_queue,
+            upload_slots,
+            optimistic_slots,
+            no_pex,
+            idle_timeout,
+            priorities,
+            discovery,
+        } => {
+            // Peers only learn the port once something listens on it.
+            let listener = bind_listener(port).await?;
+            let port = listener.local_addr()?.port();
+            let torrent = load_torrent(&torrent, Some(port)).await?;
+            let priorities = piece_priorities(torrent.info(), &priorities)?;
+            let storage = Arc::new(FilesystemStorage::new(torrent.info(), &output)?);
+            let have = load_verified_pieces(&torrent, storage.as_ref()).await?;
+
+            if !have.contains(&false)
+            {
+                storage.save_resume(torrent.info_hash(), &have).await?;
+                println!("Arquivo {} está pronto", output.join(torrent.info().name()).display());
+                return Ok(0);
+            }
+
+            let left: usize = (0..have.len())
+                .filter(|index| !have[*index])
+                .map(|index| torrent.info().piece_size(index))
+                .sum();
+            let stats = Arc::new(TransferStats::new(left as i64));
+            let discovery = discovery.for_torrent(&torrent);
+            let (peer_tx, peer_rx) = mpsc::channel(PEER_CHANNEL_SIZE);
+            let info_hash = *torrent.info_hash();
+            let dht =
+                DhtSession::start(&discovery, &output, info_hash, port, peer_tx.clone()).await;
+            let lsd = start_local_discovery(&discovery, info_hash, port, peer_tx.clone()).await;
+            let (announcer, first_announce) =
+                Announcer::start(&torrent, port, Arc::clone(&stats), peer_tx).await;
+            let tracker_peers = match first_announce
+            {
+                Ok(response) => response.peers().clone(),
+                Err(e) => {
+                    eprintln!("Tracker announce failed, retrying in the background - Error: {}", e);
+                    Vec::new()
+                }
+            };
+
+            // The download gives up once it has gone `idle_timeout` without
+            // a peer, so say where peers may still come from.
+            if tracker_peers.is_empty()
+            {
+                let mut sources = vec!["the trackers"];
+                if dht.is_some()
+                {
+                    sources.push("the DHT");
+                }
+                if lsd.is_some()
+                {
+                    sources.push("local discovery");
+                }
+                eprintln!(
+                    "No peers yet, waiting up to {}s for {}",
+                    idle_timeout,
+                    sources.join(", ")
+                );
+            }
+
+            let config = DownloadConfig::default()
+                .with_max_peers(max_peers)
+                .with_request_queue_len(request_queue)
+                .with_upload_slots(upload_slots)
+                .with_optimistic_slots(optimistic_slots)
+                .with_pex(!no_pex && !torrent.info().private())
+                .with_idle_timeout(Duration::from_secs(idle_timeout));
+            let config = priorities
+                .into_iter()
+                .fold(config, |config, (piece_index, priority)| {
+                    config.with_piece_priority(piece_index, priority)
+                });
+            let result = download_torrent(
+                &torrent,
+                &tracker_peers,
+                have,
+                storage,
+                Arc::clone(&stats),
+                peer_rx,
+                listener,
+                dht.as_ref().map(DhtSession::node),
+                config,
+            )
+            .await;
+
+            if result.is_ok()
+            {
+                announcer.completed();
+            }
+            announcer.stop().await;
+
+            if let Some(lsd) = lsd
+            {
+                lsd.abort();
+            }
+            if let Some(dht) = dht
+            {
+                dht.stop().await;
+            }
+            result?;
+
+            if stats.wasted() > 0
+            {
+                println!("Discarded {} bytes of late or duplicate blocks", stats.wasted());
+            }
+            println!("Arquivo {} está pronto", output.join(torrent.info().name()).display());
+            Ok(0)
+        }
+        Command::Verify { torrent, output } => {
+            let torrent = load_torrent(&torrent, None).await?;
+            let storage = FilesystemStorage::new(torrent.info(), &output)?;
+            let valid_pieces = verify_torrent(&torrent, &storage, print_verify_progress).await?;
+            storage.save_resume(torrent.info_hash(), &valid_pieces).await?;
+            let valid = valid_pieces.iter().filter(|valid| **valid).count();
+            println!("{}/{} pieces valid", valid, valid_pieces.len());
+            Ok(if valid == valid_pieces.len() { 0 } else { 1 })
+        }
+        Command::Seed { torrent, output, port, upload_slots, optimistic_slots, discovery } => {
+            let seeder = Seeder::bind(port).await?;
+            let port = seeder.local_port()?;
+            let torrent = load_torrent(&torrent, Some(port)).await?;
+            let storage = Arc::new(FilesystemStorage::new(torrent.info(), &output)?);
+            let have = load_verified_pieces(&torrent, storage.as_ref()).await?;
+            storage.save_resume(torrent.info_hash(), &have).await?;
+
+            let left: usize = (0..have.len())
+                .filter(|index| !have[*index])
+                .map(|index| torrent.info().piece_size(index))
+                .sum();
+            let stats = Arc::new(TransferStats::new(left as i64));
+            let discovery = discovery.for_torrent(&torrent);
+            let (peer_tx, _peer_rx) = mpsc::channel(PEER_CHANNEL_SIZE);
+            let info_hash = *torrent.info_hash();
+            let dht =
+                DhtSession::start(&discovery, &output, info_hash, port, peer_tx.clone()).await;
+
+            let seeder = seeder.with_dht(dht.as_ref().map(DhtSession::node));
+            seeder.add(
+                SeededTorrent::new(torrent.clone(), storage, have, Arc::clone(&stats)),
+                Choker::new(upload_slots, optimistic_slots),
+            );
+            let lsd = start_local_discovery(&discovery, info_hash, port, peer_tx.clone()).await;
+            let (announcer, first_announce) =
+                Announcer::start(&torrent, port, stats, peer_tx).await;
+
+            if let Err(e) = first_announce
+            {
+                eprintln!("Tracker announce failed, retrying in the background - Error: {}", e);
+            }
+            println!("Seeding {} on port {}", torrent.info().name(), port);
+
+            let result = tokio::select! {
+                result = seeder.run() => result,
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            };
+            announcer.stop().await;
+
+            if let Some(lsd) = lsd
+            {
+                lsd.abort();
+            }
+            if let Some(dht) = dht
+            {
+                dht.stop().await;
+            }
+            result?;
+            Ok(0)
+        }
+        Command::Create {
+            path,
+            output,
+            announce,
+            piece_length,
+            comment,
+            created_by,
+            no_date,
+            private,
+            web_seeds,
+            layout,
+        } => {
+            let creation_date = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs() as i64);
+            let mut config = CreateConfig::default()
+                .with_comment(comment)
+                .with_created_by(Some(created_by))
+                .with_creation_date((!no_date).then_some(creation_date))
+                .with_private(private)
+                .with_layout(layout);
+
+            for tier in &announce
+            {
+                let tier = tier.split(',').map(Url::parse).collect::<Result<Vec<_>, _>>()?;
+                config = config.with_tracker_tier(tier);
+            }
+            for web_seed in web_seeds
+            {
+                config = config.with_web_seed(web_seed);
+            }
+            if let Some(piece_length) = piece_length
+            {
+                config = config.with_piece_length(piece_length);
+            }
+
+            let torrent = create_torrent(&path, &config).await?;
+            let output = output
+                .unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.info().name())));
+            tokio::fs::write(&output, encode_torrent(&torrent)?)
+                .await
+                .map_err(FileError::IoError)?;
+            println!("Created {} ({} pieces)", output.display(), torrent.info().num_pieces());
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
+            Ok(0)
+        }
+    }
+}
+
+async fn connect_to_peers(torrent: &Torrent) -> Result<Vec<Peer>, TorrentError>
+{
+    let tracker_response = discover_peers(torrent).await?;
+    let connected_peers = perform_handshake(torrent, tracker_response.peers()).await?;
+    println!("Connected Peers:");
+
+    for peer in &connected_peers
+    {
+        println!("{}", peer);
+    }
+    Ok(connected_peers)
+}
+
+/// Loads a .torrent file, or fetches the metadata of a magnet link from its
+/// peers, telling them the `listen_port` we accept connections on, if any.
+async fn load_torrent(source: &str, listen_port: Option<u16>) -> Result<Torrent, TorrentError>
+{
+    if source.starts_with("magnet:")
+    {
+        let magnet = Magnet::parse(source)?;
+        let tracker_response = discover_peers_for_magnet(&magnet).await?;
+        fetch_metadata(&magnet, tracker_response.peers(), listen_port).await
+    }
+    else { parse_torrent_file(source).await }
+}
+
+fn parse_file_priority(value: &str) -> Result<(String, PiecePriority), String>
+{
+    let (path, level) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATH=LEVEL, got {}", value))?;
+    Ok((path.to_string(), PiecePriority::from_str(level, true)?))
+}
+
+/// Gives every piece the highest priority among the listed files it holds.
+fn piece_priorities(
+    info: &TorrentInfo,
+    priorities: &[(String, PiecePriority)],
+) -> Result<HashMap<usize, PiecePriority>, TorrentError>
+{
+    let mut pieces = HashMap::new();
+
+    for (path, priority) in priorities
+    {
+        let range = info
+            .file_pieces(path)
+            .ok_or_else(|| FileError::NotInTorrent(path.clone()))?;
+
+        for piece_index in range
+        {
+            let current = pieces.entry(piece_index).or_insert(*priority);
+            *current = (*current).max(*priority);
+        }
+    }
+    Ok(pieces)
+}
@@ -0,0 +1,53 @@
+use getset::Getters;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use reqwest::Url;
+
+/// Trackers grouped in tiers as described by BEP 12. Lower tiers are tried
+/// first and trackers that answer are moved to the front of their tier.
+#[derive(Getters, Clone, Debug)]
+pub struct AnnounceList
+{
+    #[get = "pub"]
+    tiers: Vec<Vec<Url>>,
+}
+
+impl AnnounceList
+{
+    pub fn new(tiers: Vec<Vec<Url>>) -> Self
+    {
+        Self
+        {
+            tiers: tiers.into_iter().filter(|tier| !tier.is_empty()).collect(),
+        }
+    }
+
+    /// Each tier is shuffled once when the torrent is loaded.
+    pub fn shuffled(mut self) -> Self
+    {
+        let mut rng = thread_rng();
+
+        for tier in &mut self.tiers
+        {
+            tier.shuffle(&mut rng);
+        }
+        self
+    }
+
+    pub fn promote(&mut self, tier: usize, index: usize)
+    {
+        if let Some(trackers) = self.tiers.get_mut(tier)
+        {
+            if index < trackers.len()
+            {
+                let tracker = trackers.remove(index);
+                trackers.insert(0, tracker);
+            }
+        }
+    }
+
+    pub fn trackers(&self) -> impl Iterator<Item = &Url>
+    {
+        self.tiers.iter().flatten()
+    }
+}
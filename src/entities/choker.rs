@@ -0,0 +1,165 @@
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+pub const DEFAULT_OPTIMISTIC_SLOTS: usize = 1;
+
+/// A connected peer as the choker sees it. `rate` is what ranks the peer:
+/// bytes per second downloaded from it while leeching, or uploaded to it
+/// while seeding.
+#[derive(Clone, Copy, Debug)]
+pub struct ChokeCandidate
+{
+    pub id: u64,
+    pub interested: bool,
+    pub rate: u64,
+}
+
+/// Tit-for-tat choking. The interested peers with the best rates get the
+/// regular upload slots; the optimistic slots go to other interested peers
+/// picked at random, so newcomers get a chance to prove themselves.
+#[derive(Clone, Debug)]
+pub struct Choker
+{
+    upload_slots: usize,
+    optimistic_slots: usize,
+    optimistic: Vec<u64>,
+}
+
+impl Choker
+{
+    pub fn new(upload_slots: usize, optimistic_slots: usize) -> Self
+    {
+        Self
+        {
+            upload_slots,
+            optimistic_slots,
+            optimistic: Vec::new(),
+        }
+    }
+
+    /// Returns the peers to unchoke; every other peer is choked. The
+    /// optimistic unchokes are kept between calls and only picked again
+    /// when `rotate` is set or one of them no longer qualifies.
+    pub fn unchoke(&mut self, candidates: &[ChokeCandidate], rotate: bool) -> HashSet<u64>
+    {
+        let mut interested: Vec<&ChokeCandidate> =
+            candidates.iter().filter(|candidate| candidate.interested).collect();
+        interested.sort_by_key(|candidate| std::cmp::Reverse(candidate.rate));
+
+        let mut unchoked: HashSet<u64> = interested
+            .iter()
+            .take(self.upload_slots)
+            .map(|candidate| candidate.id)
+            .collect();
+
+        let eligible: Vec<u64> = interested
+            .iter()
+            .map(|candidate| candidate.id)
+            .filter(|id| !unchoked.contains(id))
+            .collect();
+
+        if rotate
+        {
+            self.optimistic.clear();
+        }
+        self.optimistic.retain(|id| eligible.contains(id));
+
+        let open_slots = self.optimistic_slots.saturating_sub(self.optimistic.len());
+        let newcomers: Vec<u64> = eligible
+            .iter()
+            .filter(|id| !self.optimistic.contains(id))
+            .copied()
+            .collect();
+        self.optimistic
+            .extend(newcomers.choose_multiple(&mut rand::thread_rng(), open_slots));
+
+        unchoked.extend(&self.optimistic);
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// A peer as (id, interested, rate).
+    type Row = (u64, bool, u64);
+
+    fn candidates(peers: &[Row]) -> Vec<ChokeCandidate>
+    {
+        peers
+            .iter()
+            .map(|(id, interested, rate)| ChokeCandidate {
+                id: *id,
+                interested: *interested,
+                rate: *rate,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unchokes_the_best_rates_and_fills_optimistic_slots()
+    {
+        // Upload slots, optimistic slots, peers and the ones unchoked.
+        let cases: [(usize, usize, &[Row], &[u64]); 6] = [
+            (2, 0, &[(1, true, 10), (2, true, 30), (3, true, 20)], &[2, 3]),
+            (2, 0, &[(1, false, 50), (2, true, 0), (3, false, 90)], &[2]),
+            (4, 1, &[(1, true, 10), (2, true, 5)], &[1, 2]),
+            (1, 1, &[(1, true, 10), (2, true, 5)], &[1, 2]),
+            (0, 2, &[(1, true, 1), (2, true, 2), (3, false, 3)], &[1, 2]),
+            (0, 0, &[(1, true, 10)], &[]),
+        ];
+
+        for (upload_slots, optimistic_slots, peers, expected) in cases
+        {
+            let mut choker = Choker::new(upload_slots, optimistic_slots);
+            let unchoked = choker.unchoke(&candidates(peers), false);
+            let expected: HashSet<u64> = expected.iter().copied().collect();
+
+            let slots = (upload_slots, optimistic_slots);
+            assert_eq!(unchoked, expected, "slots {:?}, peers {:?}", slots, peers);
+        }
+    }
+
+    #[test]
+    fn optimistic_unchoke_stays_until_rotated()
+    {
+        let mut peers = vec![(0, true, 100)];
+        peers.extend((1..10).map(|id| (id, true, 0)));
+        let peers = candidates(&peers);
+        let mut choker = Choker::new(1, 1);
+
+        let first = choker.unchoke(&peers, false);
+        assert_eq!(first.len(), 2);
+        assert!(first.contains(&0));
+
+        for _ in 0..20
+        {
+            assert_eq!(choker.unchoke(&peers, false), first);
+        }
+
+        // Nine peers compete for the slot, so fifty rotations that all land
+        // on the same one would mean rotation does nothing.
+        let rotated = (0..50).any(|_| choker.unchoke(&peers, true) != first);
+        assert!(rotated);
+    }
+
+    #[test]
+    fn optimistic_unchoke_moves_on_when_it_no_longer_qualifies()
+    {
+        let mut choker = Choker::new(1, 1);
+        let unchoked = choker.unchoke(&candidates(&[(1, true, 50), (2, true, 0)]), false);
+        assert_eq!(unchoked, HashSet::from([1, 2]));
+
+        // Peer 2 lost interest and peer 3 arrived.
+        let peers = candidates(&[(1, true, 50), (2, false, 0), (3, true, 0)]);
+        assert_eq!(choker.unchoke(&peers, false), HashSet::from([1, 3]));
+
+        // Peer 3 earned a regular slot, so the optimistic one goes to peer 1.
+        let peers = candidates(&[(1, true, 10), (3, true, 60)]);
+        assert_eq!(choker.unchoke(&peers, false), HashSet::from([1, 3]));
+        assert_eq!(choker.optimistic, vec![1]);
+    }
+}
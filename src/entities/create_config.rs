@@ -0,0 +1,110 @@
+use getset::Getters;
+use reqwest::Url;
+
+/// Which hashing schemes a created torrent carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TorrentLayout
+{
+    V1,
+    V2,
+    /// v1 pieces over padded files plus the v2 file tree, so clients of
+    /// either version can join the same swarm.
+    Hybrid,
+}
+
+#[derive(Getters, Clone, Debug)]
+pub struct CreateConfig
+{
+    #[get = "pub"]
+    trackers: Vec<Vec<Url>>,
+    #[get = "pub"]
+    piece_length: Option<i64>,
+    #[get = "pub"]
+    comment: Option<String>,
+    #[get = "pub"]
+    created_by: Option<String>,
+    #[get = "pub"]
+    creation_date: Option<i64>,
+    #[get = "pub"]
+    private: bool,
+    #[get = "pub"]
+    web_seeds: Vec<Url>,
+    #[get = "pub"]
+    layout: TorrentLayout,
+}
+
+impl Default for CreateConfig
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            trackers: Vec::new(),
+            piece_length: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            private: false,
+            web_seeds: Vec::new(),
+            layout: TorrentLayout::V1,
+        }
+    }
+}
+
+impl CreateConfig
+{
+    /// Adds a tier of trackers. The first tracker of the first tier also
+    /// becomes the `announce` URL.
+    pub fn with_tracker_tier(mut self, tier: Vec<Url>) -> Self
+    {
+        if !tier.is_empty()
+        {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    /// Fixes the piece length instead of deriving it from the content size.
+    pub fn with_piece_length(mut self, piece_length: i64) -> Self
+    {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn with_comment(mut self, comment: Option<String>) -> Self
+    {
+        self.comment = comment;
+        self
+    }
+
+    pub fn with_created_by(mut self, created_by: Option<String>) -> Self
+    {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Seconds since the Unix epoch, or `None` to leave the date out.
+    pub fn with_creation_date(mut self, creation_date: Option<i64>) -> Self
+    {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self
+    {
+        self.private = private;
+        self
+    }
+
+    pub fn with_web_seed(mut self, web_seed: Url) -> Self
+    {
+        self.web_seeds.push(web_seed);
+        self
+    }
+
+    pub fn with_layout(mut self, layout: TorrentLayout) -> Self
+    {
+        self.layout = layout;
+        self
+    }
+}
@@ -0,0 +1,95 @@
+use crate::entities::choker::{DEFAULT_OPTIMISTIC_SLOTS, DEFAULT_UPLOAD_SLOTS};
+use crate::entities::piece_picker::PiecePriority;
+
+use getset::Getters;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Getters, Clone, Debug)]
+pub struct DownloadConfig
+{
+    #[get = "pub"]
+    max_peers: usize,
+    #[get = "pub"]
+    request_queue_len: usize,
+    #[get = "pub"]
+    priorities: HashMap<usize, PiecePriority>,
+    #[get = "pub"]
+    upload_slots: usize,
+    #[get = "pub"]
+    optimistic_slots: usize,
+    #[get = "pub"]
+    pex: bool,
+    #[get = "pub"]
+    idle_timeout: Duration,
+}
+
+impl Default for DownloadConfig
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            max_peers: 30,
+            request_queue_len: 16,
+            priorities: HashMap::new(),
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            optimistic_slots: DEFAULT_OPTIMISTIC_SLOTS,
+            pex: true,
+            idle_timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl DownloadConfig
+{
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self
+    {
+        self.max_peers = max_peers.max(1);
+        self
+    }
+
+    /// Upper bound on outstanding block requests per peer; the peer's own
+    /// `reqq` lowers it further.
+    pub fn with_request_queue_len(mut self, request_queue_len: usize) -> Self
+    {
+        self.request_queue_len = request_queue_len.max(1);
+        self
+    }
+
+    /// Overrides the priority the picker gives to one piece.
+    pub fn with_piece_priority(mut self, piece_index: usize, priority: PiecePriority) -> Self
+    {
+        self.priorities.insert(piece_index, priority);
+        self
+    }
+
+    /// Number of peers unchoked for their transfer rate.
+    pub fn with_upload_slots(mut self, upload_slots: usize) -> Self
+    {
+        self.upload_slots = upload_slots;
+        self
+    }
+
+    /// Number of peers unchoked at random on top of the upload slots.
+    pub fn with_optimistic_slots(mut self, optimistic_slots: usize) -> Self
+    {
+        self.optimistic_slots = optimistic_slots;
+        self
+    }
+
+    /// Whether peers are exchanged with the peers that support ut_pex.
+    pub fn with_pex(mut self, pex: bool) -> Self
+    {
+        self.pex = pex;
+        self
+    }
+
+    /// How long the download waits for a peer while it has none before it
+    /// gives up. Peer discovery may never find one.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self
+    {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+}
@@ -0,0 +1,268 @@
+use crate::entities::message::Message;
+use crate::utils::errors::MetadataError;
+use crate::utils::extract_torrent_metadata::{extract_dict, extract_int, extract_string};
+
+use getset::Getters;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+
+/// Extended message id reserved for the extension handshake itself.
+pub const EXTENSION_HANDSHAKE_ID: u8 = 0;
+/// Number of outstanding requests we accept, advertised as `reqq`.
+pub const MAX_INCOMING_REQUESTS: u32 = 250;
+
+#[derive(Getters, Clone, Debug, Default)]
+pub struct ExtensionHandshake
+{
+    #[get = "pub"]
+    extensions: HashMap<String, u8>,
+    #[get = "pub"]
+    client: Option<String>,
+    #[get = "pub"]
+    listen_port: Option<u16>,
+    #[get = "pub"]
+    request_queue: Option<u32>,
+    #[get = "pub"]
+    metadata_size: Option<i64>,
+}
+
+impl ExtensionHandshake
+{
+    pub fn new(extensions: HashMap<String, u8>) -> Self
+    {
+        Self
+        {
+            extensions,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_client(mut self, client: &str) -> Self
+    {
+        self.client = Some(client.to_string());
+        self
+    }
+
+    pub fn with_listen_port(mut self, listen_port: u16) -> Self
+    {
+        self.listen_port = Some(listen_port);
+        self
+    }
+
+    pub fn with_request_queue(mut self, request_queue: u32) -> Self
+    {
+        self.request_queue = Some(request_queue);
+        self
+    }
+
+    pub fn with_metadata_size(mut self, metadata_size: Option<i64>) -> Self
+    {
+        self.metadata_size = metadata_size;
+        self
+    }
+
+    /// Returns the id the remote side wants us to use for `name`; an id of
+    /// zero means the extension was disabled.
+    pub fn extension_id(&self, name: &str) -> Option<u8>
+    {
+        self.extensions.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError>
+    {
+        let value: Value = serde_bencode::from_bytes(bytes)?;
+
+        if let Value::Dict(dict) = value
+        {
+            let extensions = extract_dict("m", &dict)?
+                .into_iter()
+                .filter_map(|(name, id)| match id {
+                    Value::Int(id) => Some((String::from_utf8(name).ok()?, u8::try_from(id).ok()?)),
+                    _ => None,
+                })
+                .collect();
+
+            Ok(Self
+            {
+                extensions,
+                client: extract_string("v", &dict).ok(),
+                listen_port: extract_int("p", &dict).ok().and_then(|p| u16::try_from(p).ok()),
+                request_queue: extract_int("reqq", &dict).ok().and_then(|q| u32::try_from(q).ok()),
+                metadata_size: extract_int("metadata_size", &dict).ok(),
+            })
+        }
+        else { Err(MetadataError::IncorrectFormatError) }
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, MetadataError>
+    {
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Int(*id as i64)))
+            .collect();
+
+        let mut dict = HashMap::new();
+        dict.insert(b"m".to_vec(), Value::Dict(extensions));
+
+        if let Some(client) = &self.client
+        {
+            dict.insert(b"v".to_vec(), Value::Bytes(client.as_bytes().to_vec()));
+        }
+        if let Some(listen_port) = self.listen_port
+        {
+            dict.insert(b"p".to_vec(), Value::Int(listen_port as i64));
+        }
+        if let Some(request_queue) = self.request_queue
+        {
+            dict.insert(b"reqq".to_vec(), Value::Int(request_queue as i64));
+        }
+        if let Some(metadata_size) = self.metadata_size
+        {
+            dict.insert(b"metadata_size".to_vec(), Value::Int(metadata_size));
+        }
+        Ok(serde_bencode::to_bytes(&Value::Dict(dict))?)
+    }
+}
+
+/// A single extension negotiated through the extension handshake. Handlers
+/// return payloads to send back to the peer under the extension's remote id.
+pub trait ExtensionHandler: Send
+{
+    fn name(&self) -> &'static str;
+
+    fn metadata_size(&self) -> Option<i64>
+    {
+        None
+    }
+
+    fn on_handshake(
+        &mut self,
+        _handshake: &ExtensionHandshake,
+    ) -> Result<Vec<Vec<u8>>, MetadataError>
+    {
+        Ok(Vec::new())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, MetadataError>;
+}
+
+/// Assigns local ids to extension handlers and routes extended messages
+/// between the peer connection and the handlers.
+#[derive(Default)]
+pub struct ExtensionRegistry
+{
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    peer_handshake: Option<ExtensionHandshake>,
+    listen_port: Option<u16>,
+}
+
+impl ExtensionRegistry
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// The port we accept peer connections on, told to peers in our
+    /// handshake so they and their PEX contacts can reach us.
+    pub fn with_listen_port(mut self, listen_port: u16) -> Self
+    {
+        self.listen_port = Some(listen_port);
+        self
+    }
+
+    /// Registers a handler and returns the local id peers must use to reach it.
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) -> u8
+    {
+        self.handlers.push(handler);
+        self.handlers.len() as u8
+    }
+
+    pub fn peer_handshake(&self) -> Option<&ExtensionHandshake>
+    {
+        self.peer_handshake.as_ref()
+    }
+
+    pub fn handshake(&self, request_queue: u32) -> ExtensionHandshake
+    {
+        let extensions = self
+            .handlers
+            .iter()
+            .enumerate()
+            .map(|(index, handler)| (handler.name().to_string(), index as u8 + 1))
+            .collect();
+
+        let handshake = ExtensionHandshake::new(extensions)
+            .with_client(concat!("BitCrab ", env!("CARGO_PKG_VERSION")))
+            .with_request_queue(request_queue)
+            .with_metadata_size(self.handlers.iter().find_map(|h| h.metadata_size()));
+
+        match self.listen_port
+        {
+            Some(listen_port) => handshake.with_listen_port(listen_port),
+            None => handshake,
+        }
+    }
+
+    pub fn handshake_message(&self, request_queue: u32) -> Result<Message, MetadataError>
+    {
+        Ok(Message::Extended {
+            id: EXTENSION_HANDSHAKE_ID,
+            payload: self.handshake(request_queue).as_bytes()?,
+        })
+    }
+
+    /// Builds an extended message for `name` addressed with the id the peer
+    /// assigned, or `None` if the peer does not support that extension.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message>
+    {
+        let id = self.peer_handshake.as_ref()?.extension_id(name)?;
+        Some(Message::Extended { id, payload })
+    }
+
+    /// Dispatches an incoming extended message and returns the replies.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>, MetadataError>
+    {
+        if id == EXTENSION_HANDSHAKE_ID
+        {
+            let handshake = ExtensionHandshake::from_bytes(payload)?;
+            let mut replies = Vec::new();
+
+            for handler in &mut self.handlers
+            {
+                if let Some(remote_id) = handshake.extension_id(handler.name())
+                {
+                    replies.extend(
+                        handler
+                            .on_handshake(&handshake)?
+                            .into_iter()
+                            .map(|payload| Message::Extended { id: remote_id, payload }),
+                    );
+                }
+            }
+            self.peer_handshake = Some(handshake);
+            return Ok(replies);
+        }
+
+        let handler = match self.handlers.get_mut(id as usize - 1)
+        {
+            Some(handler) => handler,
+            None => return Ok(Vec::new()),
+        };
+        let remote_id = self
+            .peer_handshake
+            .as_ref()
+            .and_then(|handshake| handshake.extension_id(handler.name()));
+
+        let replies = handler.on_message(payload)?;
+        Ok(match remote_id
+        {
+            Some(id) => replies
+                .into_iter()
+                .map(|payload| Message::Extended { id, payload })
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,125 @@
+use crate::entities::torrent::TorrentInfo;
+use crate::utils::errors::MetadataError;
+
+use getset::Getters;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+/// A file of the torrent and the range of the piece stream it holds.
+#[derive(Getters, Clone, Debug)]
+pub struct LayoutFile
+{
+    #[get = "pub"]
+    path: PathBuf,
+    #[get = "pub"]
+    offset: u64,
+    #[get = "pub"]
+    length: u64,
+}
+
+/// The part of a read or write that falls inside one file.
+#[derive(Getters, Clone, Debug)]
+pub struct FileSpan
+{
+    #[get = "pub"]
+    file_index: usize,
+    #[get = "pub"]
+    file_offset: u64,
+    #[get = "pub"]
+    buffer_range: Range<usize>,
+}
+
+/// Maps the torrent's contiguous piece stream onto the files on disk.
+/// Single-file torrents are stored as `name`; multi-file torrents as
+/// `name/path/...`. Padding files take up their range of the stream but
+/// are never created; reads of that range find no span and stay zero.
+#[derive(Getters, Clone, Debug)]
+pub struct FileLayout
+{
+    #[get = "pub"]
+    files: Vec<LayoutFile>,
+}
+
+impl FileLayout
+{
+    pub fn new(info: &TorrentInfo, output_dir: &Path) -> Result<Self, MetadataError>
+    {
+        let name = sanitize_path(std::slice::from_ref(info.name()))?;
+
+        if info.files().is_empty()
+        {
+            let length = info.total_length() as u64;
+            let file = LayoutFile { path: output_dir.join(name), offset: 0, length };
+            return Ok(Self { files: vec![file] });
+        }
+
+        let root = output_dir.join(name);
+        let mut files = Vec::with_capacity(info.files().len());
+        let mut offset = 0;
+
+        for file in info.files()
+        {
+            let length = u64::try_from(*file.length())
+                .map_err(|_| MetadataError::FieldError("length".to_string()))?;
+
+            if *file.padding()
+            {
+                offset += length;
+                continue;
+            }
+            files.push(LayoutFile {
+                path: root.join(sanitize_path(file.path())?),
+                offset,
+                length,
+            });
+            offset += length;
+        }
+        Ok(Self { files })
+    }
+
+    /// Splits `length` bytes starting at `offset` in the piece stream into
+    /// per-file spans. Empty files never receive a span.
+    pub fn spans(&self, offset: u64, length: usize) -> Vec<FileSpan>
+    {
+        let end = offset + length as u64;
+
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.offset < end && file.offset + file.length > offset)
+            .map(|(file_index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                FileSpan {
+                    file_index,
+                    file_offset: start - file.offset,
+                    buffer_range: (start - offset) as usize..(stop - offset) as usize,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Joins path components from the metadata, rejecting anything that could
+/// escape the download directory.
+fn sanitize_path(components: &[String]) -> Result<PathBuf, MetadataError>
+{
+    let mut path = PathBuf::new();
+
+    for component in components
+    {
+        let mut parts = Path::new(component).components();
+
+        match (parts.next(), parts.next())
+        {
+            (Some(Component::Normal(part)), None) => path.push(part),
+            _ => return Err(MetadataError::FieldError("path".to_string())),
+        }
+    }
+
+    if path.as_os_str().is_empty()
+    {
+        return Err(MetadataError::FieldError("path".to_string()));
+    }
+    Ok(path)
+}
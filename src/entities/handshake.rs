@@ -1,6 +1,12 @@
 use crate::utils::extract_torrent_metadata::generate_peer_id;
 use getset::Getters;
 
+pub const HANDSHAKE_LEN: usize = 68;
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_FLAG: u8 = 0x10;
+const DHT_BYTE: usize = 7;
+const DHT_FLAG: u8 = 0x01;
+
 #[derive(Getters, Clone, Debug)]
 pub struct Handshake
 {
@@ -27,6 +33,63 @@ impl Handshake
         }
     }
 
+    /// Parses a handshake received from a peer, as the responder of an
+    /// inbound connection does before it knows which torrent is wanted.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self>
+    {
+        if bytes.len() != HANDSHAKE_LEN || bytes[0] as usize != 19
+        {
+            return None;
+        }
+
+        Some(Self
+        {
+            protocol_str: String::from_utf8(bytes[1..20].to_vec()).ok()?,
+            reserved: bytes[20..28].try_into().ok()?,
+            info_hash: bytes[28..48].try_into().ok()?,
+            peer_id: String::from_utf8_lossy(&bytes[48..68]).into_owned(),
+        })
+        .filter(|handshake| handshake.protocol_str == "BitTorrent protocol")
+    }
+
+    pub fn with_extension_protocol(mut self) -> Self
+    {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_FLAG;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool
+    {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_FLAG != 0
+    }
+
+    /// Tells the peer we run a DHT node, so it sends us its DHT port.
+    pub fn with_dht(mut self) -> Self
+    {
+        self.reserved[DHT_BYTE] |= DHT_FLAG;
+        self
+    }
+
+    pub fn is_valid_response(&self, response: &[u8]) -> bool
+    {
+        response.len() == HANDSHAKE_LEN
+            && &response[1..20] == self.protocol_str.as_bytes()
+            && response[28..48] == self.info_hash
+    }
+
+    /// The extension protocol is only used when both sides set the reserved bit.
+    pub fn negotiates_extension_protocol(&self, response: &[u8]) -> bool
+    {
+        self.supports_extension_protocol()
+            && response[20 + EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_FLAG != 0
+    }
+
+    /// Both sides run a DHT node and should send each other their port.
+    pub fn negotiates_dht(&self, response: &[u8]) -> bool
+    {
+        self.reserved[DHT_BYTE] & DHT_FLAG != 0 && response[20 + DHT_BYTE] & DHT_FLAG != 0
+    }
+
     pub fn as_bytes(&self) -> Vec<u8>
     {
         let mut bytes = Vec::new();
@@ -0,0 +1,316 @@
+use crate::entities::routing_table::{NodeId, NodeInfo};
+use crate::utils::errors::MetadataError;
+use crate::utils::extract_torrent_metadata::{
+    extract_bytes, extract_dict, extract_int, extract_list, extract_string,
+};
+
+use getset::Getters;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+type BencodeDict = HashMap<Vec<u8>, Value>;
+
+pub const ERROR_PROTOCOL: i64 = 203;
+
+/// The queries of BEP 5, without the querying node's id.
+#[derive(Clone, Debug)]
+pub enum KrpcQuery
+{
+    Ping,
+    FindNode
+    {
+        target: NodeId,
+    },
+    GetPeers
+    {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer
+    {
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl KrpcQuery
+{
+    fn method(&self) -> &'static str
+    {
+        match self
+        {
+            KrpcQuery::Ping => "ping",
+            KrpcQuery::FindNode { .. } => "find_node",
+            KrpcQuery::GetPeers { .. } => "get_peers",
+            KrpcQuery::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// Every response carries the answering node's id; the other fields
+/// depend on the query.
+#[derive(Getters, Clone, Debug)]
+pub struct KrpcResponse
+{
+    #[get = "pub"]
+    id: NodeId,
+    #[get = "pub"]
+    nodes: Vec<NodeInfo>,
+    #[get = "pub"]
+    values: Vec<SocketAddrV4>,
+    #[get = "pub"]
+    token: Option<Vec<u8>>,
+}
+
+impl KrpcResponse
+{
+    pub fn new(id: NodeId) -> Self
+    {
+        Self
+        {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+        }
+    }
+
+    pub fn with_nodes(mut self, nodes: Vec<NodeInfo>) -> Self
+    {
+        self.nodes = nodes;
+        self
+    }
+
+    pub fn with_values(mut self, values: Vec<SocketAddrV4>) -> Self
+    {
+        self.values = values;
+        self
+    }
+
+    pub fn with_token(mut self, token: Vec<u8>) -> Self
+    {
+        self.token = Some(token);
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum KrpcBody
+{
+    Query
+    {
+        id: NodeId,
+        query: KrpcQuery,
+    },
+    Response(KrpcResponse),
+    Error
+    {
+        code: i64,
+        message: String,
+    },
+}
+
+/// A KRPC message: a bencoded dictionary sent in a single UDP datagram.
+/// The transaction id pairs responses with the queries that caused them.
+#[derive(Getters, Clone, Debug)]
+pub struct KrpcMessage
+{
+    #[get = "pub"]
+    transaction_id: Vec<u8>,
+    #[get = "pub"]
+    body: KrpcBody,
+}
+
+impl KrpcMessage
+{
+    pub fn query(transaction_id: Vec<u8>, id: NodeId, query: KrpcQuery) -> Self
+    {
+        Self
+        {
+            transaction_id,
+            body: KrpcBody::Query { id, query },
+        }
+    }
+
+    pub fn response(transaction_id: Vec<u8>, response: KrpcResponse) -> Self
+    {
+        Self
+        {
+            transaction_id,
+            body: KrpcBody::Response(response),
+        }
+    }
+
+    pub fn error(transaction_id: Vec<u8>, code: i64, message: &str) -> Self
+    {
+        Self
+        {
+            transaction_id,
+            body: KrpcBody::Error { code, message: message.to_string() },
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError>
+    {
+        let value: Value = serde_bencode::from_bytes(bytes)?;
+        let Value::Dict(dict) = value else { return Err(MetadataError::IncorrectFormatError) };
+        let transaction_id = extract_bytes("t", &dict)?;
+
+        let body = match extract_string("y", &dict)?.as_str()
+        {
+            "q" => {
+                let arguments = extract_dict("a", &dict)?;
+                let id = extract_node_id("id", &arguments)?;
+                let query = parse_query(&extract_string("q", &dict)?, &arguments)?;
+                KrpcBody::Query { id, query }
+            }
+            "r" => KrpcBody::Response(parse_response(&extract_dict("r", &dict)?)?),
+            "e" => {
+                let error = extract_list("e", &dict)?;
+
+                match error.as_slice()
+                {
+                    [Value::Int(code), Value::Bytes(message), ..] => KrpcBody::Error {
+                        code: *code,
+                        message: String::from_utf8_lossy(message).into_owned(),
+                    },
+                    _ => return Err(MetadataError::FieldError("e".to_string())),
+                }
+            }
+            _ => return Err(MetadataError::FieldError("y".to_string())),
+        };
+
+        Ok(Self { transaction_id, body })
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, MetadataError>
+    {
+        let mut dict = HashMap::new();
+        dict.insert(b"t".to_vec(), Value::Bytes(self.transaction_id.clone()));
+
+        match &self.body
+        {
+            KrpcBody::Query { id, query } => {
+                let mut arguments = HashMap::new();
+                arguments.insert(b"id".to_vec(), Value::Bytes(id.as_bytes().to_vec()));
+
+                match query
+                {
+                    KrpcQuery::Ping => {}
+                    KrpcQuery::FindNode { target } => {
+                        let target = Value::Bytes(target.as_bytes().to_vec());
+                        arguments.insert(b"target".to_vec(), target);
+                    }
+                    KrpcQuery::GetPeers { info_hash } => {
+                        arguments.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+                    }
+                    KrpcQuery::AnnouncePeer { info_hash, port, implied_port, token } => {
+                        arguments.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+                        arguments.insert(b"port".to_vec(), Value::Int(*port as i64));
+                        let implied_port = Value::Int(*implied_port as i64);
+                        arguments.insert(b"implied_port".to_vec(), implied_port);
+                        arguments.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                    }
+                }
+
+                dict.insert(b"y".to_vec(), Value::Bytes(b"q".to_vec()));
+                dict.insert(b"q".to_vec(), Value::Bytes(query.method().as_bytes().to_vec()));
+                dict.insert(b"a".to_vec(), Value::Dict(arguments));
+            }
+            KrpcBody::Response(response) => {
+                let mut values = HashMap::new();
+                values.insert(b"id".to_vec(), Value::Bytes(response.id.as_bytes().to_vec()));
+
+                if !response.nodes.is_empty()
+                {
+                    let nodes = NodeInfo::to_compact(&response.nodes);
+                    values.insert(b"nodes".to_vec(), Value::Bytes(nodes));
+                }
+                if !response.values.is_empty()
+                {
+                    let peers = response.values.iter().map(|peer| Value::Bytes(compact_peer(peer)));
+                    values.insert(b"values".to_vec(), Value::List(peers.collect()));
+                }
+                if let Some(token) = &response.token
+                {
+                    values.insert(b"token".to_vec(), Value::Bytes(token.clone()));
+                }
+
+                dict.insert(b"y".to_vec(), Value::Bytes(b"r".to_vec()));
+                dict.insert(b"r".to_vec(), Value::Dict(values));
+            }
+            KrpcBody::Error { code, message } => {
+                let error = vec![Value::Int(*code), Value::Bytes(message.as_bytes().to_vec())];
+                dict.insert(b"y".to_vec(), Value::Bytes(b"e".to_vec()));
+                dict.insert(b"e".to_vec(), Value::List(error));
+            }
+        }
+        Ok(serde_bencode::to_bytes(&Value::Dict(dict))?)
+    }
+}
+
+fn parse_query(method: &str, arguments: &BencodeDict) -> Result<KrpcQuery, MetadataError>
+{
+    match method
+    {
+        "ping" => Ok(KrpcQuery::Ping),
+        "find_node" => Ok(KrpcQuery::FindNode { target: extract_node_id("target", arguments)? }),
+        "get_peers" => Ok(KrpcQuery::GetPeers {
+            info_hash: *extract_node_id("info_hash", arguments)?.as_bytes(),
+        }),
+        "announce_peer" => Ok(KrpcQuery::AnnouncePeer {
+            info_hash: *extract_node_id("info_hash", arguments)?.as_bytes(),
+            port: u16::try_from(extract_int("port", arguments)?)
+                .map_err(|_| MetadataError::FieldError("port".to_string()))?,
+            implied_port: extract_int("implied_port", arguments).is_ok_and(|implied| implied != 0),
+            token: extract_bytes("token", arguments)?,
+        }),
+        _ => Err(MetadataError::FieldError("q".to_string())),
+    }
+}
+
+fn parse_response(values: &BencodeDict) -> Result<KrpcResponse, MetadataError>
+{
+    let nodes = extract_bytes("nodes", values)
+        .map(|nodes| NodeInfo::from_compact(&nodes))
+        .unwrap_or_default();
+    let peers = extract_list("values", values)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|value| match value
+        {
+            Value::Bytes(peer) if peer.len() == 6 => Some(SocketAddrV4::new(
+                Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
+                u16::from_be_bytes([peer[4], peer[5]]),
+            )),
+            _ => None,
+        })
+        .collect();
+
+    let response = KrpcResponse::new(extract_node_id("id", values)?)
+        .with_nodes(nodes)
+        .with_values(peers);
+
+    Ok(match extract_bytes("token", values)
+    {
+        Ok(token) => response.with_token(token),
+        Err(_) => response,
+    })
+}
+
+fn extract_node_id(key: &str, dict: &BencodeDict) -> Result<NodeId, MetadataError>
+{
+    let id: [u8; 20] = extract_bytes(key, dict)?
+        .try_into()
+        .map_err(|_| MetadataError::FieldError(key.to_string()))?;
+    Ok(NodeId::new(id))
+}
+
+fn compact_peer(peer: &SocketAddrV4) -> Vec<u8>
+{
+    let mut bytes = peer.ip().octets().to_vec();
+    bytes.extend_from_slice(&peer.port().to_be_bytes());
+    bytes
+}
@@ -0,0 +1,108 @@
+use crate::utils::errors::MetadataError;
+
+use getset::Getters;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+const REQUEST_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+/// A BEP 14 announce: an HTTP-like request multicast on the local network
+/// listing the torrents the sender serves on `port`. The cookie lets the
+/// sender recognise and drop its own announces.
+#[derive(Getters, Clone, Debug, PartialEq, Eq)]
+pub struct LsdAnnounce
+{
+    #[get = "pub"]
+    port: u16,
+    #[get = "pub"]
+    info_hashes: Vec<[u8; 20]>,
+    #[get = "pub"]
+    cookie: Option<String>,
+}
+
+impl LsdAnnounce
+{
+    pub fn new(port: u16, info_hashes: Vec<[u8; 20]>) -> Self
+    {
+        Self
+        {
+            port,
+            info_hashes,
+            cookie: None,
+        }
+    }
+
+    pub fn with_cookie(mut self, cookie: &str) -> Self
+    {
+        self.cookie = Some(cookie.to_string());
+        self
+    }
+
+    /// Header names are case-insensitive; unknown headers and info hashes
+    /// that are not 40 hex digits are skipped.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError>
+    {
+        let text = std::str::from_utf8(bytes).map_err(|_| MetadataError::IncorrectFormatError)?;
+        let mut lines = text.split("\r\n");
+
+        if lines.next() != Some(REQUEST_LINE)
+        {
+            return Err(MetadataError::IncorrectFormatError);
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+
+        for line in lines.take_while(|line| !line.is_empty())
+        {
+            let Some((name, value)) = line.split_once(':') else { continue };
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str()
+            {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    let mut info_hash = [0; 20];
+
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok()
+                    {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let port = port
+            .filter(|port| *port != 0)
+            .ok_or(MetadataError::FieldError("Port".to_string()))?;
+
+        if info_hashes.is_empty()
+        {
+            return Err(MetadataError::FieldError("Infohash".to_string()));
+        }
+        Ok(Self { port, info_hashes, cookie })
+    }
+
+    /// Formats the announce for the multicast group at `group`.
+    pub fn as_bytes(&self, group: SocketAddr) -> Vec<u8>
+    {
+        let mut text = format!("{}\r\nHost: {}\r\nPort: {}\r\n", REQUEST_LINE, group, self.port);
+
+        for info_hash in &self.info_hashes
+        {
+            text.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie
+        {
+            text.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        text.push_str("\r\n\r\n");
+        text.into_bytes()
+    }
+}
@@ -0,0 +1,107 @@
+use crate::entities::announce_list::AnnounceList;
+use crate::utils::errors::MetadataError;
+
+use getset::Getters;
+use url::Url;
+
+#[derive(Getters, Clone, Debug)]
+pub struct Magnet
+{
+    #[get = "pub"]
+    info_hash: [u8; 20],
+    #[get = "pub"]
+    trackers: Vec<Url>,
+    #[get = "pub"]
+    display_name: Option<String>,
+}
+
+impl Magnet
+{
+    pub fn parse(uri: &str) -> Result<Self, MetadataError>
+    {
+        let url = Url::parse(uri).map_err(|_| MetadataError::InvalidMagnetLink(uri.to_string()))?;
+
+        if url.scheme() != "magnet"
+        {
+            return Err(MetadataError::InvalidMagnetLink(uri.to_string()));
+        }
+
+        let mut info_hash = None;
+        let mut trackers = Vec::new();
+        let mut display_name = None;
+
+        for (key, value) in url.query_pairs()
+        {
+            match key.as_ref()
+            {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:")
+                    {
+                        info_hash = Some(decode_info_hash(hash).ok_or_else(|| {
+                            MetadataError::InvalidMagnetLink(uri.to_string())
+                        })?);
+                    }
+                }
+                "tr" => {
+                    if let Ok(tracker) = Url::parse(&value)
+                    {
+                        trackers.push(tracker);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(Self
+        {
+            info_hash: info_hash.ok_or(MetadataError::FieldError("xt".to_string()))?,
+            trackers,
+            display_name,
+        })
+    }
+
+    /// Magnet trackers carry no tier information, so each gets its own tier
+    /// and all of them are announced to.
+    pub fn announce_list(&self) -> AnnounceList
+    {
+        AnnounceList::new(self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect())
+    }
+}
+
+fn decode_info_hash(hash: &str) -> Option<[u8; 20]>
+{
+    let bytes = match hash.len()
+    {
+        40 => hex::decode(hash).ok()?,
+        32 => decode_base32(hash)?,
+        _ => return None,
+    };
+    bytes.try_into().ok()
+}
+
+fn decode_base32(input: &str) -> Option<Vec<u8>>
+{
+    let mut bytes = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in input.bytes()
+    {
+        let value = match c.to_ascii_uppercase()
+        {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+
+        if bits >= 8
+        {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
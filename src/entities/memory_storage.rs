@@ -0,0 +1,75 @@
+use crate::entities::storage::Storage;
+use crate::entities::torrent::TorrentInfo;
+use crate::utils::errors::TorrentError;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// Keeps the whole torrent in memory, so tests can exercise code written
+/// against `Storage` without touching the filesystem.
+pub struct MemoryStorage
+{
+    piece_length: usize,
+    total_length: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage
+{
+    pub fn new(info: &TorrentInfo) -> Self
+    {
+        Self
+        {
+            piece_length: *info.piece_length() as usize,
+            total_length: info.total_length() as usize,
+            data: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns a copy of everything stored so far.
+    pub fn contents(&self) -> Vec<u8>
+    {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage
+{
+    async fn read_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, TorrentError>
+    {
+        let offset = piece_index * self.piece_length + begin;
+        let data = self.data.lock().unwrap();
+        Ok(data.get(offset..offset + length).map(|block| block.to_vec()))
+    }
+
+    async fn write_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> Result<(), TorrentError>
+    {
+        let offset = piece_index * self.piece_length + begin;
+        let mut stored = self.data.lock().unwrap();
+
+        if stored.len() < offset + data.len()
+        {
+            stored.resize(offset + data.len(), 0);
+        }
+        stored[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn truncate(&self) -> Result<(), TorrentError>
+    {
+        self.data.lock().unwrap().resize(self.total_length, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use sha2::{Digest, Sha256};
+
+/// BEP 52 hashes files in 16 KiB blocks, the leaves of each file's tree.
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Sha256Hash = [u8; 32];
+
+pub fn sha256(data: &[u8]) -> Sha256Hash
+{
+    Sha256::digest(data).into()
+}
+
+/// Leaf hashes of `data`; the last block may be shorter than the others.
+pub fn block_hashes(data: &[u8]) -> Vec<Sha256Hash>
+{
+    data.chunks(MERKLE_BLOCK_SIZE).map(sha256).collect()
+}
+
+fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash
+{
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Every layer of the tree over `hashes` padded with `pad` to `width`
+/// nodes, from the bottom layer up to the root.
+fn layers(hashes: &[Sha256Hash], pad: Sha256Hash, width: usize) -> Vec<Vec<Sha256Hash>>
+{
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(hashes.len()).next_power_of_two(), pad);
+    let mut layers = vec![layer];
+
+    while let Some(layer) = layers.last().filter(|layer| layer.len() > 1)
+    {
+        let parent = layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+        layers.push(parent);
+    }
+    layers
+}
+
+/// Root of the tree over `hashes` padded with `pad` to `width` nodes.
+pub fn merkle_root(hashes: &[Sha256Hash], pad: Sha256Hash, width: usize) -> Sha256Hash
+{
+    layers(hashes, pad, width).last().map_or(pad, |root| root[0])
+}
+
+/// What a piece lying wholly past the end of a file hashes to: the root of
+/// a piece worth of zero leaves.
+pub fn pad_root(piece_length: usize) -> Sha256Hash
+{
+    merkle_root(&[], [0; 32], piece_length / MERKLE_BLOCK_SIZE)
+}
+
+/// The piece layer hash of one piece of a file. The last piece of a file is
+/// padded with zero leaves, not with zero bytes.
+pub fn piece_root(data: &[u8], piece_length: usize) -> Sha256Hash
+{
+    merkle_root(&block_hashes(data), [0; 32], piece_length / MERKLE_BLOCK_SIZE)
+}
+
+/// The `pieces root` of a file no longer than one piece, which has no
+/// piece layer.
+pub fn file_root(data: &[u8]) -> Sha256Hash
+{
+    let blocks = block_hashes(data);
+    merkle_root(&blocks, [0; 32], blocks.len())
+}
+
+/// The `pieces root` of a file from its piece layer.
+pub fn layer_root(layer: &[Sha256Hash], piece_length: usize) -> Sha256Hash
+{
+    merkle_root(layer, pad_root(piece_length), layer.len())
+}
+
+/// The uncle hashes that prove `length` hashes of `layer` starting at
+/// `index` against the root. The first is the sibling of the subtree the
+/// hashes form; `proof_layers` counts layers from the bottom of `layer`
+/// and caps how far up the proof goes.
+pub fn merkle_proof(
+    layer: &[Sha256Hash],
+    pad: Sha256Hash,
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Vec<Sha256Hash>
+{
+    let layers = layers(layer, pad, layer.len());
+    let subtree_level = length.trailing_zeros() as usize;
+    let mut position = index >> subtree_level;
+
+    layers
+        .iter()
+        .take(layers.len() - 1)
+        .take(proof_layers)
+        .skip(subtree_level)
+        .map(|nodes| {
+            let sibling = nodes[position ^ 1];
+            position /= 2;
+            sibling
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn leaf(byte: u8) -> Sha256Hash
+    {
+        sha256(&[byte])
+    }
+
+    #[test]
+    fn root_pairs_leaves_up_to_a_power_of_two()
+    {
+        let (a, b, c) = (leaf(1), leaf(2), leaf(3));
+        let pad = [0; 32];
+
+        assert_eq!(merkle_root(&[a], pad, 1), a);
+        assert_eq!(merkle_root(&[a, b], pad, 2), hash_pair(&a, &b));
+        assert_eq!(
+            merkle_root(&[a, b, c], pad, 3),
+            hash_pair(&hash_pair(&a, &b), &hash_pair(&c, &pad))
+        );
+        // A wider tree pads with more leaves.
+        assert_eq!(
+            merkle_root(&[a], pad, 4),
+            hash_pair(&hash_pair(&a, &pad), &hash_pair(&pad, &pad))
+        );
+    }
+
+    #[test]
+    fn short_piece_pads_with_zero_leaves()
+    {
+        let data = vec![7; MERKLE_BLOCK_SIZE + 100];
+        let piece_length = 4 * MERKLE_BLOCK_SIZE;
+        let blocks = block_hashes(&data);
+        let zero = [0; 32];
+
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            piece_root(&data, piece_length),
+            hash_pair(&hash_pair(&blocks[0], &blocks[1]), &hash_pair(&zero, &zero))
+        );
+
+        // Zero leaves are not the hash of zero bytes.
+        let mut padded = data.clone();
+        padded.resize(piece_length, 0);
+        assert_ne!(piece_root(&data, piece_length), piece_root(&padded, piece_length));
+    }
+
+    #[test]
+    fn file_root_spans_only_its_blocks()
+    {
+        let data = vec![3; 3 * MERKLE_BLOCK_SIZE];
+        let blocks = block_hashes(&data);
+
+        assert_eq!(file_root(&data), merkle_root(&blocks, [0; 32], 4));
+        assert_eq!(file_root(&data[..10]), sha256(&data[..10]));
+    }
+
+    #[test]
+    fn layer_root_pads_with_whole_zero_pieces()
+    {
+        let piece_length = 2 * MERKLE_BLOCK_SIZE;
+        let pad = pad_root(piece_length);
+        let layer = [leaf(1), leaf(2), leaf(3)];
+
+        assert_eq!(pad, hash_pair(&[0; 32], &[0; 32]));
+        assert_eq!(
+            layer_root(&layer, piece_length),
+            hash_pair(&hash_pair(&layer[0], &layer[1]), &hash_pair(&layer[2], &pad))
+        );
+    }
+
+    #[test]
+    fn proof_leads_back_to_the_root()
+    {
+        let layer: Vec<Sha256Hash> = (0..5).map(leaf).collect();
+        let pad = [9; 32];
+        let root = merkle_root(&layer, pad, layer.len());
+
+        for index in 0..layer.len()
+        {
+            let proof = merkle_proof(&layer, pad, index, 1, usize::MAX);
+            assert_eq!(proof.len(), 3);
+
+            let mut node = layer[index];
+            let mut position = index;
+
+            for sibling in proof
+            {
+                node = if position % 2 == 0
+                {
+                    hash_pair(&node, &sibling)
+                }
+                else { hash_pair(&sibling, &node) };
+                position /= 2;
+            }
+            assert_eq!(node, root);
+        }
+
+        // Two hashes form a subtree, so the proof starts one layer up.
+        let proof = merkle_proof(&layer, pad, 2, 2, usize::MAX);
+        assert_eq!(proof, vec![hash_pair(&layer[0], &layer[1]), merkle_root(&[layer[4]], pad, 4)]);
+    }
+}
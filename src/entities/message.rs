@@ -1,6 +1,56 @@
-#[derive(Debug)]
+use crate::entities::merkle::Sha256Hash;
+use crate::utils::errors::MessageError;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use std::cmp::Ordering;
+
+/// Length of the fields shared by the BEP 52 hash messages.
+const HASH_REQUEST_LEN: usize = 48;
+
+/// Asks for `length` hashes of the layer `base_layer` levels above the
+/// leaves of the file tree with root `pieces_root`, starting at `index`,
+/// with the uncle hashes of up to `proof_layers` layers to verify them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashRequest
+{
+    pub pieces_root: Sha256Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest
+{
+    fn read(bytes: &[u8]) -> Self
+    {
+        let mut pieces_root = [0; 32];
+        pieces_root.copy_from_slice(&bytes[1..33]);
+
+        Self
+        {
+            pieces_root,
+            base_layer: read_u32(bytes, 33),
+            index: read_u32(bytes, 37),
+            length: read_u32(bytes, 41),
+            proof_layers: read_u32(bytes, 45),
+        }
+    }
+
+    fn put(&self, dst: &mut BytesMut)
+    {
+        dst.put_slice(&self.pieces_root);
+        dst.put_u32(self.base_layer);
+        dst.put_u32(self.index);
+        dst.put_u32(self.length);
+        dst.put_u32(self.proof_layers);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum Message
 {
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
@@ -23,7 +73,7 @@ pub enum Message
     {
         index: u32,
         begin: u32,
-        block: Vec<u8>,
+        block: Bytes,
     },
     Cancel
     {
@@ -35,145 +85,344 @@ pub enum Message
     {
         listen_port: u16,
     },
+    Extended
+    {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    HashRequest(HashRequest),
+    /// The requested hashes followed by their uncle hashes.
+    Hashes
+    {
+        request: HashRequest,
+        hashes: Vec<Sha256Hash>,
+    },
+    HashReject(HashRequest),
 }
 
 impl Message
 {
-    pub fn from_bytes(bytes: &[u8]) -> Option<Self>
+    pub fn bitfield(pieces: &[bool]) -> Self
+    {
+        Message::Bitfield { bitfield: Self::encode_bitfield(pieces) }
+    }
+
+    /// Packs piece flags with the high bit of the first byte as piece 0.
+    pub fn encode_bitfield(pieces: &[bool]) -> Vec<u8>
     {
-        if bytes.is_empty()
+        let mut bitfield = vec![0u8; pieces.len().div_ceil(8)];
+
+        for (index, _) in pieces.iter().enumerate().filter(|(_, have)| **have)
         {
-            return None;
+            bitfield[index / 8] |= 0x80 >> (index % 8);
         }
+        bitfield
+    }
 
-        let id = bytes[0];
-        match id
+    pub fn decode_bitfield(bitfield: &[u8], num_pieces: usize) -> Vec<bool>
+    {
+        (0..num_pieces)
+            .map(|index| {
+                bitfield
+                    .get(index / 8)
+                    .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+            })
+            .collect()
+    }
+
+    /// Checks a peer's bitfield against the torrent: one bit per piece,
+    /// with the spare bits of the last byte cleared.
+    pub fn check_bitfield(bitfield: &[u8], num_pieces: usize) -> Result<(), MessageError>
+    {
+        if bitfield.len() != num_pieces.div_ceil(8)
         {
-            0 => Some(Message::Choke),
-            1 => Some(Message::Unchoke),
-            2 => Some(Message::Interested),
-            3 => Some(Message::NotInterested),
-            4 => {
-                if bytes.len() < 5
-                {
-                    return None;
-                }
-                let piece_index = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
-                Some(Message::Have { piece_index })
+            return Err(MessageError::InvalidBitfieldLength(bitfield.len(), num_pieces));
+        }
+
+        let spare_bits = bitfield.len() * 8 - num_pieces;
+        match bitfield.last()
+        {
+            Some(last) if spare_bits > 0 && last & ((1 << spare_bits) - 1) != 0 => {
+                Err(MessageError::SpareBitsSet)
             }
-            5 => Some(Message::Bitfield {
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses a frame without its length prefix. `Piece` blocks share the
+    /// frame's buffer instead of being copied.
+    pub fn from_frame(bytes: Bytes) -> Result<Self, MessageError>
+    {
+        let Some(&id) = bytes.first() else { return Err(MessageError::Empty) };
+
+        let payload_len = match id
+        {
+            0..=3 => Some(0),
+            4 => Some(4),
+            6 | 8 => Some(12),
+            9 => Some(2),
+            21 | 23 => Some(HASH_REQUEST_LEN),
+            _ => None,
+        };
+        if let Some(expected) = payload_len
+        {
+            check_payload_len(id, bytes.len() - 1, expected)?;
+        }
+
+        match id
+        {
+            0 => Ok(Message::Choke),
+            1 => Ok(Message::Unchoke),
+            2 => Ok(Message::Interested),
+            3 => Ok(Message::NotInterested),
+            4 => Ok(Message::Have { piece_index: read_u32(&bytes, 1) }),
+            5 => Ok(Message::Bitfield {
                 bitfield: bytes[1..].to_vec(),
             }),
-            6 => {
-                if bytes.len() < 13
+            6 => Ok(Message::Request {
+                index: read_u32(&bytes, 1),
+                begin: read_u32(&bytes, 5),
+                length: read_u32(&bytes, 9),
+            }),
+            7 => {
+                if bytes.len() < 9
                 {
-                    return None;
+                    return Err(MessageError::Truncated(id));
                 }
-                let index = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
-                let begin = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
-                let length = u32::from_be_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]);
-                Some(Message::Request {
-                    index,
-                    begin,
-                    length,
+                Ok(Message::Piece {
+                    index: read_u32(&bytes, 1),
+                    begin: read_u32(&bytes, 5),
+                    block: bytes.slice(9..),
                 })
             }
-            7 => {
-                if bytes.len() < 13
+            8 => Ok(Message::Cancel {
+                index: read_u32(&bytes, 1),
+                begin: read_u32(&bytes, 5),
+                length: read_u32(&bytes, 9),
+            }),
+            9 => Ok(Message::Port {
+                listen_port: u16::from_be_bytes([bytes[1], bytes[2]]),
+            }),
+            20 => {
+                if bytes.len() < 2
                 {
-                    return None;
+                    return Err(MessageError::Truncated(id));
                 }
-                let index = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
-                let begin = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
-                let block = bytes[9..].to_vec();
-                Some(Message::Piece {
-                    index,
-                    begin,
-                    block,
+                Ok(Message::Extended {
+                    id: bytes[1],
+                    payload: bytes[2..].to_vec(),
                 })
             }
-            8 => {
-                if bytes.len() < 13
+            21 => Ok(Message::HashRequest(HashRequest::read(&bytes))),
+            22 => {
+                if bytes.len() < 1 + HASH_REQUEST_LEN
                 {
-                    return None;
+                    return Err(MessageError::Truncated(id));
                 }
-                let index = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
-                let begin = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
-                let length = u32::from_be_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]);
-                Some(Message::Cancel {
-                    index,
-                    begin,
-                    length,
-                })
-            }
-            9 => {
-                if bytes.len() < 3
+                if !(bytes.len() - 1 - HASH_REQUEST_LEN).is_multiple_of(32)
                 {
-                    return None;
+                    return Err(MessageError::TrailingBytes(id));
                 }
-                let listen_port = u16::from_be_bytes([bytes[1], bytes[2]]);
-                Some(Message::Port { listen_port })
+                Ok(Message::Hashes {
+                    request: HashRequest::read(&bytes),
+                    hashes: bytes[1 + HASH_REQUEST_LEN..]
+                        .chunks(32)
+                        .map(|chunk| chunk.try_into().unwrap())
+                        .collect(),
+                })
             }
-            _ => None,
+            23 => Ok(Message::HashReject(HashRequest::read(&bytes))),
+            _ => Err(MessageError::UnknownId(id)),
         }
     }
 
-    pub fn as_bytes(&self) -> Vec<u8>
+    /// Appends the length-prefixed wire form of the message to `dst`.
+    pub fn encode(&self, dst: &mut BytesMut)
     {
         match self
         {
-            Message::Choke => vec![0, 0, 0, 1, 0],
-            Message::Unchoke => vec![0, 0, 0, 1, 1],
-            Message::Interested => vec![0, 0, 0, 1, 2],
-            Message::NotInterested => vec![0, 0, 0, 1, 3],
+            Message::KeepAlive => dst.put_u32(0),
+            Message::Choke => put_header(dst, 0, 0),
+            Message::Unchoke => put_header(dst, 1, 0),
+            Message::Interested => put_header(dst, 2, 0),
+            Message::NotInterested => put_header(dst, 3, 0),
             Message::Have { piece_index } => {
-                let mut buf = vec![0, 0, 0, 5, 4];
-                buf.extend_from_slice(&piece_index.to_be_bytes());
-                buf
+                put_header(dst, 4, 4);
+                dst.put_u32(*piece_index);
             }
             Message::Bitfield { bitfield } => {
-                let mut buf = vec![0, 0, 0, 1 + bitfield.len() as u8, 5];
-                buf.extend_from_slice(bitfield);
-                buf
+                put_header(dst, 5, bitfield.len());
+                dst.put_slice(bitfield);
             }
             Message::Request {
                 index,
                 begin,
                 length,
             } => {
-                let mut buf = vec![0, 0, 0, 13, 6];
-                buf.extend_from_slice(&index.to_be_bytes());
-                buf.extend_from_slice(&begin.to_be_bytes());
-                buf.extend_from_slice(&length.to_be_bytes());
-                buf
+                put_header(dst, 6, 12);
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
             }
             Message::Piece {
                 index,
                 begin,
                 block,
             } => {
-                let mut buf = vec![0, 0, 0, (9 + block.len()) as u8, 7];
-                buf.extend_from_slice(&index.to_be_bytes());
-                buf.extend_from_slice(&begin.to_be_bytes());
-                buf.extend_from_slice(block);
-                buf
+                put_header(dst, 7, 8 + block.len());
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_slice(block);
             }
             Message::Cancel {
                 index,
                 begin,
                 length,
             } => {
-                let mut buf = vec![0, 0, 0, 13, 8];
-                buf.extend_from_slice(&index.to_be_bytes());
-                buf.extend_from_slice(&begin.to_be_bytes());
-                buf.extend_from_slice(&length.to_be_bytes());
-                buf
+                put_header(dst, 8, 12);
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
             }
             Message::Port { listen_port } => {
-                let mut buf = vec![0, 0, 0, 3, 9];
-                buf.extend_from_slice(&listen_port.to_be_bytes());
-                buf
+                put_header(dst, 9, 2);
+                dst.put_u16(*listen_port);
+            }
+            Message::Extended { id, payload } => {
+                put_header(dst, 20, 1 + payload.len());
+                dst.put_u8(*id);
+                dst.put_slice(payload);
+            }
+            Message::HashRequest(request) => {
+                put_header(dst, 21, HASH_REQUEST_LEN);
+                request.put(dst);
+            }
+            Message::Hashes { request, hashes } => {
+                put_header(dst, 22, HASH_REQUEST_LEN + 32 * hashes.len());
+                request.put(dst);
+
+                for hash in hashes
+                {
+                    dst.put_slice(hash);
+                }
+            }
+            Message::HashReject(request) => {
+                put_header(dst, 23, HASH_REQUEST_LEN);
+                request.put(dst);
             }
         }
     }
 }
+
+fn check_payload_len(id: u8, actual: usize, expected: usize) -> Result<(), MessageError>
+{
+    match actual.cmp(&expected)
+    {
+        Ordering::Less => Err(MessageError::Truncated(id)),
+        Ordering::Greater => Err(MessageError::TrailingBytes(id)),
+        Ordering::Equal => Ok(()),
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32
+{
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// Writes the 4-byte length prefix and the message id.
+fn put_header(dst: &mut BytesMut, id: u8, payload_len: usize)
+{
+    dst.reserve(5 + payload_len);
+    dst.put_u32(1 + payload_len as u32);
+    dst.put_u8(id);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn frame(bytes: &[u8]) -> Result<Message, MessageError>
+    {
+        Message::from_frame(Bytes::copy_from_slice(bytes))
+    }
+
+    #[test]
+    fn messages_survive_encoding()
+    {
+        let request = HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 1,
+            index: 2,
+            length: 4,
+            proof_layers: 3,
+        };
+        let messages = vec![
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { piece_index: 9 },
+            Message::bitfield(&[true, false, true]),
+            Message::Request { index: 1, begin: 16384, length: 16384 },
+            Message::Piece { index: 1, begin: 0, block: Bytes::from_static(b"data") },
+            Message::Cancel { index: 1, begin: 16384, length: 16384 },
+            Message::Port { listen_port: 6881 },
+            Message::Extended { id: 3, payload: b"d1:ai1ee".to_vec() },
+            Message::HashRequest(request),
+            Message::Hashes { request, hashes: vec![[1; 32], [2; 32]] },
+            Message::HashReject(request),
+        ];
+
+        for message in messages
+        {
+            let mut encoded = BytesMut::new();
+            message.encode(&mut encoded);
+            let length = read_u32(&encoded, 0) as usize;
+
+            assert_eq!(length, encoded.len() - 4);
+            assert_eq!(frame(&encoded[4..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn wrong_payload_sizes_are_rejected()
+    {
+        let cases: Vec<(Vec<u8>, MessageError)> = vec![
+            (vec![], MessageError::Empty),
+            (vec![0, 0], MessageError::TrailingBytes(0)),
+            (vec![4, 0, 0, 1], MessageError::Truncated(4)),
+            (vec![4, 0, 0, 0, 1, 0], MessageError::TrailingBytes(4)),
+            (vec![6; 12], MessageError::Truncated(6)),
+            (vec![8; 14], MessageError::TrailingBytes(8)),
+            (vec![7, 0, 0, 0, 1, 0, 0, 0], MessageError::Truncated(7)),
+            (vec![9, 0x1a], MessageError::Truncated(9)),
+            (vec![20], MessageError::Truncated(20)),
+            (vec![21; 48], MessageError::Truncated(21)),
+            (vec![22; 1 + HASH_REQUEST_LEN + 31], MessageError::TrailingBytes(22)),
+            (vec![23; 1 + HASH_REQUEST_LEN + 1], MessageError::TrailingBytes(23)),
+            (vec![14], MessageError::UnknownId(14)),
+        ];
+
+        for (bytes, expected) in cases
+        {
+            assert_eq!(frame(&bytes).unwrap_err(), expected, "frame {:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn bitfield_must_fit_the_torrent()
+    {
+        assert!(Message::check_bitfield(&[0xff, 0xe0], 11).is_ok());
+        assert_eq!(
+            Message::check_bitfield(&[0xff], 11),
+            Err(MessageError::InvalidBitfieldLength(1, 11))
+        );
+        assert_eq!(
+            Message::check_bitfield(&[0xff, 0xff, 0x00], 11),
+            Err(MessageError::InvalidBitfieldLength(3, 11))
+        );
+        assert_eq!(Message::check_bitfield(&[0xff, 0xf0], 11), Err(MessageError::SpareBitsSet));
+        assert_eq!(Message::decode_bitfield(&[0xa0], 3), vec![true, false, true]);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::entities::message::Message;
+use crate::utils::errors::{MessageError, TorrentError};
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Large enough for the bitfield of an eight million piece torrent and far
+/// above the 16 KiB blocks peers send.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+const LENGTH_PREFIX_LEN: usize = 4;
+
+/// Frames peer wire messages behind their 4-byte big-endian length prefix.
+/// A zero length is a keep-alive; frames longer than `max_frame_len` are
+/// rejected before anything is allocated for them.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageCodec
+{
+    max_frame_len: usize,
+}
+
+impl Default for MessageCodec
+{
+    fn default() -> Self
+    {
+        Self { max_frame_len: DEFAULT_MAX_FRAME_LEN }
+    }
+}
+
+impl MessageCodec
+{
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self
+    {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+}
+
+impl Decoder for MessageCodec
+{
+    type Item = Message;
+    type Error = TorrentError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, TorrentError>
+    {
+        loop
+        {
+            if src.len() < LENGTH_PREFIX_LEN
+            {
+                return Ok(None);
+            }
+
+            let mut length_prefix = [0; LENGTH_PREFIX_LEN];
+            length_prefix.copy_from_slice(&src[..LENGTH_PREFIX_LEN]);
+            let length = u32::from_be_bytes(length_prefix) as usize;
+
+            if length > self.max_frame_len
+            {
+                return Err(MessageError::Oversized(length, self.max_frame_len).into());
+            }
+            if src.len() < LENGTH_PREFIX_LEN + length
+            {
+                src.reserve(LENGTH_PREFIX_LEN + length - src.len());
+                return Ok(None);
+            }
+
+            src.advance(LENGTH_PREFIX_LEN);
+
+            if length == 0
+            {
+                return Ok(Some(Message::KeepAlive));
+            }
+
+            // Messages of protocol extensions we do not implement, such as
+            // the fast extension, are skipped whole.
+            match Message::from_frame(src.split_to(length).freeze())
+            {
+                Err(MessageError::UnknownId(_)) => continue,
+                result => return Ok(Some(result?)),
+            }
+        }
+    }
+}
+
+impl Encoder<&Message> for MessageCodec
+{
+    type Error = TorrentError;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<(), TorrentError>
+    {
+        message.encode(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn encoded(messages: &[Message]) -> BytesMut
+    {
+        let mut bytes = BytesMut::new();
+
+        for message in messages
+        {
+            message.encode(&mut bytes);
+        }
+        bytes
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame()
+    {
+        let message = Message::Request { index: 1, begin: 2, length: 3 };
+        let bytes = encoded(&[message]);
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::new();
+
+        for byte in &bytes[..bytes.len() - 1]
+        {
+            src.extend_from_slice(&[*byte]);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+        }
+        src.extend_from_slice(&bytes[bytes.len() - 1..]);
+
+        let decoded = codec.decode(&mut src).unwrap();
+        assert_eq!(decoded, Some(Message::Request { index: 1, begin: 2, length: 3 }));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn splits_several_frames_from_one_buffer()
+    {
+        let mut src = encoded(&[
+            Message::Unchoke,
+            Message::KeepAlive,
+            Message::Have { piece_index: 5 },
+        ]);
+        src.extend_from_slice(&[0, 0]);
+        let mut codec = MessageCodec::default();
+
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Unchoke));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::KeepAlive));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Have { piece_index: 5 }));
+        // Half a length prefix stays buffered.
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), 2);
+    }
+
+    #[test]
+    fn rejects_frames_over_the_limit_before_they_arrive()
+    {
+        let mut codec = MessageCodec::default().with_max_frame_len(16);
+        let mut src = BytesMut::from(&17_u32.to_be_bytes()[..]);
+
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(TorrentError::MessageError(MessageError::Oversized(17, 16)))
+        ));
+
+        let mut src = encoded(&[Message::Bitfield { bitfield: vec![0xff; 15] }]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+    }
+
+    #[test]
+    fn skips_unknown_messages()
+    {
+        // A fast extension Have All, then an Unchoke.
+        let mut src = BytesMut::from(&[0, 0, 0, 1, 0x0e][..]);
+        src.extend_from_slice(&encoded(&[Message::Unchoke]));
+        let mut codec = MessageCodec::default();
+
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Unchoke));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn malformed_frames_are_errors()
+    {
+        let mut src = BytesMut::from(&[0, 0, 0, 2, 4, 0][..]);
+
+        assert!(matches!(
+            MessageCodec::default().decode(&mut src),
+            Err(TorrentError::MessageError(MessageError::Truncated(4)))
+        ));
+    }
+}
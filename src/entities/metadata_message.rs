@@ -0,0 +1,78 @@
+use crate::utils::errors::MetadataError;
+use crate::utils::extract_torrent_metadata::{bencode_value_len, extract_int};
+
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum MetadataMessage
+{
+    Request
+    {
+        piece: u32,
+    },
+    Data
+    {
+        piece: u32,
+        total_size: i64,
+        data: Vec<u8>,
+    },
+    Reject
+    {
+        piece: u32,
+    },
+}
+
+impl MetadataMessage
+{
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError>
+    {
+        let header_len = bencode_value_len(bytes).ok_or(MetadataError::IncorrectFormatError)?;
+        let value: Value = serde_bencode::from_bytes(&bytes[..header_len])?;
+
+        let dict = if let Value::Dict(dict) = value { dict }
+        else { return Err(MetadataError::IncorrectFormatError) };
+
+        let piece = u32::try_from(extract_int("piece", &dict)?)
+            .map_err(|_| MetadataError::FieldError("piece".to_string()))?;
+
+        match extract_int("msg_type", &dict)?
+        {
+            0 => Ok(MetadataMessage::Request { piece }),
+            1 => Ok(MetadataMessage::Data {
+                piece,
+                total_size: extract_int("total_size", &dict)?,
+                data: bytes[header_len..].to_vec(),
+            }),
+            2 => Ok(MetadataMessage::Reject { piece }),
+            _ => Err(MetadataError::FieldError("msg_type".to_string())),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, MetadataError>
+    {
+        let mut dict = HashMap::new();
+
+        let (msg_type, piece) = match self
+        {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data { piece, total_size, .. } => {
+                dict.insert(b"total_size".to_vec(), Value::Int(*total_size));
+                (1, piece)
+            }
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+        dict.insert(b"msg_type".to_vec(), Value::Int(msg_type));
+        dict.insert(b"piece".to_vec(), Value::Int(*piece as i64));
+
+        let mut bytes = serde_bencode::to_bytes(&Value::Dict(dict))?;
+
+        if let MetadataMessage::Data { data, .. } = self
+        {
+            bytes.extend_from_slice(data);
+        }
+        Ok(bytes)
+    }
+}
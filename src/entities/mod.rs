@@ -1,4 +1,25 @@
 pub mod torrent;
+pub mod announce_list;
 pub mod peer;
 pub mod handshake;
 pub mod message;
+pub mod message_codec;
+pub mod merkle;
+pub mod magnet;
+pub mod extension;
+pub mod pex;
+pub mod metadata_message;
+pub mod transfer_stats;
+pub mod scrape;
+pub mod download_config;
+pub mod create_config;
+pub mod file_layout;
+pub mod storage;
+#[cfg(test)]
+pub mod memory_storage;
+pub mod resume_data;
+pub mod piece_picker;
+pub mod choker;
+pub mod routing_table;
+pub mod krpc;
+pub mod lsd;
@@ -1,10 +1,17 @@
+use crate::entities::magnet::Magnet;
 use crate::entities::torrent::Torrent;
 use crate::utils::extract_torrent_metadata::generate_peer_id;
+use crate::utils::local_address::LocalAddresses;
 
 use getset::Getters;
 use reqwest::Url;
-use std::net::IpAddr;
-use urlencoding::encode_binary;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use urlencoding::{encode, encode_binary};
+
+pub const DEFAULT_PORT: u16 = 6881;
+const MAGNET_LEFT_PLACEHOLDER: i64 = 16 * 1024;
 
 #[derive(Getters, Clone, Debug)]
 pub struct Peer
@@ -13,13 +20,70 @@ pub struct Peer
     ip: IpAddr,
     #[get = "pub"]
     port: u16,
+    #[get = "pub"]
+    peer_id: Option<Vec<u8>>,
 }
 
 impl Peer
 {
     pub fn new(ip: IpAddr, port: u16) -> Self
     {
-        Self { ip, port }
+        Self
+        {
+            ip,
+            port,
+            peer_id: None,
+        }
+    }
+
+    /// Only the dictionary peer model tells us the remote peer id up front.
+    pub fn with_peer_id(mut self, peer_id: Option<Vec<u8>>) -> Self
+    {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr
+    {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    /// Parses the compact model: 4 bytes of address followed by 2 of port.
+    /// Trailing bytes that do not form a whole entry are ignored.
+    pub fn from_compact(bytes: &[u8]) -> Vec<Peer>
+    {
+        bytes
+            .chunks_exact(6)
+            .map(|chunk| {
+                let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
+                let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+                Peer::new(ip, port)
+            })
+            .collect()
+    }
+
+    /// Parses the compact IPv6 model used by `peers6` and IPv6 UDP trackers:
+    /// 16 bytes of address followed by 2 of port.
+    pub fn from_compact_v6(bytes: &[u8]) -> Vec<Peer>
+    {
+        bytes
+            .chunks_exact(18)
+            .map(|chunk| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&chunk[..16]);
+                let ip = IpAddr::V6(Ipv6Addr::from(octets));
+                let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+                Peer::new(ip, port)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Peer
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}", self.addr())
     }
 }
 
@@ -29,6 +93,8 @@ pub struct TrackerResponse
     #[get = "pub"]
     interval: i64,
     #[get = "pub"]
+    min_interval: Option<i64>,
+    #[get = "pub"]
     peers: Vec<Peer>,
 }
 
@@ -36,7 +102,39 @@ impl TrackerResponse
 {
     pub fn new(interval: i64, peers: Vec<Peer>) -> Self
     {
-        Self { interval, peers }
+        Self
+        {
+            interval,
+            min_interval: None,
+            peers,
+        }
+    }
+
+    pub fn with_min_interval(mut self, min_interval: Option<i64>) -> Self
+    {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Combines the answers of several trackers, keeping the shortest
+    /// interval and dropping peers reported more than once.
+    pub fn merge(responses: Vec<TrackerResponse>) -> Self
+    {
+        let interval = responses.iter().map(|r| r.interval).min().unwrap_or(0);
+        let min_interval = responses.iter().filter_map(|r| r.min_interval).max();
+        let mut seen = HashSet::new();
+        let peers = responses
+            .into_iter()
+            .flat_map(|r| r.peers)
+            .filter(|peer| seen.insert(peer.addr()))
+            .collect();
+
+        Self
+        {
+            interval,
+            min_interval,
+            peers,
+        }
     }
 }
 
@@ -59,6 +157,16 @@ pub struct TrackerRequest
     left: i64,
     #[get = "pub"]
     compact: u8,
+    #[get = "pub"]
+    event: Option<TrackerEvent>,
+    #[get = "pub"]
+    key: u32,
+    #[get = "pub"]
+    num_want: i32,
+    #[get = "pub"]
+    ipv4: Option<Ipv4Addr>,
+    #[get = "pub"]
+    ipv6: Option<Ipv6Addr>,
 }
 
 impl TrackerRequest
@@ -70,17 +178,86 @@ impl TrackerRequest
             tracker_url: torrent.announce().clone(),
             info_hash: *torrent.info_hash(),
             peer_id: generate_peer_id(),
-            port: 6881,
+            port: DEFAULT_PORT,
+            uploaded: 0,
+            downloaded: 0,
+            left: torrent.info().total_length(),
+            compact: 1,
+            event: None,
+            key: rand::random(),
+            num_want: -1,
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    /// Builds an announce for a magnet link, where the total size is not
+    /// known until the metadata has been fetched from peers.
+    pub fn from_magnet(magnet: &Magnet, tracker_url: Url) -> Self
+    {
+        Self
+        {
+            tracker_url,
+            info_hash: *magnet.info_hash(),
+            peer_id: generate_peer_id(),
+            port: DEFAULT_PORT,
             uploaded: 0,
             downloaded: 0,
-            left: *torrent.info().length(),
+            left: MAGNET_LEFT_PLACEHOLDER,
             compact: 1,
+            event: None,
+            key: rand::random(),
+            num_want: -1,
+            ipv4: None,
+            ipv6: None,
         }
     }
 
+    pub fn with_tracker_url(mut self, tracker_url: Url) -> Self
+    {
+        self.tracker_url = tracker_url;
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self
+    {
+        self.port = port;
+        self
+    }
+
+    pub fn with_event(mut self, event: Option<TrackerEvent>) -> Self
+    {
+        self.event = event;
+        self
+    }
+
+    pub fn with_transfer(mut self, uploaded: i64, downloaded: i64, left: i64) -> Self
+    {
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+        self.left = left;
+        self
+    }
+
+    /// A negative value lets the tracker pick its default number of peers.
+    pub fn with_num_want(mut self, num_want: i32) -> Self
+    {
+        self.num_want = num_want;
+        self
+    }
+
+    /// BEP 7: trackers only see the address family we connect over, so the
+    /// other family's address is reported explicitly.
+    pub fn with_local_addresses(mut self, local_addresses: &LocalAddresses) -> Self
+    {
+        self.ipv4 = local_addresses.public_ipv4();
+        self.ipv6 = local_addresses.public_ipv6();
+        self
+    }
+
     pub fn build_url(&self) -> String
     {
-        format!(
+        let mut url = format!(
             "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}",
             self.tracker_url,
             encode_binary(&self.info_hash),
@@ -90,6 +267,59 @@ impl TrackerRequest
             self.downloaded,
             self.left,
             self.compact
-        )
+        );
+
+        url.push_str(&format!("&key={:08x}", self.key));
+
+        if let Some(ipv4) = self.ipv4
+        {
+            url.push_str(&format!("&ipv4={}", ipv4));
+        }
+        if let Some(ipv6) = self.ipv6
+        {
+            url.push_str(&format!("&ipv6={}", encode(&ipv6.to_string())));
+        }
+        if self.num_want >= 0
+        {
+            url.push_str(&format!("&numwant={}", self.num_want));
+        }
+        if let Some(event) = self.event
+        {
+            url.push_str(&format!("&event={}", event.as_str()));
+        }
+        url
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackerEvent
+{
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl TrackerEvent
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            TrackerEvent::Started => "started",
+            TrackerEvent::Completed => "completed",
+            TrackerEvent::Stopped => "stopped",
+        }
+    }
+
+    /// Event codes used by the UDP tracker protocol, where 0 means none.
+    pub fn udp_code(event: Option<TrackerEvent>) -> u32
+    {
+        match event
+        {
+            None => 0,
+            Some(TrackerEvent::Completed) => 1,
+            Some(TrackerEvent::Started) => 2,
+            Some(TrackerEvent::Stopped) => 3,
+        }
     }
 }
\ No newline at end of file
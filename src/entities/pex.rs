@@ -0,0 +1,146 @@
+use crate::entities::peer::Peer;
+use crate::utils::errors::MetadataError;
+use crate::utils::extract_torrent_metadata::extract_bytes;
+
+use getset::Getters;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+pub const UT_PEX: &str = "ut_pex";
+/// BEP 11 allows at most 50 added and 50 dropped peers per message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+pub const PEX_SEED: u8 = 0x02;
+/// The peer accepts incoming connections on the address given.
+pub const PEX_CONNECTABLE: u8 = 0x10;
+
+/// A ut_pex message: the peers the sender connected to and disconnected
+/// from since its previous message. Added peers carry their flags.
+#[derive(Getters, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PexMessage
+{
+    #[get = "pub"]
+    added: Vec<(SocketAddr, u8)>,
+    #[get = "pub"]
+    dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage
+{
+    pub fn new(added: Vec<(SocketAddr, u8)>, dropped: Vec<SocketAddr>) -> Self
+    {
+        Self { added, dropped }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    /// Parses both address families. Missing lists count as empty and
+    /// missing flags as zero.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError>
+    {
+        let value: Value = serde_bencode::from_bytes(bytes)?;
+        let Value::Dict(dict) = value else { return Err(MetadataError::IncorrectFormatError) };
+        let list = |key: &str| extract_bytes(key, &dict).unwrap_or_default();
+
+        let with_flags = |peers: Vec<Peer>, flags: Vec<u8>| {
+            peers
+                .into_iter()
+                .enumerate()
+                .map(move |(index, peer)| (peer.addr(), flags.get(index).copied().unwrap_or(0)))
+        };
+        let added = with_flags(Peer::from_compact(&list("added")), list("added.f"))
+            .chain(with_flags(Peer::from_compact_v6(&list("added6")), list("added6.f")))
+            .collect();
+        let dropped = Peer::from_compact(&list("dropped"))
+            .into_iter()
+            .chain(Peer::from_compact_v6(&list("dropped6")))
+            .map(|peer| peer.addr())
+            .collect();
+
+        Ok(Self { added, dropped })
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, MetadataError>
+    {
+        let (added, added6): (Vec<&(SocketAddr, u8)>, Vec<_>) =
+            self.added.iter().partition(|(addr, _)| addr.is_ipv4());
+        let (dropped, dropped6): (Vec<&SocketAddr>, Vec<_>) =
+            self.dropped.iter().partition(|addr| addr.is_ipv4());
+        let addrs = |peers: &[&(SocketAddr, u8)]| to_compact(peers.iter().map(|(addr, _)| addr));
+        let flags = |peers: &[&(SocketAddr, u8)]| peers.iter().map(|(_, flags)| *flags).collect();
+
+        let mut dict = HashMap::new();
+        dict.insert(b"added".to_vec(), Value::Bytes(addrs(&added)));
+        dict.insert(b"added.f".to_vec(), Value::Bytes(flags(&added)));
+        dict.insert(b"added6".to_vec(), Value::Bytes(addrs(&added6)));
+        dict.insert(b"added6.f".to_vec(), Value::Bytes(flags(&added6)));
+        dict.insert(b"dropped".to_vec(), Value::Bytes(to_compact(dropped)));
+        dict.insert(b"dropped6".to_vec(), Value::Bytes(to_compact(dropped6)));
+        Ok(serde_bencode::to_bytes(&Value::Dict(dict))?)
+    }
+}
+
+/// What we last told one peer about the swarm, so each message only carries
+/// the changes since the previous one.
+#[derive(Clone, Debug, Default)]
+pub struct PexHistory
+{
+    sent: HashMap<SocketAddr, u8>,
+}
+
+impl PexHistory
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Returns the message that brings the peer up to date with `swarm`, or
+    /// `None` if nothing changed. Changes beyond `MAX_PEX_PEERS` per list
+    /// are left for later messages.
+    pub fn update(&mut self, swarm: &HashMap<SocketAddr, u8>) -> Option<PexMessage>
+    {
+        let added: Vec<(SocketAddr, u8)> = swarm
+            .iter()
+            .filter(|(addr, flags)| self.sent.get(addr) != Some(flags))
+            .map(|(addr, flags)| (*addr, *flags))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .keys()
+            .filter(|addr| !swarm.contains_key(addr))
+            .copied()
+            .take(MAX_PEX_PEERS)
+            .collect();
+
+        self.sent.extend(added.iter().copied());
+        for addr in &dropped
+        {
+            self.sent.remove(addr);
+        }
+
+        let message = PexMessage::new(added, dropped);
+        (!message.is_empty()).then_some(message)
+    }
+}
+
+fn to_compact<'a>(addrs: impl IntoIterator<Item = &'a SocketAddr>) -> Vec<u8>
+{
+    let mut bytes = Vec::new();
+
+    for addr in addrs
+    {
+        match addr.ip()
+        {
+            IpAddr::V4(ip) => bytes.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => bytes.extend_from_slice(&ip.octets()),
+        }
+        bytes.extend_from_slice(&addr.port().to_be_bytes());
+    }
+    bytes
+}
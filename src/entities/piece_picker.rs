@@ -0,0 +1,285 @@
+use rand::seq::SliceRandom;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum PiecePriority
+{
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Chooses which piece to download next. Higher priorities go first; within
+/// a priority the piece held by the fewest peers wins, with ties broken at
+/// random so peers do not all converge on the same piece. Once every block of
+/// every missing piece has been requested the picker enters endgame and hands
+/// pieces out to additional peers.
+#[derive(Clone, Debug)]
+pub struct PiecePicker
+{
+    availability: Vec<u32>,
+    have: Vec<bool>,
+    requesters: Vec<u32>,
+    fully_requested: Vec<bool>,
+    priorities: Vec<PiecePriority>,
+}
+
+impl PiecePicker
+{
+    pub fn new(have: &[bool]) -> Self
+    {
+        Self
+        {
+            availability: vec![0; have.len()],
+            have: have.to_vec(),
+            requesters: vec![0; have.len()],
+            fully_requested: vec![false; have.len()],
+            priorities: vec![PiecePriority::default(); have.len()],
+        }
+    }
+
+    pub fn set_priority(&mut self, piece_index: usize, priority: PiecePriority)
+    {
+        if let Some(current) = self.priorities.get_mut(piece_index)
+        {
+            *current = priority;
+        }
+    }
+
+    pub fn add_availability(&mut self, piece_index: usize)
+    {
+        if let Some(count) = self.availability.get_mut(piece_index)
+        {
+            *count += 1;
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected.
+    pub fn remove_peer(&mut self, bitfield: &[bool])
+    {
+        for (count, has) in self.availability.iter_mut().zip(bitfield)
+        {
+            if *has
+            {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    fn is_wanted(&self, piece_index: usize, peer_has: &[bool]) -> bool
+    {
+        !self.have[piece_index]
+            && self.requesters[piece_index] == 0
+            && peer_has.get(piece_index).copied().unwrap_or(false)
+    }
+
+    /// Whether the peer has any piece we are still missing.
+    pub fn wants_any(&self, peer_has: &[bool]) -> bool
+    {
+        self.have
+            .iter()
+            .zip(peer_has)
+            .any(|(have, peer_has)| !*have && *peer_has)
+    }
+
+    /// Picks a piece the peer has and marks it as in progress.
+    pub fn pick(&mut self, peer_has: &[bool]) -> Option<usize>
+    {
+        let best = (0..self.have.len())
+            .filter(|index| self.is_wanted(*index, peer_has))
+            .map(|index| (self.priorities[index], std::cmp::Reverse(self.availability[index])))
+            .max()?;
+
+        let candidates: Vec<usize> = (0..self.have.len())
+            .filter(|index| self.is_wanted(*index, peer_has))
+            .filter(|index| {
+                (self.priorities[*index], std::cmp::Reverse(self.availability[*index])) == best
+            })
+            .collect();
+
+        let piece_index = *candidates.choose(&mut rand::thread_rng())?;
+        self.requesters[piece_index] += 1;
+        Some(piece_index)
+    }
+
+    /// Records that every block of a piece in progress has been requested.
+    pub fn set_fully_requested(&mut self, piece_index: usize)
+    {
+        if self.requesters[piece_index] > 0
+        {
+            self.fully_requested[piece_index] = true;
+        }
+    }
+
+    /// True when every block of every missing piece has been requested.
+    pub fn is_endgame(&self) -> bool
+    {
+        self.have
+            .iter()
+            .zip(&self.fully_requested)
+            .all(|(have, fully_requested)| *have || *fully_requested)
+    }
+
+    /// In endgame, picks a piece the peer has that is in progress elsewhere
+    /// and not in `exclude`, preferring the one with the fewest requesters.
+    pub fn pick_endgame(&mut self, peer_has: &[bool], exclude: &[usize]) -> Option<usize>
+    {
+        if !self.is_endgame()
+        {
+            return None;
+        }
+
+        let piece_index = (0..self.have.len())
+            .filter(|index| !self.have[*index] && !exclude.contains(index))
+            .filter(|index| peer_has.get(*index).copied().unwrap_or(false))
+            .min_by_key(|index| self.requesters[*index])?;
+        self.requesters[piece_index] += 1;
+        Some(piece_index)
+    }
+
+    pub fn is_complete(&self, piece_index: usize) -> bool
+    {
+        self.have[piece_index]
+    }
+
+    /// Drops one requester of an unfinished piece; with none left the piece
+    /// becomes available to other peers again.
+    pub fn release(&mut self, piece_index: usize)
+    {
+        self.requesters[piece_index] = self.requesters[piece_index].saturating_sub(1);
+
+        if self.requesters[piece_index] == 0
+        {
+            self.fully_requested[piece_index] = false;
+        }
+    }
+
+    pub fn complete(&mut self, piece_index: usize)
+    {
+        self.release(piece_index);
+        self.have[piece_index] = true;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// A picker missing every piece, where piece `i` is held by
+    /// `availability[i]` peers.
+    fn picker_with(availability: &[u32]) -> PiecePicker
+    {
+        let mut picker = PiecePicker::new(&vec![false; availability.len()]);
+
+        for (piece_index, count) in availability.iter().enumerate()
+        {
+            for _ in 0..*count
+            {
+                picker.add_availability(piece_index);
+            }
+        }
+        picker
+    }
+
+    #[test]
+    fn picks_rarest_piece_first()
+    {
+        let mut picker = picker_with(&[3, 1, 2, 4]);
+        let peer_has = [true; 4];
+
+        assert_eq!(picker.pick(&peer_has), Some(1));
+        assert_eq!(picker.pick(&peer_has), Some(2));
+        assert_eq!(picker.pick(&peer_has), Some(0));
+        assert_eq!(picker.pick(&peer_has), Some(3));
+        assert_eq!(picker.pick(&peer_has), None);
+    }
+
+    #[test]
+    fn picks_only_pieces_the_peer_has()
+    {
+        let mut picker = picker_with(&[1, 1, 1]);
+
+        assert_eq!(picker.pick(&[false, false, true]), Some(2));
+        assert_eq!(picker.pick(&[false, false, true]), None);
+        assert!(!picker.wants_any(&[false, false, false]));
+    }
+
+    #[test]
+    fn higher_priority_beats_rarity()
+    {
+        let mut picker = picker_with(&[1, 5, 1, 5]);
+        picker.set_priority(1, PiecePriority::High);
+        picker.set_priority(2, PiecePriority::Low);
+        let peer_has = [true; 4];
+
+        assert_eq!(picker.pick(&peer_has), Some(1));
+        assert_eq!(picker.pick(&peer_has), Some(0));
+        assert_eq!(picker.pick(&peer_has), Some(3));
+        assert_eq!(picker.pick(&peer_has), Some(2));
+    }
+
+    #[test]
+    fn removed_peer_no_longer_counts()
+    {
+        let mut picker = picker_with(&[2, 1]);
+        picker.remove_peer(&[true, false]);
+        picker.remove_peer(&[true, false]);
+
+        // Piece 0 is now held by nobody we know of, so it is the rarest.
+        assert_eq!(picker.pick(&[true, true]), Some(0));
+    }
+
+    #[test]
+    fn released_piece_is_picked_again()
+    {
+        let mut picker = picker_with(&[1, 2]);
+        let peer_has = [true, true];
+
+        assert_eq!(picker.pick(&peer_has), Some(0));
+        picker.release(0);
+        assert_eq!(picker.pick(&peer_has), Some(0));
+        assert_eq!(picker.pick(&peer_has), Some(1));
+    }
+
+    #[test]
+    fn completed_piece_is_never_picked()
+    {
+        let mut picker = picker_with(&[1, 2]);
+        let peer_has = [true, true];
+
+        assert_eq!(picker.pick(&peer_has), Some(0));
+        picker.complete(0);
+        assert!(picker.is_complete(0));
+        assert_eq!(picker.pick(&peer_has), Some(1));
+        picker.release(1);
+        assert_eq!(picker.pick(&peer_has), Some(1));
+        assert_eq!(picker.pick(&peer_has), None);
+    }
+
+    #[test]
+    fn endgame_shares_pieces_with_fewest_requesters()
+    {
+        let mut picker = picker_with(&[1, 2, 1]);
+        picker.complete(2);
+        let peer_has = [true; 3];
+
+        assert_eq!(picker.pick(&peer_has), Some(0));
+        assert_eq!(picker.pick(&peer_has), Some(1));
+        picker.set_fully_requested(0);
+        // Piece 1 still has blocks nobody asked for.
+        assert!(!picker.is_endgame());
+        assert_eq!(picker.pick_endgame(&peer_has, &[]), None);
+
+        picker.set_fully_requested(1);
+        assert!(picker.is_endgame());
+        assert_eq!(picker.pick_endgame(&peer_has, &[0]), Some(1));
+        // Piece 1 now has two requesters and piece 0 one.
+        assert_eq!(picker.pick_endgame(&peer_has, &[]), Some(0));
+        assert_eq!(picker.pick_endgame(&peer_has, &[0, 1]), None);
+
+        picker.release(0);
+        picker.release(0);
+        assert!(!picker.is_endgame());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::entities::message::Message;
+use crate::utils::errors::MetadataError;
+use crate::utils::extract_torrent_metadata::{extract_bytes, extract_int, extract_list};
+
+use getset::Getters;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+
+/// Size and modification time of a file when the resume data was saved.
+#[derive(Getters, Clone, Debug, PartialEq, Eq)]
+pub struct ResumeFile
+{
+    #[get = "pub"]
+    length: u64,
+    #[get = "pub"]
+    mtime: i64,
+}
+
+impl ResumeFile
+{
+    pub fn new(length: u64, mtime: i64) -> Self
+    {
+        Self { length, mtime }
+    }
+}
+
+/// Fast-resume state: which pieces were verified and what the files looked
+/// like at that moment. The pieces can be trusted only while every file
+/// still has the recorded size and modification time.
+#[derive(Getters, Clone, Debug)]
+pub struct ResumeData
+{
+    #[get = "pub"]
+    info_hash: [u8; 20],
+    #[get = "pub"]
+    pieces: Vec<bool>,
+    #[get = "pub"]
+    files: Vec<ResumeFile>,
+}
+
+impl ResumeData
+{
+    pub fn new(info_hash: [u8; 20], pieces: Vec<bool>, files: Vec<ResumeFile>) -> Self
+    {
+        Self
+        {
+            info_hash,
+            pieces,
+            files,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], num_pieces: usize) -> Result<Self, MetadataError>
+    {
+        let value: Value = serde_bencode::from_bytes(bytes)?;
+
+        if let Value::Dict(dict) = value
+        {
+            let info_hash = extract_bytes("info hash", &dict)?
+                .try_into()
+                .map_err(|_| MetadataError::FieldError("info hash".to_string()))?;
+            let pieces = Message::decode_bitfield(&extract_bytes("pieces", &dict)?, num_pieces);
+            let files = extract_list("files", &dict)?
+                .into_iter()
+                .map(|file| match file
+                {
+                    Value::Dict(file) => Ok(ResumeFile::new(
+                        extract_int("length", &file)? as u64,
+                        extract_int("mtime", &file)?,
+                    )),
+                    _ => Err(MetadataError::IncorrectFormatError),
+                })
+                .collect::<Result<_, _>>()?;
+
+            Ok(Self::new(info_hash, pieces, files))
+        }
+        else { Err(MetadataError::IncorrectFormatError) }
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, MetadataError>
+    {
+        let files = self
+            .files
+            .iter()
+            .map(|file| {
+                let mut dict = HashMap::new();
+                dict.insert(b"length".to_vec(), Value::Int(file.length as i64));
+                dict.insert(b"mtime".to_vec(), Value::Int(file.mtime));
+                Value::Dict(dict)
+            })
+            .collect();
+
+        let mut dict = HashMap::new();
+        dict.insert(b"info hash".to_vec(), Value::Bytes(self.info_hash.to_vec()));
+        dict.insert(b"pieces".to_vec(), Value::Bytes(Message::encode_bitfield(&self.pieces)));
+        dict.insert(b"files".to_vec(), Value::List(files));
+        Ok(serde_bencode::to_bytes(&Value::Dict(dict))?)
+    }
+}
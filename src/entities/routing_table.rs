@@ -0,0 +1,267 @@
+use crate::utils::errors::MetadataError;
+use crate::utils::extract_torrent_metadata::extract_bytes;
+
+use getset::Getters;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+/// Nodes kept per bucket, the `K` of Kademlia.
+pub const BUCKET_SIZE: usize = 8;
+/// A node that missed this many queries in a row is replaced by the next
+/// node that fits its bucket.
+const MAX_FAILURES: u32 = 2;
+const COMPACT_NODE_LEN: usize = 26;
+
+/// 160-bit identifier shared by DHT nodes and info hashes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; 20]);
+
+impl NodeId
+{
+    pub fn new(bytes: [u8; 20]) -> Self
+    {
+        Self(bytes)
+    }
+
+    pub fn random() -> Self
+    {
+        Self(rand::random())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20]
+    {
+        &self.0
+    }
+
+    /// XOR distance; comparing two distances compares them as numbers.
+    pub fn distance(&self, other: &NodeId) -> NodeId
+    {
+        let mut distance = [0; 20];
+
+        for (byte, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(&other.0))
+        {
+            *byte = a ^ b;
+        }
+        NodeId(distance)
+    }
+
+    fn leading_zeros(&self) -> usize
+    {
+        self.0
+            .iter()
+            .position(|byte| *byte != 0)
+            .map_or(160, |index| index * 8 + self.0[index].leading_zeros() as usize)
+    }
+}
+
+impl fmt::Debug for NodeId
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// A DHT node's id and the UDP address it answers on.
+#[derive(Getters, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeInfo
+{
+    #[get = "pub"]
+    id: NodeId,
+    #[get = "pub"]
+    addr: SocketAddrV4,
+}
+
+impl NodeInfo
+{
+    pub fn new(id: NodeId, addr: SocketAddrV4) -> Self
+    {
+        Self { id, addr }
+    }
+
+    /// Parses BEP 5 compact node info: 20 bytes of id, 4 of address and 2
+    /// of port per node. Trailing bytes that do not form a node are ignored.
+    pub fn from_compact(bytes: &[u8]) -> Vec<NodeInfo>
+    {
+        bytes
+            .chunks_exact(COMPACT_NODE_LEN)
+            .map(|chunk| {
+                let mut id = [0; 20];
+                id.copy_from_slice(&chunk[..20]);
+                let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+                let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+                NodeInfo::new(NodeId(id), SocketAddrV4::new(ip, port))
+            })
+            .collect()
+    }
+
+    pub fn to_compact(nodes: &[NodeInfo]) -> Vec<u8>
+    {
+        let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+
+        for node in nodes
+        {
+            bytes.extend_from_slice(&node.id.0);
+            bytes.extend_from_slice(&node.addr.ip().octets());
+            bytes.extend_from_slice(&node.addr.port().to_be_bytes());
+        }
+        bytes
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RoutingEntry
+{
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// Kademlia routing table with one bucket per length of the prefix a node
+/// shares with our id, so we know many nodes close to us and a few far
+/// away. Full buckets only take new nodes in place of failing ones.
+#[derive(Clone, Debug)]
+pub struct RoutingTable
+{
+    own_id: NodeId,
+    buckets: Vec<Vec<RoutingEntry>>,
+}
+
+impl RoutingTable
+{
+    pub fn new(own_id: NodeId) -> Self
+    {
+        Self
+        {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own_id(&self) -> &NodeId
+    {
+        &self.own_id
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize>
+    {
+        let leading_zeros = self.own_id.distance(id).leading_zeros();
+        (leading_zeros < 160).then_some(leading_zeros)
+    }
+
+    /// Records that `node` answered us. Returns whether it is in the table.
+    pub fn insert(&mut self, node: NodeInfo) -> bool
+    {
+        let Some(index) = self.bucket_index(&node.id) else { return false };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id)
+        {
+            entry.node = node;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return true;
+        }
+
+        if bucket.len() >= BUCKET_SIZE
+        {
+            match bucket.iter().position(|entry| entry.failures >= MAX_FAILURES)
+            {
+                Some(failing) => {
+                    bucket.remove(failing);
+                }
+                None => return false,
+            }
+        }
+
+        bucket.push(RoutingEntry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        });
+        true
+    }
+
+    /// Records that the node with `id` did not answer a query.
+    pub fn mark_failed(&mut self, id: &NodeId)
+    {
+        let Some(index) = self.bucket_index(id) else { return };
+
+        if let Some(entry) = self.buckets[index].iter_mut().find(|entry| entry.node.id == *id)
+        {
+            entry.failures += 1;
+        }
+    }
+
+    /// The `count` responsive nodes closest to `target`.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo>
+    {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes we have not heard from within `max_age`, which should be
+    /// pinged to find out whether they are still around.
+    pub fn questionable(&self, max_age: Duration) -> Vec<NodeInfo>
+    {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES && entry.last_seen.elapsed() > max_age)
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    /// Restores a table saved by `as_bytes`. Saved nodes count as seen now
+    /// and are dropped by the usual failure handling if they are gone.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError>
+    {
+        let value: Value = serde_bencode::from_bytes(bytes)?;
+
+        if let Value::Dict(dict) = value
+        {
+            let own_id: [u8; 20] = extract_bytes("id", &dict)?
+                .try_into()
+                .map_err(|_| MetadataError::FieldError("id".to_string()))?;
+            let mut table = Self::new(NodeId(own_id));
+
+            for node in NodeInfo::from_compact(&extract_bytes("nodes", &dict)?)
+            {
+                table.insert(node);
+            }
+            Ok(table)
+        }
+        else { Err(MetadataError::IncorrectFormatError) }
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, MetadataError>
+    {
+        let nodes: Vec<NodeInfo> = self.buckets.iter().flatten().map(|entry| entry.node).collect();
+
+        let mut dict = HashMap::new();
+        dict.insert(b"id".to_vec(), Value::Bytes(self.own_id.0.to_vec()));
+        dict.insert(b"nodes".to_vec(), Value::Bytes(NodeInfo::to_compact(&nodes)));
+        Ok(serde_bencode::to_bytes(&Value::Dict(dict))?)
+    }
+}
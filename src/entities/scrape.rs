@@ -0,0 +1,37 @@
+use getset::Getters;
+
+#[derive(Getters, Clone, Debug)]
+pub struct ScrapeResponse
+{
+    #[get = "pub"]
+    info_hash: [u8; 20],
+    #[get = "pub"]
+    seeders: i64,
+    #[get = "pub"]
+    completed: i64,
+    #[get = "pub"]
+    leechers: i64,
+    #[get = "pub"]
+    name: Option<String>,
+}
+
+impl ScrapeResponse
+{
+    pub fn new(info_hash: [u8; 20], seeders: i64, completed: i64, leechers: i64) -> Self
+    {
+        Self
+        {
+            info_hash,
+            seeders,
+            completed,
+            leechers,
+            name: None,
+        }
+    }
+
+    pub fn with_name(mut self, name: Option<String>) -> Self
+    {
+        self.name = name;
+        self
+    }
+}
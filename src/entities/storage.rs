@@ -0,0 +1,48 @@
+use crate::utils::errors::TorrentError;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// Where downloaded pieces are kept. Blocks are addressed by piece index and
+/// offset within the piece; implementations map that onto their own layout.
+/// The download engine only talks to storage through this trait, so
+/// applications can plug in their own backend.
+#[async_trait]
+pub trait Storage: Send + Sync
+{
+    /// Reads `length` bytes at `begin` within the piece, or `None` if the
+    /// backend does not hold that range.
+    async fn read_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, TorrentError>;
+
+    async fn write_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> Result<(), TorrentError>;
+
+    async fn flush(&self) -> Result<(), TorrentError>
+    {
+        Ok(())
+    }
+
+    /// Creates the backing store if needed and sets it to the torrent's size.
+    async fn truncate(&self) -> Result<(), TorrentError>;
+
+    /// Returns the pieces an earlier run recorded as verified, provided the
+    /// backend keeps resume data and the stored content has not changed since.
+    async fn load_resume(&self, _info_hash: &[u8; 20]) -> Result<Option<Vec<bool>>, TorrentError>
+    {
+        Ok(None)
+    }
+
+    async fn save_resume(&self, _info_hash: &[u8; 20], _pieces: &[bool]) -> Result<(), TorrentError>
+    {
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
+use crate::entities::announce_list::AnnounceList;
+use crate::entities::merkle::{
+    file_root, merkle_proof, pad_root, piece_root, Sha256Hash, MERKLE_BLOCK_SIZE,
+};
+use crate::entities::message::{HashRequest, Message};
+
 use getset::Getters;
 use reqwest::Url;
 use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// BEP 52 caps the hashes one request may ask for.
+const MAX_HASH_REQUEST_LEN: u32 = 512;
 
 #[derive(Getters, Clone, Debug)]
 pub struct Torrent
@@ -8,22 +20,161 @@ pub struct Torrent
     #[get = "pub"]
     announce: Url,
     #[get = "pub"]
+    announce_list: AnnounceList,
+    #[get = "pub"]
     info: TorrentInfo,
     #[get = "pub"]
     info_hash: [u8; 20],
+    #[get = "pub"]
+    info_hash_v2: Option<Sha256Hash>,
+    #[get = "pub"]
+    piece_layers: HashMap<Sha256Hash, Vec<Sha256Hash>>,
+    #[get = "pub"]
+    comment: Option<String>,
+    #[get = "pub"]
+    created_by: Option<String>,
+    /// Seconds since the Unix epoch.
+    #[get = "pub"]
+    creation_date: Option<i64>,
+    /// BEP 19 HTTP servers holding the same content, from `url-list`.
+    #[get = "pub"]
+    web_seeds: Vec<Url>,
 }
 
 impl Torrent
 {
+    /// `info_hash` is what the handshake carries: the SHA-1 info hash for
+    /// v1 and hybrid torrents, the truncated SHA-256 one for v2 torrents.
     pub fn new(announce: Url, info: TorrentInfo, info_hash: [u8; 20]) -> Self
     {
         Self
         {
+            announce_list: AnnounceList::new(vec![vec![announce.clone()]]),
             announce,
             info,
             info_hash,
+            info_hash_v2: None,
+            piece_layers: HashMap::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            web_seeds: Vec::new(),
         }
     }
+
+    /// The full v2 info hash and the piece layers of the files longer than
+    /// one piece, keyed by the files' `pieces root`.
+    pub fn with_v2(
+        mut self,
+        info_hash_v2: Sha256Hash,
+        piece_layers: HashMap<Sha256Hash, Vec<Sha256Hash>>,
+    ) -> Self
+    {
+        self.info_hash_v2 = Some(info_hash_v2);
+        self.piece_layers = piece_layers;
+        self
+    }
+
+    pub fn with_announce_list(mut self, announce_list: AnnounceList) -> Self
+    {
+        if !announce_list.tiers().is_empty()
+        {
+            self.announce_list = announce_list;
+        }
+        self
+    }
+
+    pub fn with_comment(mut self, comment: Option<String>) -> Self
+    {
+        self.comment = comment;
+        self
+    }
+
+    pub fn with_created_by(mut self, created_by: Option<String>) -> Self
+    {
+        self.created_by = created_by;
+        self
+    }
+
+    pub fn with_creation_date(mut self, creation_date: Option<i64>) -> Self
+    {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn with_web_seeds(mut self, web_seeds: Vec<Url>) -> Self
+    {
+        self.web_seeds = web_seeds;
+        self
+    }
+
+    /// Checks a piece against every hash the torrent has for it, so hybrid
+    /// torrents must validate as both v1 and v2.
+    pub fn verify_piece(&self, piece_index: usize, data: &[u8]) -> bool
+    {
+        let begin = piece_index * 20;
+        let valid_v1 = !self.info.has_v1()
+            || self.info.pieces().get(begin..begin + 20).is_some_and(|hash| {
+                let mut hasher = Sha1::new();
+                hasher.update(data);
+                hasher.finalize().as_slice() == hash
+            });
+
+        valid_v1 && (!self.info.has_v2() || self.verify_piece_v2(piece_index, data))
+    }
+
+    /// Pieces of hybrid torrents whose file has no piece layer, as when the
+    /// metadata came from a magnet link, rely on the v1 hash alone.
+    fn verify_piece_v2(&self, piece_index: usize, data: &[u8]) -> bool
+    {
+        let piece_length = self.info.piece_length as usize;
+        let begin = piece_index as u64 * piece_length as u64;
+        let Some((offset, file)) = self.info.file_tree_at(begin) else { return false };
+        let Some(pieces_root) = file.pieces_root else { return false };
+
+        let data = &data[..data.len().min((offset + file.length as u64 - begin) as usize)];
+
+        if file.length as usize <= piece_length
+        {
+            return file_root(data) == pieces_root;
+        }
+        match self.piece_layers.get(&pieces_root)
+        {
+            Some(layer) => {
+                let index = ((begin - offset) / piece_length as u64) as usize;
+                layer.get(index) == Some(&piece_root(data, piece_length))
+            }
+            None => self.info.has_v1(),
+        }
+    }
+
+    /// Answers a BEP 52 hash request from the piece layers. Only the piece
+    /// layer itself is served; other layers are rejected.
+    pub fn answer_hash_request(&self, request: &HashRequest) -> Message
+    {
+        let piece_length = self.info.piece_length as usize;
+        let piece_layer = (piece_length / MERKLE_BLOCK_SIZE).trailing_zeros();
+        let Some(layer) = self.piece_layers.get(&request.pieces_root)
+        else { return Message::HashReject(*request) };
+
+        let (index, length) = (request.index as usize, request.length as usize);
+        let valid = request.base_layer == piece_layer
+            && request.length.is_power_of_two()
+            && request.length <= MAX_HASH_REQUEST_LEN
+            && index.is_multiple_of(length)
+            && index + length <= layer.len().next_power_of_two();
+
+        if !valid
+        {
+            return Message::HashReject(*request);
+        }
+
+        let pad = pad_root(piece_length);
+        let mut hashes: Vec<Sha256Hash> =
+            (index..index + length).map(|i| layer.get(i).copied().unwrap_or(pad)).collect();
+        hashes.extend(merkle_proof(layer, pad, index, length, request.proof_layers as usize));
+        Message::Hashes { request: *request, hashes }
+    }
 }
 
 #[derive(Getters, Clone, Debug)]
@@ -39,6 +190,13 @@ pub struct TorrentInfo
     length: i64,
     #[get = "pub"]
     files: Vec<FileInfo>,
+    #[get = "pub"]
+    meta_version: i64,
+    #[get = "pub"]
+    file_tree: Vec<FileInfo>,
+    /// BEP 27: peers may only come from the trackers of the torrent.
+    #[get = "pub"]
+    private: bool,
 }
 
 impl TorrentInfo
@@ -58,9 +216,132 @@ impl TorrentInfo
             pieces,
             length,
             files,
+            meta_version: 1,
+            file_tree: Vec::new(),
+            private: false,
         }
     }
 
+    pub fn with_private(mut self, private: bool) -> Self
+    {
+        self.private = private;
+        self
+    }
+
+    /// Adds the BEP 52 file tree, flattened in path order, to a v2 or
+    /// hybrid torrent.
+    pub fn with_file_tree(mut self, file_tree: Vec<FileInfo>) -> Self
+    {
+        self.meta_version = 2;
+        self.file_tree = file_tree;
+        self
+    }
+
+    /// Whether the torrent carries v1 `pieces`, alone or in a hybrid.
+    pub fn has_v1(&self) -> bool
+    {
+        !self.pieces.is_empty()
+    }
+
+    pub fn has_v2(&self) -> bool
+    {
+        self.meta_version == 2 && !self.file_tree.is_empty()
+    }
+
+    /// Whether checking the pieces takes the piece layers kept outside the
+    /// info dictionary: a v2-only torrent with a file longer than a piece.
+    pub fn needs_piece_layers(&self) -> bool
+    {
+        !self.has_v1() && self.file_tree.iter().any(|file| file.length > self.piece_length)
+    }
+
+    /// The file of the tree holding the piece stream byte at `offset`, and
+    /// where the file starts. Every file starts on a piece boundary.
+    pub fn file_tree_at(&self, offset: u64) -> Option<(u64, &FileInfo)>
+    {
+        let piece_length = self.piece_length as u64;
+        let mut start = 0;
+
+        for file in &self.file_tree
+        {
+            let length = file.length as u64;
+
+            if offset >= start && offset < start + length
+            {
+                return Some((start, file));
+            }
+            start += length.div_ceil(piece_length) * piece_length;
+        }
+        None
+    }
+
+    /// The pieces holding the file at `path`, written with `/` between its
+    /// components, or `None` when the torrent has no such file.
+    pub fn file_pieces(&self, path: &str) -> Option<Range<usize>>
+    {
+        let piece_length = self.piece_length as usize;
+
+        if self.files.is_empty()
+        {
+            return (path == self.name).then(|| 0..self.num_pieces());
+        }
+
+        let mut offset = 0;
+
+        for file in &self.files
+        {
+            let length = file.length as usize;
+
+            if !file.padding && file.path.join("/") == path
+            {
+                let first = offset / piece_length;
+                // An empty file holds no byte of any piece.
+                let end = if length == 0
+                {
+                    first
+                }
+                else { (offset + length).div_ceil(piece_length) };
+                return Some(first..end);
+            }
+            offset += length;
+        }
+        None
+    }
+
+    /// Single-file torrents carry `length`; multi-file ones only list the
+    /// sizes of their files.
+    pub fn total_length(&self) -> i64
+    {
+        if self.length > 0
+        {
+            self.length
+        }
+        else { self.files.iter().map(|file| file.length).sum() }
+    }
+
+    pub fn num_pieces(&self) -> usize
+    {
+        if self.has_v1()
+        {
+            self.pieces.len() / 20
+        }
+        else { (self.total_length() as usize).div_ceil(self.piece_length as usize) }
+    }
+
+    /// Every piece has `piece length` bytes except the last one, which holds
+    /// whatever remains. In v2 torrents that applies to every file, while
+    /// hybrids keep the v1 pieces that run into the padding files.
+    pub fn piece_size(&self, piece_index: usize) -> usize
+    {
+        let begin = piece_index as i64 * self.piece_length;
+        let end = match self.file_tree_at(begin as u64).filter(|_| !self.has_v1())
+        {
+            Some((offset, file)) => offset as i64 + file.length,
+            None => self.total_length(),
+        };
+        (end - begin).clamp(0, self.piece_length) as usize
+    }
+
     pub fn piece_hashes(&self) -> Vec<[u8; 20]>
     {
         self.pieces
@@ -81,12 +362,38 @@ pub struct FileInfo
     length: i64,
     #[get = "pub"]
     path: Vec<String>,
+    /// BEP 47 padding files only align the next file to a piece boundary
+    /// and are never written to disk.
+    #[get = "pub"]
+    #[serde(default)]
+    padding: bool,
+    #[get = "pub"]
+    #[serde(default)]
+    pieces_root: Option<Sha256Hash>,
 }
 
 impl FileInfo
 {
     pub fn new(length: i64, path: Vec<String>) -> Self
     {
-        Self { length, path }
+        Self
+        {
+            length,
+            path,
+            padding: false,
+            pieces_root: None,
+        }
+    }
+
+    pub fn with_padding(mut self, padding: bool) -> Self
+    {
+        self.padding = padding;
+        self
+    }
+
+    pub fn with_pieces_root(mut self, pieces_root: Option<Sha256Hash>) -> Self
+    {
+        self.pieces_root = pieces_root;
+        self
     }
 }
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Byte counters shared between the download engine and the announcer so
+/// trackers are told the real progress of the transfer.
+#[derive(Debug)]
+pub struct TransferStats
+{
+    uploaded: AtomicI64,
+    downloaded: AtomicI64,
+    left: AtomicI64,
+    wasted: AtomicI64,
+}
+
+impl TransferStats
+{
+    pub fn new(left: i64) -> Self
+    {
+        Self
+        {
+            uploaded: AtomicI64::new(0),
+            downloaded: AtomicI64::new(0),
+            left: AtomicI64::new(left),
+            wasted: AtomicI64::new(0),
+        }
+    }
+
+    pub fn uploaded(&self) -> i64
+    {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> i64
+    {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> i64
+    {
+        self.left.load(Ordering::Relaxed).max(0)
+    }
+
+    /// Bytes of blocks that arrived late or twice and were thrown away.
+    pub fn wasted(&self) -> i64
+    {
+        self.wasted.load(Ordering::Relaxed)
+    }
+
+    pub fn add_uploaded(&self, bytes: i64)
+    {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a verified piece, which also shrinks the amount left.
+    pub fn add_downloaded(&self, bytes: i64)
+    {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        self.left.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_wasted(&self, bytes: i64)
+    {
+        self.wasted.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
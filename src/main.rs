@@ -1,23 +1,21 @@
+mod cli;
 mod entities;
 pub mod usecases;
 pub mod utils;
 
-use crate::usecases::parse_torrent_file::{
-    parse_torrent_file, print_torrent_info, process_torrent,
-};
-use std::path::PathBuf;
+use crate::cli::{run, Cli};
+use clap::Parser;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main()
+async fn main() -> ExitCode
 {
-    let file_path = "./src/test3.torrent";
-
-    match parse_torrent_file(PathBuf::from(file_path)).await
+    match run(Cli::parse()).await
     {
-        Ok(torrent) => {
-            print_torrent_info(&torrent).await;
-            process_torrent(&torrent).await;
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
         }
-        Err(e) => { eprintln!("Failed to parse torrent file: {}", e); }
     }
 }
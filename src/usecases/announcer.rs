@@ -0,0 +1,186 @@
+use crate::entities::announce_list::AnnounceList;
+use crate::entities::peer::{Peer, TrackerEvent, TrackerRequest, TrackerResponse};
+use crate::entities::torrent::Torrent;
+use crate::entities::transfer_stats::TransferStats;
+use crate::usecases::peer_tracker::announce_to_tiers;
+use crate::utils::errors::{TorrentError, TrackerError};
+use crate::utils::local_address::local_addresses;
+
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+const MIN_ANNOUNCE_INTERVAL: u64 = 30;
+const RETRY_INTERVAL: u64 = 60;
+const STOPPED_TIMEOUT: u64 = 5;
+const COMPLETED_TIMEOUT: u64 = 30;
+
+enum AnnouncerCommand
+{
+    Completed,
+    Stop,
+}
+
+/// Background task that keeps the trackers informed for the whole transfer
+/// and forwards the peers of every announce to the download.
+pub struct Announcer
+{
+    commands: mpsc::UnboundedSender<AnnouncerCommand>,
+    task: JoinHandle<()>,
+}
+
+impl Announcer
+{
+    /// Sends the `started` announce and spawns the re-announce loop. The
+    /// result of the first announce is returned directly so callers can use
+    /// its peers right away; when it failed the loop keeps retrying it.
+    /// `port` is the port peers can reach us on.
+    pub async fn start(
+        torrent: &Torrent,
+        port: u16,
+        stats: Arc<TransferStats>,
+        peer_tx: mpsc::Sender<Vec<Peer>>,
+    ) -> (Self, Result<TrackerResponse, TorrentError>)
+    {
+        let mut announce_list = torrent.announce_list().clone();
+        let tracker_request = TrackerRequest::new(torrent)
+            .with_port(port)
+            .with_local_addresses(&local_addresses());
+        let request = transfer_request(&tracker_request, &stats, Some(TrackerEvent::Started));
+        let first = announce_to_tiers(&mut announce_list, &request).await;
+        let interval = first.as_ref().map_or(Duration::from_secs(RETRY_INTERVAL), next_interval);
+
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(announce_loop(
+            announce_list,
+            tracker_request,
+            stats,
+            peer_tx,
+            command_rx,
+            interval,
+            first.is_ok(),
+        ));
+        (Self { commands, task }, first)
+    }
+
+    pub fn completed(&self)
+    {
+        let _ = self.commands.send(AnnouncerCommand::Completed);
+    }
+
+    /// Sends the `stopped` announce and waits for the loop to finish. A
+    /// `completed` announce requested before is sent first.
+    pub async fn stop(self)
+    {
+        let _ = self.commands.send(AnnouncerCommand::Stop);
+        let _ = self.task.await;
+    }
+}
+
+/// Re-announces every `interval`. Until some tracker has heard `started`,
+/// the regular announces repeat it.
+async fn announce_loop(
+    mut announce_list: AnnounceList,
+    tracker_request: TrackerRequest,
+    stats: Arc<TransferStats>,
+    peer_tx: mpsc::Sender<Vec<Peer>>,
+    mut commands: mpsc::UnboundedReceiver<AnnouncerCommand>,
+    mut interval: Duration,
+    mut started: bool,
+)
+{
+    let mut pending = None;
+
+    loop
+    {
+        let event = match pending.take()
+        {
+            Some(event) => Some(event),
+            None => tokio::select! {
+                _ = sleep(interval) => None,
+                command = commands.recv() => Some(command_event(command)),
+            },
+        };
+        let event = event.or((!started).then_some(TrackerEvent::Started));
+        let request = transfer_request(&tracker_request, &stats, event);
+
+        let result = match event
+        {
+            Some(TrackerEvent::Stopped) => {
+                let request = request.with_num_want(0);
+                let stopped = announce_to_tiers(&mut announce_list, &request);
+                let _ = timeout(Duration::from_secs(STOPPED_TIMEOUT), stopped).await;
+                return;
+            }
+            // A `stop` right behind `completed` must not cancel it, so
+            // commands wait until it is answered or times out.
+            Some(TrackerEvent::Completed) => {
+                let completed = announce_to_tiers(&mut announce_list, &request);
+                match timeout(Duration::from_secs(COMPLETED_TIMEOUT), completed).await
+                {
+                    Ok(result) => result,
+                    Err(_) => Err(TrackerError::Timeout(request.tracker_url().to_string()).into()),
+                }
+            }
+            // UDP retransmissions can keep an announce going for minutes; a
+            // command arriving meanwhile abandons it and is handled right
+            // away.
+            _ => tokio::select! {
+                result = announce_to_tiers(&mut announce_list, &request) => result,
+                command = commands.recv() => {
+                    pending = Some(command_event(command));
+                    continue;
+                }
+            },
+        };
+
+        match result
+        {
+            Ok(tracker_response) => {
+                started = true;
+                interval = next_interval(&tracker_response);
+                // A receiver that stopped reading, like the seeder's, must
+                // not hold up the loop or `stop`.
+                let _ = peer_tx.try_send(tracker_response.peers().clone());
+            }
+            Err(e) => {
+                eprintln!("Re-announce failed - Error: {}", e);
+                interval = Duration::from_secs(RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// A closed command channel means the `Announcer` was dropped.
+fn command_event(command: Option<AnnouncerCommand>) -> TrackerEvent
+{
+    match command
+    {
+        Some(AnnouncerCommand::Completed) => TrackerEvent::Completed,
+        Some(AnnouncerCommand::Stop) | None => TrackerEvent::Stopped,
+    }
+}
+
+fn transfer_request(
+    tracker_request: &TrackerRequest,
+    stats: &TransferStats,
+    event: Option<TrackerEvent>,
+) -> TrackerRequest
+{
+    tracker_request
+        .clone()
+        .with_transfer(stats.uploaded(), stats.downloaded(), stats.left())
+        .with_event(event)
+}
+
+/// Trackers ask for `interval` between announces but never allow less than
+/// `min interval`.
+fn next_interval(tracker_response: &TrackerResponse) -> Duration
+{
+    let interval = (*tracker_response.interval())
+        .max(tracker_response.min_interval().unwrap_or(0))
+        .max(MIN_ANNOUNCE_INTERVAL as i64);
+    Duration::from_secs(interval as u64)
+}
@@ -0,0 +1,270 @@
+use crate::entities::choker::{ChokeCandidate, Choker};
+use crate::entities::transfer_stats::TransferStats;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, Notify};
+use tokio::time::{interval, Duration};
+
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke rotates every third rechoke, i.e. every 30 seconds.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// Byte counters and interest of one peer, updated by its session.
+#[derive(Default)]
+struct PeerCounters
+{
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    interested: AtomicBool,
+}
+
+struct ChokeEntry
+{
+    counters: Arc<PeerCounters>,
+    choke_tx: watch::Sender<bool>,
+    last_downloaded: u64,
+    last_uploaded: u64,
+    download_rate: u64,
+    upload_rate: u64,
+}
+
+struct Shared
+{
+    peers: Mutex<HashMap<u64, ChokeEntry>>,
+    next_id: AtomicU64,
+    changed: Notify,
+}
+
+/// Runs the choker for the sessions of one torrent. Sessions register to
+/// report their traffic and interest and are told when to choke or unchoke.
+#[derive(Clone)]
+pub struct ChokeManager
+{
+    shared: Arc<Shared>,
+}
+
+impl Default for ChokeManager
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl ChokeManager
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            shared: Arc::new(Shared {
+                peers: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Adds a session; its peer starts out choked.
+    pub fn register(&self) -> ChokeHandle
+    {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let counters = Arc::new(PeerCounters::default());
+        let (choke_tx, choke_rx) = watch::channel(true);
+
+        self.shared.peers.lock().unwrap().insert(
+            id,
+            ChokeEntry {
+                counters: Arc::clone(&counters),
+                choke_tx,
+                last_downloaded: 0,
+                last_uploaded: 0,
+                download_rate: 0,
+                upload_rate: 0,
+            },
+        );
+
+        ChokeHandle
+        {
+            id,
+            counters,
+            choke_rx,
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Recomputes the unchoked peers every 10 seconds, ranking them by
+    /// download rate while `stats` has bytes left and by upload rate once
+    /// seeding. Peers joining, leaving or becoming interested trigger a
+    /// rechoke with the last measured rates so free slots are filled
+    /// without waiting. Runs until the task is dropped.
+    pub async fn run(&self, mut choker: Choker, stats: Arc<TransferStats>)
+    {
+        let mut ticker = interval(RECHOKE_INTERVAL);
+        let mut round: u32 = 0;
+
+        loop
+        {
+            let rotate = tokio::select! {
+                _ = ticker.tick() => {
+                    self.measure_rates();
+                    round = round.wrapping_add(1);
+                    round % OPTIMISTIC_ROUNDS == 1
+                }
+                _ = self.shared.changed.notified() => false,
+            };
+            self.rechoke(&mut choker, stats.left() == 0, rotate);
+        }
+    }
+
+    fn measure_rates(&self)
+    {
+        let seconds = RECHOKE_INTERVAL.as_secs();
+
+        for entry in self.shared.peers.lock().unwrap().values_mut()
+        {
+            let downloaded = entry.counters.downloaded.load(Ordering::Relaxed);
+            let uploaded = entry.counters.uploaded.load(Ordering::Relaxed);
+            entry.download_rate = (downloaded - entry.last_downloaded) / seconds;
+            entry.upload_rate = (uploaded - entry.last_uploaded) / seconds;
+            entry.last_downloaded = downloaded;
+            entry.last_uploaded = uploaded;
+        }
+    }
+
+    fn rechoke(&self, choker: &mut Choker, seeding: bool, rotate: bool)
+    {
+        let peers = self.shared.peers.lock().unwrap();
+        let candidates: Vec<ChokeCandidate> = peers
+            .iter()
+            .map(|(id, entry)| ChokeCandidate {
+                id: *id,
+                interested: entry.counters.interested.load(Ordering::Relaxed),
+                rate: if seeding { entry.upload_rate } else { entry.download_rate },
+            })
+            .collect();
+        let unchoked = choker.unchoke(&candidates, rotate);
+
+        for (id, entry) in peers.iter()
+        {
+            let choke = !unchoked.contains(id);
+            entry.choke_tx.send_if_modified(|current| {
+                let modified = *current != choke;
+                *current = choke;
+                modified
+            });
+        }
+    }
+}
+
+/// A session's registration with the `ChokeManager`. Dropping it removes
+/// the peer from the choker.
+pub struct ChokeHandle
+{
+    id: u64,
+    counters: Arc<PeerCounters>,
+    choke_rx: watch::Receiver<bool>,
+    shared: Arc<Shared>,
+}
+
+impl ChokeHandle
+{
+    pub fn add_downloaded(&self, bytes: usize)
+    {
+        self.counters.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: usize)
+    {
+        self.counters.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_interested(&self, interested: bool)
+    {
+        if self.counters.interested.swap(interested, Ordering::Relaxed) != interested
+        {
+            self.shared.changed.notify_one();
+        }
+    }
+
+    /// Waits until the choker changes its decision for this peer and
+    /// returns whether the peer should now be choked. Cancel-safe.
+    pub async fn changed(&mut self) -> bool
+    {
+        if self.choke_rx.changed().await.is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+        *self.choke_rx.borrow_and_update()
+    }
+}
+
+impl Drop for ChokeHandle
+{
+    fn drop(&mut self)
+    {
+        self.shared.peers.lock().unwrap().remove(&self.id);
+        self.shared.changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn ranks_by_download_rate_while_leeching_and_upload_rate_when_seeding()
+    {
+        // Whether we are seeding and which of the two peers gets the slot.
+        let cases = [(false, 0), (true, 1)];
+
+        for (seeding, expected) in cases
+        {
+            let manager = ChokeManager::new();
+            let handles = [manager.register(), manager.register()];
+
+            for handle in &handles
+            {
+                handle.set_interested(true);
+            }
+            // The first peer sends us a lot, the second takes a lot.
+            handles[0].add_downloaded(100_000);
+            handles[0].add_uploaded(1_000);
+            handles[1].add_downloaded(1_000);
+            handles[1].add_uploaded(100_000);
+
+            manager.measure_rates();
+            manager.rechoke(&mut Choker::new(1, 0), seeding, false);
+
+            let choked: Vec<bool> =
+                handles.iter().map(|handle| *handle.choke_rx.borrow()).collect();
+            let unchoked: Vec<usize> = (0..2).filter(|index| !choked[*index]).collect();
+            assert_eq!(unchoked, vec![expected], "seeding: {}", seeding);
+        }
+    }
+
+    #[test]
+    fn dropped_sessions_free_their_slot()
+    {
+        let manager = ChokeManager::new();
+        let mut choker = Choker::new(1, 0);
+        let first = manager.register();
+        let second = manager.register();
+        first.set_interested(true);
+        first.add_downloaded(50_000);
+        second.set_interested(true);
+        manager.measure_rates();
+
+        manager.rechoke(&mut choker, false, false);
+        assert!(!*first.choke_rx.borrow());
+        assert!(*second.choke_rx.borrow());
+
+        drop(first);
+        manager.rechoke(&mut choker, false, false);
+        assert_eq!(manager.shared.peers.lock().unwrap().len(), 1);
+        assert!(!*second.choke_rx.borrow());
+    }
+}
@@ -0,0 +1,523 @@
+use crate::entities::announce_list::AnnounceList;
+use crate::entities::create_config::{CreateConfig, TorrentLayout};
+use crate::entities::merkle::{file_root, layer_root, piece_root, Sha256Hash, MERKLE_BLOCK_SIZE};
+use crate::entities::torrent::{FileInfo, Torrent, TorrentInfo};
+use crate::usecases::parse_torrent_file::{
+    aligned_files, calculate_info_hash, calculate_info_hash_v2,
+};
+use crate::utils::errors::{FileError, MetadataError, TorrentError};
+
+use anyhow::Result;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::thread::available_parallelism;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::task::JoinSet;
+
+const MIN_PIECE_LENGTH: i64 = MERKLE_BLOCK_SIZE as i64;
+const MAX_PIECE_LENGTH: i64 = 16 * 1024 * 1024;
+/// Derived piece lengths keep torrents at or below this many pieces.
+const TARGET_PIECES: u64 = 1024;
+
+/// A file to include and its path inside the torrent.
+struct SourceFile
+{
+    path: PathBuf,
+    components: Vec<String>,
+    length: u64,
+}
+
+/// The hashes of one piece: SHA-1 for v1 and, for v2, the piece layer hash
+/// of its file or the whole root of a file no longer than one piece.
+struct PieceHash
+{
+    file_index: usize,
+    sha1: Option<[u8; 20]>,
+    root: Option<Sha256Hash>,
+}
+
+/// Builds a torrent for the file or directory at `path`. Directories are
+/// walked recursively in path order; pieces are hashed on every core while
+/// the next ones are read.
+pub async fn create_torrent(path: &Path, config: &CreateConfig) -> Result<Torrent, TorrentError>
+{
+    let announce = config
+        .trackers()
+        .first()
+        .and_then(|tier| tier.first())
+        .cloned()
+        .ok_or(MetadataError::FieldError("announce".to_string()))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(MetadataError::FieldError("name".to_string()))?
+        .to_string();
+
+    let metadata = std::fs::metadata(path).map_err(FileError::IoError)?;
+    let sources = if metadata.is_dir()
+    {
+        let mut sources = Vec::new();
+        collect_files(path, &mut Vec::new(), &mut sources)?;
+        sources
+    }
+    else
+    {
+        vec![SourceFile
+        {
+            path: path.to_path_buf(),
+            components: vec![name.clone()],
+            length: metadata.len(),
+        }]
+    };
+
+    let total_length: u64 = sources.iter().map(|file| file.length).sum();
+
+    if total_length == 0
+    {
+        return Err(MetadataError::InvalidLength.into());
+    }
+
+    let piece_length = match config.piece_length()
+    {
+        Some(piece_length) if is_valid_piece_length(*piece_length) => *piece_length,
+        Some(_) => return Err(MetadataError::FieldError("piece length".to_string()).into()),
+        None => choose_piece_length(total_length),
+    };
+    let layout = *config.layout();
+    let hashes = hash_pieces(&sources, piece_length as usize, layout).await?;
+
+    let pieces: Vec<u8> = hashes.iter().filter_map(|hash| hash.sha1).flatten().collect();
+    let mut file_tree = Vec::with_capacity(sources.len());
+    let mut piece_layers = HashMap::new();
+
+    for (file_index, file) in sources.iter().enumerate()
+    {
+        let layer: Vec<Sha256Hash> = hashes
+            .iter()
+            .filter(|hash| hash.file_index == file_index)
+            .filter_map(|hash| hash.root)
+            .collect();
+        let pieces_root = match layer.as_slice()
+        {
+            [] => None,
+            [root] if file.length <= piece_length as u64 => Some(*root),
+            _ => {
+                let root = layer_root(&layer, piece_length as usize);
+                piece_layers.insert(root, layer);
+                Some(root)
+            }
+        };
+        file_tree.push(
+            FileInfo::new(file.length as i64, file.components.clone())
+                .with_pieces_root(pieces_root),
+        );
+    }
+
+    let single_file = !metadata.is_dir();
+    let info = match layout
+    {
+        TorrentLayout::V1 if single_file => {
+            TorrentInfo::new(name, piece_length, pieces, total_length as i64, Vec::new())
+        }
+        TorrentLayout::V1 => TorrentInfo::new(name, piece_length, pieces, 0, file_tree),
+        TorrentLayout::V2 | TorrentLayout::Hybrid => {
+            let (length, files) = aligned_files(&name, &file_tree, piece_length);
+            TorrentInfo::new(name, piece_length, pieces, length, files).with_file_tree(file_tree)
+        }
+    };
+    let info = info.with_private(*config.private());
+
+    let info_dict = encode_info(&info);
+    let info_hash_v2 = if info.has_v2() { Some(calculate_info_hash_v2(&info_dict)?) } else { None };
+    let info_hash = match info_hash_v2
+    {
+        Some(hash) if !info.has_v1() => hash[..20].try_into().unwrap(),
+        _ => calculate_info_hash(&info_dict)?,
+    };
+
+    let torrent = Torrent::new(announce, info, info_hash)
+        .with_announce_list(AnnounceList::new(config.trackers().clone()))
+        .with_comment(config.comment().clone())
+        .with_created_by(config.created_by().clone())
+        .with_creation_date(*config.creation_date())
+        .with_web_seeds(config.web_seeds().clone());
+
+    Ok(match info_hash_v2
+    {
+        Some(info_hash_v2) => torrent.with_v2(info_hash_v2, piece_layers),
+        None => torrent,
+    })
+}
+
+/// The smallest power of two that keeps the torrent within `TARGET_PIECES`
+/// pieces, bounded to what clients commonly accept.
+pub fn choose_piece_length(total_length: u64) -> i64
+{
+    let piece_length = total_length.div_ceil(TARGET_PIECES).next_power_of_two() as i64;
+    piece_length.clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// v2 needs whole merkle subtrees per piece; v1 torrents are held to the
+/// same rule so any layout can be created with any piece length.
+fn is_valid_piece_length(piece_length: i64) -> bool
+{
+    piece_length >= MIN_PIECE_LENGTH && piece_length.count_ones() == 1
+}
+
+/// Adds the files below `dir` to `sources`, sorting the entries of every
+/// directory by name so the order matches the v2 file tree.
+fn collect_files(
+    dir: &Path,
+    components: &mut Vec<String>,
+    sources: &mut Vec<SourceFile>,
+) -> Result<(), TorrentError>
+{
+    let mut entries = std::fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(FileError::IoError)?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries
+    {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| MetadataError::FieldError("path".to_string()))?;
+        let metadata = std::fs::metadata(entry.path()).map_err(FileError::IoError)?;
+        components.push(name);
+
+        if metadata.is_dir()
+        {
+            collect_files(&entry.path(), components, sources)?;
+        }
+        else
+        {
+            sources.push(SourceFile
+            {
+                path: entry.path(),
+                components: components.clone(),
+                length: metadata.len(),
+            });
+        }
+        components.pop();
+    }
+    Ok(())
+}
+
+/// Reads the files in order and hashes their pieces, with at most one piece
+/// per core in flight. v1 pieces run across file boundaries; v2 and hybrid
+/// pieces end with their file.
+async fn hash_pieces(
+    sources: &[SourceFile],
+    piece_length: usize,
+    layout: TorrentLayout,
+) -> Result<Vec<PieceHash>, TorrentError>
+{
+    let workers = available_parallelism().map_or(1, |workers| workers.get());
+    let mut jobs = JoinSet::new();
+    let mut hashes = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+
+    for (file_index, file) in sources.iter().enumerate()
+    {
+        let mut reader = File::open(&file.path).await.map_err(FileError::IoError)?;
+        let mut remaining = file.length;
+        let last_file = file_index + 1 == sources.len();
+
+        while remaining > 0
+        {
+            let start = piece.len();
+            let length = remaining.min((piece_length - start) as u64) as usize;
+            piece.resize(start + length, 0);
+            reader.read_exact(&mut piece[start..]).await?;
+            remaining -= length as u64;
+
+            let file_ends = remaining == 0 && layout != TorrentLayout::V1;
+
+            if piece.len() < piece_length && !file_ends
+            {
+                continue;
+            }
+            if jobs.len() >= workers
+            {
+                if let Some(job) = jobs.join_next().await
+                {
+                    hashes.push(job.map_err(std::io::Error::other)?);
+                }
+            }
+
+            let data = std::mem::replace(&mut piece, Vec::with_capacity(piece_length));
+            let index = hashes.len() + jobs.len();
+            let source = (file_index, file.length, last_file);
+            jobs.spawn_blocking(move || (index, hash_piece(data, piece_length, layout, source)));
+        }
+    }
+
+    if !piece.is_empty()
+    {
+        let source = (sources.len() - 1, 0, true);
+        let hash = hash_piece(piece, piece_length, layout, source);
+        hashes.push((hashes.len() + jobs.len(), hash));
+    }
+    while let Some(job) = jobs.join_next().await
+    {
+        hashes.push(job.map_err(std::io::Error::other)?);
+    }
+    hashes.sort_by_key(|(index, _)| *index);
+    Ok(hashes.into_iter().map(|(_, hash)| hash).collect())
+}
+
+/// Hashes one piece of `source`: the index of the file the piece ends in,
+/// the file's length and whether it is the last one. In hybrids the v1 hash
+/// covers the padding that follows every file but the last.
+fn hash_piece(
+    mut data: Vec<u8>,
+    piece_length: usize,
+    layout: TorrentLayout,
+    source: (usize, u64, bool),
+) -> PieceHash
+{
+    let (file_index, file_length, last_file) = source;
+    let root = match layout
+    {
+        TorrentLayout::V1 => None,
+        _ if file_length <= piece_length as u64 => Some(file_root(&data)),
+        _ => Some(piece_root(&data, piece_length)),
+    };
+
+    let sha1 = (layout != TorrentLayout::V2).then(|| {
+        if layout == TorrentLayout::Hybrid && !last_file
+        {
+            data.resize(piece_length, 0);
+        }
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        hasher.finalize().into()
+    });
+
+    PieceHash { file_index, sha1, root }
+}
+
+/// The info dict of `info` as the parser reads it back. v2-only torrents
+/// leave out the v1 fields synthesized from their file tree.
+fn encode_info(info: &TorrentInfo) -> HashMap<Vec<u8>, Value>
+{
+    let mut dict = HashMap::new();
+    dict.insert(b"name".to_vec(), Value::Bytes(info.name().as_bytes().to_vec()));
+    dict.insert(b"piece length".to_vec(), Value::Int(*info.piece_length()));
+
+    if info.has_v1()
+    {
+        dict.insert(b"pieces".to_vec(), Value::Bytes(info.pieces().clone()));
+
+        if info.files().is_empty()
+        {
+            dict.insert(b"length".to_vec(), Value::Int(*info.length()));
+        }
+        else
+        {
+            let files = info.files().iter().map(encode_file).collect();
+            dict.insert(b"files".to_vec(), Value::List(files));
+        }
+    }
+    if info.has_v2()
+    {
+        dict.insert(b"meta version".to_vec(), Value::Int(*info.meta_version()));
+        dict.insert(b"file tree".to_vec(), encode_file_tree(info.file_tree()));
+    }
+    if *info.private()
+    {
+        dict.insert(b"private".to_vec(), Value::Int(1));
+    }
+    dict
+}
+
+fn encode_file(file: &FileInfo) -> Value
+{
+    let path = file.path().iter().map(|part| Value::Bytes(part.as_bytes().to_vec())).collect();
+    let mut dict = HashMap::new();
+    dict.insert(b"length".to_vec(), Value::Int(*file.length()));
+    dict.insert(b"path".to_vec(), Value::List(path));
+
+    if *file.padding()
+    {
+        dict.insert(b"attr".to_vec(), Value::Bytes(b"p".to_vec()));
+    }
+    Value::Dict(dict)
+}
+
+/// Nests the flattened file tree back into one dict per directory.
+fn encode_file_tree(file_tree: &[FileInfo]) -> Value
+{
+    let mut tree = HashMap::new();
+
+    for file in file_tree
+    {
+        let mut node = &mut tree;
+
+        for part in file.path()
+        {
+            let child = node
+                .entry(part.as_bytes().to_vec())
+                .or_insert_with(|| Value::Dict(HashMap::new()));
+            let Value::Dict(child) = child else { unreachable!("file tree nodes are dicts") };
+            node = child;
+        }
+
+        let mut leaf = HashMap::new();
+        leaf.insert(b"length".to_vec(), Value::Int(*file.length()));
+
+        if let Some(pieces_root) = file.pieces_root()
+        {
+            leaf.insert(b"pieces root".to_vec(), Value::Bytes(pieces_root.to_vec()));
+        }
+        node.insert(Vec::new(), Value::Dict(leaf));
+    }
+    Value::Dict(tree)
+}
+
+/// Bencodes the torrent for a .torrent file. Only the fields the parser
+/// knows are written; the announce list is left out when it holds nothing
+/// beyond `announce`.
+pub fn encode_torrent(torrent: &Torrent) -> Result<Vec<u8>, MetadataError>
+{
+    let text = |text: &str| Value::Bytes(text.as_bytes().to_vec());
+    let mut dict = HashMap::new();
+    dict.insert(b"announce".to_vec(), text(torrent.announce().as_str()));
+
+    if torrent.announce_list().trackers().count() > 1
+    {
+        let tiers = torrent
+            .announce_list()
+            .tiers()
+            .iter()
+            .map(|tier| Value::List(tier.iter().map(|url| text(url.as_str())).collect()))
+            .collect();
+        dict.insert(b"announce-list".to_vec(), Value::List(tiers));
+    }
+    if let Some(comment) = torrent.comment()
+    {
+        dict.insert(b"comment".to_vec(), text(comment));
+    }
+    if let Some(created_by) = torrent.created_by()
+    {
+        dict.insert(b"created by".to_vec(), text(created_by));
+    }
+    if let Some(creation_date) = torrent.creation_date()
+    {
+        dict.insert(b"creation date".to_vec(), Value::Int(*creation_date));
+    }
+    if !torrent.web_seeds().is_empty()
+    {
+        let web_seeds = torrent.web_seeds().iter().map(|url| text(url.as_str())).collect();
+        dict.insert(b"url-list".to_vec(), Value::List(web_seeds));
+    }
+    dict.insert(b"info".to_vec(), Value::Dict(encode_info(torrent.info())));
+
+    if torrent.info().has_v2()
+    {
+        let layers = torrent
+            .piece_layers()
+            .iter()
+            .map(|(root, layer)| (root.to_vec(), Value::Bytes(layer.concat())))
+            .collect();
+        dict.insert(b"piece layers".to_vec(), Value::Dict(layers));
+    }
+    Ok(serde_bencode::to_bytes(&Value::Dict(dict))?)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::usecases::parse_torrent_file::parse_torrent_file;
+    use reqwest::Url;
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+
+    /// Creates a torrent with `layout` over a file spanning two pieces and
+    /// one ending mid-piece, writes it out and parses it back.
+    async fn create_and_parse(layout: TorrentLayout) -> (Torrent, Torrent, Vec<Vec<u8>>)
+    {
+        let dir = std::env::temp_dir()
+            .join(format!("bitcrab-create-{:?}-{}", layout, std::process::id()));
+        let content = dir.join("content");
+        std::fs::create_dir_all(content.join("sub")).unwrap();
+
+        let a: Vec<u8> = (0..PIECE_LENGTH + 5000).map(|i| (i % 251) as u8).collect();
+        let b: Vec<u8> = (0..3000).map(|i| (i % 13) as u8).collect();
+        std::fs::write(content.join("a.bin"), &a).unwrap();
+        std::fs::write(content.join("sub").join("b.bin"), &b).unwrap();
+
+        let config = CreateConfig::default()
+            .with_tracker_tier(vec![Url::parse("http://127.0.0.1/announce").unwrap()])
+            .with_piece_length(PIECE_LENGTH as i64)
+            .with_layout(layout);
+        let created = create_torrent(&content, &config).await.unwrap();
+        let torrent_path = dir.join("content.torrent");
+        std::fs::write(&torrent_path, encode_torrent(&created).unwrap()).unwrap();
+        let parsed = parse_torrent_file(&torrent_path).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        (created, parsed, vec![a, b])
+    }
+
+    /// Every piece of the torrent, with zeros for the padding files.
+    fn pieces(torrent: &Torrent, contents: &[Vec<u8>]) -> Vec<Vec<u8>>
+    {
+        let mut contents = contents.iter();
+        let stream: Vec<u8> = torrent
+            .info()
+            .files()
+            .iter()
+            .flat_map(|file| {
+                if *file.padding()
+                {
+                    vec![0; *file.length() as usize]
+                }
+                else { contents.next().unwrap().clone() }
+            })
+            .collect();
+
+        (0..torrent.info().num_pieces())
+            .map(|index| {
+                let begin = index * PIECE_LENGTH;
+                stream[begin..begin + torrent.info().piece_size(index)].to_vec()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn created_torrents_parse_back_and_verify()
+    {
+        let cases = [
+            // v1 packs `b` right after `a`, the others start it on a new piece.
+            (TorrentLayout::V1, true, false, 2),
+            (TorrentLayout::V2, false, true, 3),
+            (TorrentLayout::Hybrid, true, true, 3),
+        ];
+
+        for (layout, v1, v2, num_pieces) in cases
+        {
+            let (created, parsed, contents) = create_and_parse(layout).await;
+
+            assert_eq!(parsed.info_hash(), created.info_hash(), "{:?}", layout);
+            assert_eq!(parsed.info_hash_v2(), created.info_hash_v2(), "{:?}", layout);
+            assert_eq!(parsed.info().has_v1(), v1, "{:?}", layout);
+            assert_eq!(parsed.info().has_v2(), v2, "{:?}", layout);
+            assert_eq!(parsed.info().num_pieces(), num_pieces, "{:?}", layout);
+
+            for (index, piece) in pieces(&parsed, &contents).iter().enumerate()
+            {
+                assert!(parsed.verify_piece(index, piece), "{:?} piece {}", layout, index);
+
+                let mut corrupt = piece.clone();
+                corrupt[0] ^= 1;
+                assert!(!parsed.verify_piece(index, &corrupt), "{:?} piece {}", layout, index);
+            }
+        }
+    }
+}
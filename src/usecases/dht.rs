@@ -0,0 +1,567 @@
+use crate::entities::krpc::{KrpcBody, KrpcMessage, KrpcQuery, KrpcResponse, ERROR_PROTOCOL};
+use crate::entities::peer::Peer;
+use crate::entities::routing_table::{NodeId, NodeInfo, RoutingTable, BUCKET_SIZE};
+use crate::utils::errors::{DhtError, TorrentError};
+
+use anyhow::Result;
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, timeout, Duration, Instant};
+
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries a lookup keeps in flight, the `alpha` of Kademlia.
+const LOOKUP_PARALLELISM: usize = 3;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Tokens stay valid for one rotation after they are handed out, i.e. five
+/// to ten minutes.
+const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const TOKEN_LEN: usize = 8;
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+const QUESTIONABLE_AGE: Duration = Duration::from_secs(15 * 60);
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Keeps `get_peers` responses inside a single unfragmented datagram.
+const MAX_VALUES: usize = 50;
+const MAX_DATAGRAM_LEN: usize = 2048;
+
+type PendingQueries =
+    HashMap<Vec<u8>, (SocketAddrV4, oneshot::Sender<Result<KrpcResponse, DhtError>>)>;
+
+struct TokenSecrets
+{
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+struct DhtState
+{
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<PendingQueries>,
+    next_transaction: AtomicU16,
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>>,
+    secrets: Mutex<TokenSecrets>,
+}
+
+/// What an iterative lookup found: the closest nodes that answered, with
+/// the tokens they gave, and the peers returned by `get_peers`.
+struct Lookup
+{
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: HashSet<SocketAddrV4>,
+}
+
+/// A Mainline DHT node (BEP 5) over IPv4. Incoming queries are answered by a
+/// background task for as long as the node lives, so peers can find the
+/// torrents announced through it.
+pub struct Dht
+{
+    state: Arc<DhtState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Dht
+{
+    /// Starts a node with the id and nodes saved at `path`, or a fresh one
+    /// when the file is missing or unreadable.
+    pub async fn load(addr: SocketAddrV4, path: &Path) -> Result<Self, TorrentError>
+    {
+        let table = tokio::fs::read(path)
+            .await
+            .ok()
+            .and_then(|bytes| RoutingTable::from_bytes(&bytes).ok())
+            .unwrap_or_else(|| RoutingTable::new(NodeId::random()));
+
+        Self::with_table(addr, table).await
+    }
+
+    async fn with_table(addr: SocketAddrV4, table: RoutingTable) -> Result<Self, TorrentError>
+    {
+        let secrets = TokenSecrets {
+            current: rand::random(),
+            previous: rand::random(),
+            rotated: Instant::now(),
+        };
+        let state = Arc::new(DhtState {
+            socket: UdpSocket::bind(addr).await?,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(secrets),
+        });
+
+        let tasks = vec![
+            tokio::spawn(receive_loop(Arc::clone(&state))),
+            tokio::spawn(maintenance_loop(Arc::clone(&state))),
+        ];
+        Ok(Self { state, tasks })
+    }
+
+    /// Saves the node id and routing table for `load`.
+    pub async fn save(&self, path: &Path) -> Result<(), TorrentError>
+    {
+        let bytes = self.state.table.lock().unwrap().as_bytes()?;
+        tokio::fs::write(path, bytes).await?;
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, TorrentError>
+    {
+        Ok(self.state.socket.local_addr()?)
+    }
+
+    pub fn id(&self) -> NodeId
+    {
+        self.state.own_id()
+    }
+
+    pub fn node_count(&self) -> usize
+    {
+        self.state.table.lock().unwrap().len()
+    }
+
+    /// Pings `nodes` and the nodes already in the table, then looks up our
+    /// own id to fill the buckets close to us. Fails when nobody answered.
+    pub async fn bootstrap(&self, nodes: &[SocketAddrV4]) -> Result<usize, TorrentError>
+    {
+        let own_id = self.id();
+        let known = self.state.table.lock().unwrap().closest(&own_id, usize::MAX);
+        let addrs = nodes.iter().copied().chain(known.iter().map(|node| *node.addr()));
+
+        join_all(addrs.map(|addr| self.state.query(addr, KrpcQuery::Ping))).await;
+        self.state.lookup(own_id, None).await;
+
+        let table = self.state.table.lock().unwrap();
+
+        if table.is_empty()
+        {
+            return Err(DhtError::NoNodes.into());
+        }
+        Ok(table.len())
+    }
+
+    /// Pings `addr` in the background; if it answers, it joins the routing
+    /// table. Peers tell us their DHT port this way. The node only speaks
+    /// IPv4, so other addresses are ignored.
+    pub fn add_node(&self, addr: SocketAddr)
+    {
+        let SocketAddr::V4(addr) = addr else { return };
+        let state = Arc::clone(&self.state);
+
+        tokio::spawn(async move {
+            let _ = state.query(addr, KrpcQuery::Ping).await;
+        });
+    }
+
+    /// Looks up `info_hash` and tells the closest nodes that gave us a token
+    /// that we accept its peers on `port`. Returns the peers found on the way.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<Peer>
+    {
+        let lookup = self.state.lookup(NodeId::new(info_hash), Some(info_hash)).await;
+
+        let announces = lookup.closest.iter().filter_map(|(node, token)| {
+            let query = KrpcQuery::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port: false,
+                token: token.clone()?,
+            };
+            Some(self.state.query(*node.addr(), query))
+        });
+        join_all(announces).await;
+
+        to_peers(lookup.peers)
+    }
+}
+
+impl Drop for Dht
+{
+    fn drop(&mut self)
+    {
+        for task in &self.tasks
+        {
+            task.abort();
+        }
+    }
+}
+
+impl DhtState
+{
+    fn own_id(&self) -> NodeId
+    {
+        *self.table.lock().unwrap().own_id()
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        query: KrpcQuery,
+    ) -> Result<KrpcResponse, TorrentError>
+    {
+        let transaction_id = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        let message = KrpcMessage::query(transaction_id.to_vec(), self.own_id(), query);
+        let bytes = message.as_bytes()?;
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction_id.to_vec(), (addr, tx));
+
+        let result = match self.socket.send_to(&bytes, addr).await
+        {
+            Ok(_) => match timeout(QUERY_TIMEOUT, rx).await
+            {
+                Ok(Ok(response)) => response.map_err(TorrentError::from),
+                _ => Err(DhtError::Timeout(addr.into()).into()),
+            },
+            Err(e) => Err(e.into()),
+        };
+        self.pending.lock().unwrap().remove(transaction_id.as_slice());
+        result
+    }
+
+    /// Iterative Kademlia lookup: keeps querying the closest nodes it knows
+    /// of until the closest `BUCKET_SIZE` that answered have all been asked.
+    /// With an `info_hash` the nodes are asked for peers as well.
+    async fn lookup(self: &Arc<Self>, target: NodeId, info_hash: Option<[u8; 20]>) -> Lookup
+    {
+        let own_id = self.own_id();
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, BUCKET_SIZE)
+            .into_iter()
+            .map(|node| (node.id().distance(&target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded: BTreeMap<NodeId, (NodeInfo, Option<Vec<u8>>)> = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut in_flight = FuturesUnordered::new();
+
+        loop
+        {
+            while in_flight.len() < LOOKUP_PARALLELISM
+            {
+                let farthest_kept = responded.keys().nth(BUCKET_SIZE - 1).copied();
+                let next = candidates
+                    .iter()
+                    .find(|(_, node)| !queried.contains(node.id()))
+                    .filter(|(distance, _)| farthest_kept.is_none_or(|kept| **distance < kept))
+                    .map(|(_, node)| *node);
+                let Some(node) = next else { break };
+
+                queried.insert(*node.id());
+                let query = match info_hash
+                {
+                    Some(info_hash) => KrpcQuery::GetPeers { info_hash },
+                    None => KrpcQuery::FindNode { target },
+                };
+                let state = Arc::clone(self);
+                in_flight.push(async move { (node, state.query(*node.addr(), query).await) });
+            }
+
+            let Some((node, result)) = in_flight.next().await else { break };
+
+            match result
+            {
+                Ok(response) => {
+                    peers.extend(response.values());
+
+                    for found in response.nodes().iter().filter(|found| *found.id() != own_id)
+                    {
+                        candidates.entry(found.id().distance(&target)).or_insert(*found);
+                    }
+                    let answered = NodeInfo::new(*response.id(), *node.addr());
+                    responded.insert(
+                        response.id().distance(&target),
+                        (answered, response.token().clone()),
+                    );
+                }
+                Err(_) => self.table.lock().unwrap().mark_failed(node.id()),
+            }
+        }
+
+        Lookup
+        {
+            closest: responded.into_values().take(BUCKET_SIZE).collect(),
+            peers,
+        }
+    }
+
+    async fn handle(&self, message: KrpcMessage, from: SocketAddrV4)
+    {
+        let transaction_id = message.transaction_id();
+
+        let (responder, result) = match message.body()
+        {
+            KrpcBody::Query { id, query } => {
+                self.table.lock().unwrap().insert(NodeInfo::new(*id, from));
+                let reply = self.answer(transaction_id.clone(), query, from);
+
+                if let Ok(bytes) = reply.as_bytes()
+                {
+                    let _ = self.socket.send_to(&bytes, from).await;
+                }
+                return;
+            }
+            KrpcBody::Response(response) => (Some(*response.id()), Ok(response.clone())),
+            KrpcBody::Error { code, message } => {
+                (None, Err(DhtError::RemoteError(from.into(), *code, message.clone())))
+            }
+        };
+
+        // Only the node a query went to may answer it.
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(transaction_id)
+            {
+                Some((addr, _)) if *addr == from => pending.remove(transaction_id),
+                _ => None,
+            }
+        };
+        let Some((_, tx)) = pending else { return };
+
+        if let Some(id) = responder
+        {
+            self.table.lock().unwrap().insert(NodeInfo::new(id, from));
+        }
+        let _ = tx.send(result);
+    }
+
+    fn answer(&self, transaction_id: Vec<u8>, query: &KrpcQuery, from: SocketAddrV4) -> KrpcMessage
+    {
+        let table = self.table.lock().unwrap();
+        let response = KrpcResponse::new(*table.own_id());
+
+        let response = match query
+        {
+            KrpcQuery::Ping => response,
+            KrpcQuery::FindNode { target } => {
+                response.with_nodes(table.closest(target, BUCKET_SIZE))
+            }
+            KrpcQuery::GetPeers { info_hash } => {
+                let values = self
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .get(info_hash)
+                    .map(|peers| peers.keys().take(MAX_VALUES).copied().collect())
+                    .unwrap_or_default();
+
+                response
+                    .with_nodes(table.closest(&NodeId::new(*info_hash), BUCKET_SIZE))
+                    .with_values(values)
+                    .with_token(self.token(from.ip(), false))
+            }
+            KrpcQuery::AnnouncePeer { info_hash, port, implied_port, token } => {
+                if *token != self.token(from.ip(), false) && *token != self.token(from.ip(), true)
+                {
+                    return KrpcMessage::error(transaction_id, ERROR_PROTOCOL, "Bad token");
+                }
+                let port = if *implied_port { from.port() } else { *port };
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .entry(*info_hash)
+                    .or_default()
+                    .insert(SocketAddrV4::new(*from.ip(), port), Instant::now());
+                response
+            }
+        };
+        KrpcMessage::response(transaction_id, response)
+    }
+
+    /// The token a node at `ip` must present to announce to us, derived
+    /// from the current or the previous secret.
+    fn token(&self, ip: &Ipv4Addr, previous: bool) -> Vec<u8>
+    {
+        let secrets = self.secrets.lock().unwrap();
+        let secret = if previous { secrets.previous } else { secrets.current };
+
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(ip.octets());
+        hasher.finalize()[..TOKEN_LEN].to_vec()
+    }
+
+    fn rotate_secrets(&self)
+    {
+        let mut secrets = self.secrets.lock().unwrap();
+
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION_INTERVAL
+        {
+            secrets.previous = secrets.current;
+            secrets.current = rand::random();
+            secrets.rotated = Instant::now();
+        }
+    }
+
+    fn expire_peers(&self)
+    {
+        let mut peers = self.peers.lock().unwrap();
+
+        for announced in peers.values_mut()
+        {
+            announced.retain(|_, seen| seen.elapsed() < PEER_LIFETIME);
+        }
+        peers.retain(|_, announced| !announced.is_empty());
+    }
+}
+
+async fn receive_loop(state: Arc<DhtState>)
+{
+    let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+
+    loop
+    {
+        let Ok((length, from)) = state.socket.recv_from(&mut buffer).await else { continue };
+        let SocketAddr::V4(from) = from else { continue };
+
+        if let Ok(message) = KrpcMessage::from_bytes(&buffer[..length])
+        {
+            state.handle(message, from).await;
+        }
+    }
+}
+
+/// Rotates the token secrets, forgets stale announces and pings the nodes
+/// we have not heard from in a while.
+async fn maintenance_loop(state: Arc<DhtState>)
+{
+    let mut ticker = interval(MAINTENANCE_INTERVAL);
+
+    loop
+    {
+        ticker.tick().await;
+        state.rotate_secrets();
+        state.expire_peers();
+
+        let questionable = state.table.lock().unwrap().questionable(QUESTIONABLE_AGE);
+        let pings = questionable.iter().map(|node| {
+            let state = &state;
+            async move {
+                if state.query(*node.addr(), KrpcQuery::Ping).await.is_err()
+                {
+                    state.table.lock().unwrap().mark_failed(node.id());
+                }
+            }
+        });
+        join_all(pings).await;
+    }
+}
+
+/// Resolves `host:port` bootstrap nodes, keeping their IPv4 addresses.
+pub async fn resolve_nodes(hosts: &[String]) -> Vec<SocketAddrV4>
+{
+    let mut nodes = Vec::new();
+
+    for host in hosts
+    {
+        match lookup_host(host.as_str()).await
+        {
+            Ok(addrs) => nodes.extend(addrs.filter_map(|addr| match addr
+            {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })),
+            Err(e) => eprintln!("Could not resolve DHT node {} - Error: {}", host, e),
+        }
+    }
+    nodes
+}
+
+/// Bootstraps `dht` and announces `info_hash` on `port` right away and every
+/// 15 minutes after, sending the peers found to `peer_tx`. An empty routing
+/// table is bootstrapped again before each announce. Runs until aborted.
+pub async fn announce_torrent(
+    dht: Arc<Dht>,
+    bootstrap: Vec<SocketAddrV4>,
+    info_hash: [u8; 20],
+    port: u16,
+    peer_tx: mpsc::Sender<Vec<Peer>>,
+)
+{
+    loop
+    {
+        if dht.node_count() == 0
+        {
+            match dht.bootstrap(&bootstrap).await
+            {
+                Ok(count) => println!("DHT bootstrapped with {} nodes", count),
+                Err(e) => eprintln!("DHT bootstrap failed - Error: {}", e),
+            }
+        }
+
+        let peers = dht.announce(info_hash, port).await;
+
+        if !peers.is_empty()
+        {
+            println!("DHT returned {} peers", peers.len());
+            let _ = peer_tx.try_send(peers);
+        }
+        sleep(ANNOUNCE_INTERVAL).await;
+    }
+}
+
+fn to_peers(peers: HashSet<SocketAddrV4>) -> Vec<Peer>
+{
+    peers
+        .into_iter()
+        .map(|peer| Peer::new(IpAddr::V4(*peer.ip()), peer.port()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    async fn local_node() -> (Dht, SocketAddrV4)
+    {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+        let dht = Dht::with_table(addr, RoutingTable::new(NodeId::random())).await.unwrap();
+        let SocketAddr::V4(addr) = dht.local_addr().unwrap()
+        else { unreachable!("the node binds an IPv4 address") };
+        (dht, addr)
+    }
+
+    #[tokio::test]
+    async fn announced_peer_is_found_from_another_node()
+    {
+        let mut nodes = Vec::new();
+        for _ in 0..4
+        {
+            nodes.push(local_node().await);
+        }
+        let addrs: Vec<SocketAddrV4> = nodes.iter().map(|(_, addr)| *addr).collect();
+
+        for (dht, own) in &nodes
+        {
+            let others: Vec<SocketAddrV4> =
+                addrs.iter().copied().filter(|addr| addr != own).collect();
+            assert_eq!(dht.bootstrap(&others).await.unwrap(), addrs.len() - 1);
+        }
+
+        let info_hash = [7; 20];
+        nodes[0].0.announce(info_hash, 6881).await;
+        // The lookup of an announce returns the peers it came across.
+        let peers = nodes[3].0.announce(info_hash, 6882).await;
+
+        assert!(peers.iter().any(|peer| peer.addr() == SocketAddr::from(([127, 0, 0, 1], 6881))));
+    }
+}
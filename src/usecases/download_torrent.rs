@@ -1,247 +1,735 @@
+use crate::entities::choker::Choker;
+use crate::entities::download_config::DownloadConfig;
+use crate::entities::extension::ExtensionRegistry;
 use crate::entities::message::Message;
 use crate::entities::peer::Peer;
+use crate::entities::pex::{PEX_CONNECTABLE, PEX_SEED, UT_PEX};
+use crate::entities::piece_picker::PiecePicker;
+use crate::entities::storage::Storage;
 use crate::entities::torrent::Torrent;
-use crate::utils::errors::{HandshakeError, TorrentError};
+use crate::entities::transfer_stats::TransferStats;
+use crate::usecases::choke_manager::{ChokeHandle, ChokeManager};
+use crate::usecases::dht::Dht;
+use crate::usecases::peer_exchange::{PeerExchange, PexHandle, PEX_INTERVAL};
+use crate::usecases::peer_session::PeerSession;
+use crate::usecases::seeder::{accept_handshake, serve_request};
+use crate::utils::errors::{HandshakeError, MessageError, TorrentError};
 
 use anyhow::Result;
-use sha1::{Digest, Sha1};
-use std::collections::VecDeque;
-use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
-use tokio::task;
+use bytes::Bytes;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{spawn_blocking, JoinSet};
+use tokio::time::{interval, sleep_until, Duration, Instant};
 
 const BLOCK_SIZE: usize = 16 * 1024;
-const NUM_WORKERS: usize = 4;
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+const ENDGAME_CHANNEL_SIZE: usize = 256;
+const PEX_CHANNEL_SIZE: usize = 16;
+/// Peers learned through peer exchange are dropped while this many
+/// candidates are already waiting for a connection slot.
+const MAX_CANDIDATES: usize = 500;
 
-pub async fn download_torrent(torrent: &Torrent, peers: &[Peer]) -> Result<(), TorrentError>
+/// Downloads the pieces missing from `have`, connecting to `peers` and to
+/// those arriving on `new_peers`, and taking in the peers that connect to
+/// `listener` while slots are free. Peers running a DHT node too are told
+/// the port of `dht` and have theirs added to it.
+#[allow(clippy::too_many_arguments)]
+pub async fn download_torrent(
+    torrent: &Torrent,
+    peers: &[Peer],
+    have: Vec<bool>,
+    storage: Arc<dyn Storage>,
+    stats: Arc<TransferStats>,
+    mut new_peers: mpsc::Receiver<Vec<Peer>>,
+    listener: TcpListener,
+    dht: Option<Arc<Dht>>,
+    config: DownloadConfig,
+) -> Result<(), TorrentError>
 {
-    let piece_length = *torrent.info().piece_length() as usize;
-    let num_pieces = torrent.info().piece_hashes().len();
-    let work_queue = Arc::new(Mutex::new(VecDeque::from(
-        (0..num_pieces).collect::<Vec<_>>(),
-    )));
-    let (tx, mut rx) = mpsc::channel(NUM_WORKERS);
-
-    for _ in 0..NUM_WORKERS
-    {
-        let work_queue = Arc::clone(&work_queue);
-        let torrent = torrent.clone();
-        let peers = peers.to_vec();
-        let tx = tx.clone();
-
-        task::spawn(async move {
-            while let Some(piece_index) = get_work(&work_queue).await
+    storage.truncate().await?;
+    let listen_port = listener.local_addr()?.port();
+
+    let mut picker = PiecePicker::new(&have);
+
+    for (piece_index, priority) in config.priorities()
+    {
+        picker.set_priority(*piece_index, *priority);
+    }
+    let (piece_tx, mut piece_rx) = mpsc::channel(*config.max_peers());
+    let (have_tx, have_rx) = watch::channel(have.clone());
+    let (pex_tx, mut pex_rx) = mpsc::channel(PEX_CHANNEL_SIZE);
+    let context = SessionContext {
+        torrent: Arc::new(torrent.clone()),
+        picker: Arc::new(Mutex::new(picker)),
+        piece_tx,
+        endgame_tx: broadcast::channel(ENDGAME_CHANNEL_SIZE).0,
+        have_rx,
+        storage: Arc::clone(&storage),
+        stats: Arc::clone(&stats),
+        choke: ChokeManager::new(),
+        pex: config.pex().then(|| PeerExchange::new(pex_tx)),
+        dht,
+        listen_port,
+        queue_len: *config.request_queue_len(),
+    };
+    let choker = Choker::new(*config.upload_slots(), *config.optimistic_slots());
+    let rechoker = tokio::spawn({
+        let choke = context.choke.clone();
+        let stats = Arc::clone(&stats);
+        async move { choke.run(choker, stats).await }
+    });
+
+    let mut candidates: VecDeque<Peer> = peers.iter().cloned().collect();
+    let mut connected: HashSet<SocketAddr> = HashSet::new();
+    let mut tried: HashSet<SocketAddr> = HashSet::new();
+    let mut sessions = JoinSet::new();
+    let mut peers_open = true;
+    let mut have = have;
+    let mut remaining = have.iter().filter(|have| !**have).count();
+    let mut save_resume = interval(RESUME_SAVE_INTERVAL);
+    let mut idle_since = None;
+
+    let result = loop
+    {
+        if remaining == 0
+        {
+            break Ok(());
+        }
+
+        while sessions.len() < *config.max_peers()
+        {
+            let Some(peer) = candidates.pop_front() else { break };
+
+            if connected.insert(peer.addr())
             {
-                if let Err(_) = download_and_verify_piece(&torrent, &peers, piece_index).await
+                tried.insert(peer.addr());
+                let context = context.clone();
+
+                sessions.spawn(async move {
+                    let result = run_session(context, &peer, None).await;
+                    (peer, result)
+                });
+            }
+        }
+
+        if sessions.is_empty() && candidates.is_empty() && !peers_open && piece_rx.is_empty()
+        {
+            break Err(HandshakeError::ConnectionError("no peers left".to_string()).into());
+        }
+
+        // Discovery keeps `new_peers` open, so without peers the download
+        // only ends once it has gone without them for too long.
+        if !sessions.is_empty() || !candidates.is_empty()
+        {
+            idle_since = None;
+        }
+        else if idle_since.is_none()
+        {
+            idle_since = Some(Instant::now());
+        }
+        let idle_deadline = idle_since.unwrap_or_else(Instant::now) + *config.idle_timeout();
+
+        tokio::select! {
+            Some((piece_index, piece)) = piece_rx.recv() => {
+                if have[piece_index]
                 {
-                    add_work(&work_queue, piece_index).await;
+                    continue;
                 }
-                else
+                if let Err(e) = storage.write_block(piece_index, 0, &piece).await
                 {
-                    tx.send(Some(piece_index)).await.unwrap();
+                    break Err(e);
                 }
+                stats.add_downloaded(piece.len() as i64);
+                have[piece_index] = true;
+                have_tx.send_modify(|shared| shared[piece_index] = true);
+                remaining -= 1;
+                println!("Piece {} downloaded, {} remaining", piece_index, remaining);
             }
-            tx.send(None).await.unwrap();
-        });
-    }
-
-    let mut pieces_downloaded = vec![false; num_pieces];
-    let final_file_name = format!("{}", torrent.info().name());
-    let mut file = File::create(&final_file_name)
-        .await
-        .expect("Failed to create file");
-    let mut completed_workers = 0;
+            _ = save_resume.tick() => {
+                if let Err(e) = storage.save_resume(torrent.info_hash(), &have).await
+                {
+                    eprintln!("Failed to save resume data - Error: {}", e);
+                }
+            }
+            Some(joined) = sessions.join_next(), if !sessions.is_empty() => {
+                if let Ok((peer, result)) = joined
+                {
+                    connected.remove(&peer.addr());
 
-    while completed_workers < NUM_WORKERS
-    {
-        if let Some(piece_index) = rx.recv().await
-        {
-            if let Some(piece_index) = piece_index
+                    if let Err(e) = result
+                    {
+                        eprintln!("Session with peer {} ended - Error: {}", peer, e);
+                    }
+                }
+            }
+            accepted = listener.accept() => match accepted
             {
-                pieces_downloaded[piece_index] = true;
-                for i in 0..num_pieces
-                {
-                    if !pieces_downloaded[i] { break; }
+                Ok((stream, addr)) => {
+                    // Past the peer limit the connection is simply dropped.
+                    let peer = Peer::new(addr.ip().to_canonical(), addr.port());
 
-                    let piece_file = format!("piece_{}.data", i);
+                    if sessions.len() < *config.max_peers() && connected.insert(peer.addr())
+                    {
+                        let context = context.clone();
 
-                    match File::open(&piece_file).await
+                        sessions.spawn(async move {
+                            let result = run_session(context, &peer, Some(stream)).await;
+                            (peer, result)
+                        });
+                    }
+                }
+                Err(e) => eprintln!("Failed to accept a peer - Error: {}", e),
+            },
+            _ = sleep_until(idle_deadline), if idle_since.is_some() => {
+                break Err(HandshakeError::IdleTimeout(config.idle_timeout().as_secs()).into());
+            }
+            found = new_peers.recv(), if peers_open => {
+                match found
+                {
+                    Some(found) => candidates.extend(found),
+                    None => peers_open = false,
+                }
+            }
+            Some(exchanged) = pex_rx.recv() => {
+                // Peers we connected to before are known to the trackers
+                // already, or failed us.
+                for peer in exchanged
+                {
+                    if candidates.len() >= MAX_CANDIDATES
                     {
-                        Ok(mut piece) => {
-                            let mut buffer = vec![0; piece_length];
-                            let bytes_read = piece
-                                .read(&mut buffer)
-                                .await
-                                .expect("Failed to read piece file");
-                            file.seek(SeekFrom::End(0))
-                                .await
-                                .expect("Failed to seek file");
-                            file.write_all(&buffer[..bytes_read])
-                                .await
-                                .expect("Failed to write piece to file");
-                            tokio::fs::remove_file(piece_file)
-                                .await
-                                .expect("Failed to delete piece file");
-                        }
-                        Err(e) => { eprintln!("Failed to open piece file: {:?}", e); }
+                        break;
+                    }
+                    if !tried.contains(&peer.addr())
+                        && !candidates.iter().any(|candidate| candidate.addr() == peer.addr())
+                    {
+                        candidates.push_back(peer);
                     }
                 }
             }
-            else { completed_workers += 1; }
+        }
+    };
+    sessions.abort_all();
+    rechoker.abort();
+    storage.save_resume(torrent.info_hash(), &have).await?;
+    result
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BlockState
+{
+    Missing,
+    Requested,
+    Received,
+}
+
+/// A piece whose blocks are being requested from one peer.
+struct PieceInProgress
+{
+    index: usize,
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+}
+
+impl PieceInProgress
+{
+    fn new(index: usize, size: usize) -> Self
+    {
+        Self
+        {
+            index,
+            data: vec![0; size],
+            blocks: vec![BlockState::Missing; size.div_ceil(BLOCK_SIZE)],
         }
     }
-    println!("Arquivo {} está pronto", final_file_name);
-    Ok(())
+
+    fn block_range(&self, block: usize) -> Range<usize>
+    {
+        let begin = block * BLOCK_SIZE;
+        begin..self.data.len().min(begin + BLOCK_SIZE)
+    }
+
+    fn next_request(&mut self) -> Option<Message>
+    {
+        let block = self.blocks.iter().position(|state| *state == BlockState::Missing)?;
+        let range = self.block_range(block);
+        self.blocks[block] = BlockState::Requested;
+
+        Some(Message::Request {
+            index: self.index as u32,
+            begin: range.start as u32,
+            length: range.len() as u32,
+        })
+    }
+
+    /// Stores a block and returns its previous state, or `None` if it does
+    /// not line up with a block of this piece.
+    fn store(&mut self, begin: usize, data: &[u8]) -> Option<BlockState>
+    {
+        let block = begin / BLOCK_SIZE;
+
+        if !begin.is_multiple_of(BLOCK_SIZE) || self.blocks.get(block).is_none()
+        {
+            return None;
+        }
+        let range = self.block_range(block);
+
+        if range.len() != data.len()
+        {
+            return None;
+        }
+        let previous = self.blocks[block];
+
+        if previous != BlockState::Received
+        {
+            self.data[range].copy_from_slice(data);
+            self.blocks[block] = BlockState::Received;
+        }
+        Some(previous)
+    }
+
+    fn is_complete(&self) -> bool
+    {
+        self.blocks.iter().all(|state| *state == BlockState::Received)
+    }
 }
 
-async fn get_work(work_queue: &Arc<Mutex<VecDeque<usize>>>) -> Option<usize>
+/// A block one session received during endgame, shared with the sessions
+/// that requested the same block from other peers.
+struct EndgameBlock
 {
-    let mut queue = work_queue.lock().await;
-    queue.pop_front()
+    index: usize,
+    begin: usize,
+    data: Bytes,
 }
 
-async fn add_work(work_queue: &Arc<Mutex<VecDeque<usize>>>, piece_index: usize)
+/// Handles every session of a download shares.
+#[derive(Clone)]
+struct SessionContext
 {
-    let mut queue = work_queue.lock().await;
-    queue.push_back(piece_index);
+    torrent: Arc<Torrent>,
+    picker: Arc<Mutex<PiecePicker>>,
+    piece_tx: mpsc::Sender<(usize, Vec<u8>)>,
+    endgame_tx: broadcast::Sender<Arc<EndgameBlock>>,
+    have_rx: watch::Receiver<Vec<bool>>,
+    storage: Arc<dyn Storage>,
+    stats: Arc<TransferStats>,
+    choke: ChokeManager,
+    pex: Option<PeerExchange>,
+    dht: Option<Arc<Dht>>,
+    listen_port: u16,
+    queue_len: usize,
 }
 
-async fn download_and_verify_piece(
-    torrent: &Torrent,
-    peers: &[Peer],
-    piece_index: usize,
+/// What one session is downloading from its peer.
+struct SessionState
+{
+    active: Vec<PieceInProgress>,
+    counted: Vec<bool>,
+    outstanding: usize,
+    announced: Vec<bool>,
+}
+
+/// Downloads pieces from one peer over a single connection, serving it the
+/// pieces we already have while the choker lets it. `stream` is a connection
+/// the peer opened to us; without one we connect to the peer. When the
+/// session ends its unfinished pieces go back to the picker and the peer's
+/// pieces stop counting towards availability.
+async fn run_session(
+    context: SessionContext,
+    peer: &Peer,
+    stream: Option<TcpStream>,
 ) -> Result<(), TorrentError>
 {
-    for peer in peers
+    let mut have_rx = context.have_rx.clone();
+    let announced = have_rx.borrow_and_update().clone();
+    let mut registry = ExtensionRegistry::new().with_listen_port(context.listen_port);
+    // An inbound peer connects from a port nobody else can reach it on, so
+    // it stays out of the peer exchange.
+    let exchange = context.pex.as_ref().filter(|_| stream.is_none());
+    let dht_port = context.dht.as_ref().and_then(|dht| dht.local_addr().ok()).map(|a| a.port());
+
+    if let Some(pex) = exchange
+    {
+        registry.register(pex.handler(peer.addr()));
+    }
+    let mut session = match stream
     {
-        match download_piece(torrent, peer, piece_index as u32).await
+        Some(mut stream) => {
+            let torrent = &context.torrent;
+            let ((), extensions, dht_port) =
+                accept_handshake(&mut stream, peer, dht_port, |info_hash| {
+                    (info_hash == torrent.info_hash()).then_some(())
+                })
+                .await?;
+            let peer = peer.clone();
+            PeerSession::from_stream(stream, peer, &announced, extensions, dht_port, registry)
+                .await?
+        }
+        None => {
+            PeerSession::connect(&context.torrent, peer, &announced, dht_port, registry).await?
+        }
+    };
+    let mut choke = context.choke.register();
+    let mut pex = exchange.map(|pex| pex.join(peer.addr(), PEX_CONNECTABLE));
+    let mut state = SessionState {
+        active: Vec::new(),
+        counted: vec![false; context.torrent.info().num_pieces()],
+        outstanding: 0,
+        announced,
+    };
+
+    let result = download_from_session(
+        &context,
+        &mut session,
+        &mut state,
+        &mut choke,
+        pex.as_mut(),
+        &mut have_rx,
+    )
+    .await;
+
+    let mut picker = context.picker.lock().unwrap();
+    for piece in state.active
+    {
+        picker.release(piece.index);
+    }
+    picker.remove_peer(&state.counted);
+    result
+}
+
+async fn download_from_session(
+    context: &SessionContext,
+    session: &mut PeerSession,
+    state: &mut SessionState,
+    choke: &mut ChokeHandle,
+    mut pex: Option<&mut PexHandle>,
+    have_rx: &mut watch::Receiver<Vec<bool>>,
+) -> Result<(), TorrentError>
+{
+    let mut endgame_rx = context.endgame_tx.subscribe();
+    let mut pex_timer = interval(PEX_INTERVAL);
+
+    loop
+    {
+        cancel_completed(context, session, state).await?;
+
+        let wants_more = context.picker.lock().unwrap().wants_any(session.bitfield());
+        session.set_interested(wants_more || !state.active.is_empty()).await?;
+
+        if !session.is_choked()
         {
-            Ok(piece) => {
-                let piece_hash = torrent.info().piece_hashes()[piece_index];
-                let mut hasher = Sha1::new();
-                hasher.update(&piece);
-                let hash = hasher.finalize();
+            request_blocks(context, session, state).await?;
+        }
+
+        tokio::select! {
+            message = session.receive() => match message?
+            {
+                Message::Interested | Message::NotInterested => {
+                    choke.set_interested(session.is_peer_interested());
+                }
+                Message::Request { index, begin, length } => {
+                    let has_piece = state.announced.get(index as usize).copied().unwrap_or(false);
+                    let sent = serve_request(
+                        session,
+                        context.storage.as_ref(),
+                        &context.torrent,
+                        has_piece,
+                        index,
+                        begin,
+                        length,
+                    )
+                    .await?;
+                    context.stats.add_uploaded(sent as i64);
+                    choke.add_uploaded(sent);
+                }
+                Message::Choke => {
+                    // Outstanding requests are discarded by a choking peer.
+                    let mut picker = context.picker.lock().unwrap();
+                    for piece in state.active.drain(..)
+                    {
+                        picker.release(piece.index);
+                    }
+                    state.outstanding = 0;
+                }
+                message @ (Message::Bitfield { .. } | Message::Have { .. }) => {
+                    let pieces = match message
+                    {
+                        // A Have adds one piece to what the peer holds.
+                        Message::Have { piece_index } => {
+                            piece_index as usize..piece_index as usize + 1
+                        }
+                        _ => 0..state.counted.len(),
+                    };
+                    let mut picker = context.picker.lock().unwrap();
 
-                if hash.as_slice() == piece_hash
+                    for piece_index in pieces
+                    {
+                        if session.has_piece(piece_index) && !state.counted[piece_index]
+                        {
+                            state.counted[piece_index] = true;
+                            picker.add_availability(piece_index);
+                        }
+                    }
+
+                    if let Some(pex) = pex.as_mut()
+                    {
+                        let seed = session.bitfield().iter().all(|has| *has);
+                        let flags = if seed { PEX_SEED } else { 0 };
+                        pex.set_flags(PEX_CONNECTABLE | flags);
+                    }
+                }
+                Message::Piece { index: piece_index, begin: offset, block } => {
+                    choke.add_downloaded(block.len());
+                    let (index, begin) = (piece_index as usize, offset as usize);
+
+                    // Blocks of pieces we cancelled, were choked on or saw
+                    // another session finish can still arrive afterwards.
+                    let Some(position) = state.active.iter().position(|p| p.index == index)
+                    else {
+                        context.stats.add_wasted(block.len() as i64);
+                        continue;
+                    };
+
+                    // A block that fits none of ours would leave its request
+                    // outstanding for good, so the peer is dropped and its
+                    // pieces released.
+                    let Some(previous) = state.active[position].store(begin, &block)
+                    else {
+                        let error = MessageError::InvalidBlock(piece_index, offset, block.len());
+                        return Err(error.into());
+                    };
+
+                    match previous
+                    {
+                        BlockState::Requested => {
+                            state.outstanding = state.outstanding.saturating_sub(1);
+                        }
+                        BlockState::Received => context.stats.add_wasted(block.len() as i64),
+                        BlockState::Missing => {}
+                    }
+
+                    if context.picker.lock().unwrap().is_endgame()
+                    {
+                        let _ = context.endgame_tx.send(Arc::new(EndgameBlock {
+                            index,
+                            begin,
+                            data: block,
+                        }));
+                    }
+                    if !finish_piece(context, session.peer(), state, position).await?
+                    {
+                        return Ok(());
+                    }
+                }
+                Message::HashRequest(request) => {
+                    session.send(&context.torrent.answer_hash_request(&request)).await?;
+                }
+                Message::Port { listen_port } => {
+                    if let Some(dht) = &context.dht
+                    {
+                        dht.add_node(SocketAddr::new(*session.peer().ip(), listen_port));
+                    }
+                }
+                _ => {}
+            },
+            shared = endgame_rx.recv() => {
+                let shared = match shared
+                {
+                    Ok(shared) => shared,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                let Some(position) = state.active.iter().position(|p| p.index == shared.index)
+                else { continue };
+
+                if state.active[position].store(shared.begin, &shared.data)
+                    == Some(BlockState::Requested)
+                {
+                    state.outstanding = state.outstanding.saturating_sub(1);
+                    session
+                        .send(&Message::Cancel {
+                            index: shared.index as u32,
+                            begin: shared.begin as u32,
+                            length: shared.data.len() as u32,
+                        })
+                        .await?;
+                }
+                if !finish_piece(context, session.peer(), state, position).await?
                 {
-                    let piece_file = format!("piece_{}.data", piece_index);
-                    let mut file = File::create(&piece_file)
-                        .await
-                        .expect("Failed to create piece file");
-                    file.write_all(&piece)
-                        .await
-                        .expect("Failed to write piece to file");
                     return Ok(());
                 }
             }
-            Err(_) => { continue; }
+            choking = choke.changed() => session.set_choking(choking).await?,
+            _ = pex_timer.tick() => {
+                // Nothing counts as sent until the peer enabled ut_pex.
+                let Some(pex) = pex.as_mut().filter(|_| session.supports_extension(UT_PEX))
+                else { continue };
+
+                if let Some(message) = pex.next_message()
+                {
+                    session.send_extension(UT_PEX, message.as_bytes()?).await?;
+                }
+            }
+            Ok(()) = have_rx.changed() => {
+                let have = have_rx.borrow_and_update().clone();
+
+                for (piece_index, has) in have.iter().enumerate()
+                {
+                    if *has && !state.announced[piece_index]
+                    {
+                        session.send(&Message::Have { piece_index: piece_index as u32 }).await?;
+                    }
+                }
+                state.announced = have;
+            }
         }
     }
-    Err(TorrentError::IoError(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "Failed to download piece",
-    )))
 }
 
-pub async fn download_piece(
-    torrent: &Torrent,
-    peer: &Peer,
-    piece_index: u32,
-) -> Result<Vec<u8>, TorrentError>
+/// Drops pieces another session completed during endgame, cancelling the
+/// blocks still requested for them.
+async fn cancel_completed(
+    context: &SessionContext,
+    session: &mut PeerSession,
+    state: &mut SessionState,
+) -> Result<(), TorrentError>
 {
-    let addr = format!("{}:{}", peer.ip(), peer.port());
-    let mut stream = TcpStream::connect(&addr)
-        .await
-        .map_err(|_| HandshakeError::ConnectionError(addr.clone()))?;
-    let handshake = crate::entities::handshake::Handshake::new(*torrent.info_hash());
-    stream
-        .write_all(&handshake.as_bytes())
-        .await
-        .map_err(|_| HandshakeError::HandshakeSendError(addr.clone()))?;
-
-    let mut response = vec![0; 68];
-    stream
-        .read_exact(&mut response)
-        .await
-        .map_err(|_| HandshakeError::HandshakeReceiveError(addr.clone()))?;
-
-    if &response[1..20] != handshake.protocol_str().as_bytes()
-        || &response[28..48] != handshake.info_hash()
-    {
-        return Err(HandshakeError::InvalidHandshakeResponse(addr).into());
-    }
+    let completed: Vec<PieceInProgress> = {
+        let mut picker = context.picker.lock().unwrap();
+        let (completed, active) = state
+            .active
+            .drain(..)
+            .partition(|piece| picker.is_complete(piece.index));
+        state.active = active;
 
-    let bitfield_message = read_message(&mut stream).await?;
-    let _bitfield = if let Message::Bitfield { bitfield } = bitfield_message {
-        bitfield
-    }
-    else
-    {
-        return Err(TorrentError::IoError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Esperava mensagem bitfield",
-        )));
+        for piece in &completed
+        {
+            picker.release(piece.index);
+        }
+        completed
     };
 
-    let interested_message = Message::Interested;
-    stream.write_all(&interested_message.as_bytes()).await?;
-
-    let unchoke_message = read_message(&mut stream).await?;
-    if !matches!(unchoke_message, Message::Unchoke)
+    for piece in completed
     {
-        return Err(TorrentError::IoError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Esperava mensagem unchoke",
-        )));
+        let requested = piece
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| **state == BlockState::Requested);
+
+        for (block, _) in requested
+        {
+            let range = piece.block_range(block);
+            session
+                .send(&Message::Cancel {
+                    index: piece.index as u32,
+                    begin: range.start as u32,
+                    length: range.len() as u32,
+                })
+                .await?;
+            state.outstanding = state.outstanding.saturating_sub(1);
+        }
     }
+    Ok(())
+}
 
-    let piece_length = *torrent.info().piece_length() as usize;
-    let mut buffer = vec![];
-    let mut offset = 0;
+/// Keeps the request pipeline full, joining pieces other peers are already
+/// downloading once the picker is in endgame.
+async fn request_blocks(
+    context: &SessionContext,
+    session: &mut PeerSession,
+    state: &mut SessionState,
+) -> Result<(), TorrentError>
+{
+    let queue_len = session.request_queue_len(context.queue_len);
 
-    while offset < piece_length
+    while state.outstanding < queue_len
     {
-        let block_size = std::cmp::min(BLOCK_SIZE, piece_length - offset);
-        let request_message = Message::Request {
-            index: piece_index,
-            begin: offset as u32,
-            length: block_size as u32,
-        };
-        stream.write_all(&request_message.as_bytes()).await?;
-        let piece_message = read_message(&mut stream).await?;
-
-        if let Message::Piece { block, .. } = piece_message
+        if let Some(request) = state.active.iter_mut().find_map(|piece| piece.next_request())
         {
-            buffer.extend_from_slice(&block);
+            session.send(&request).await?;
+            state.outstanding += 1;
+            continue;
         }
-        else
+
+        let picked = {
+            let mut picker = context.picker.lock().unwrap();
+            let active: Vec<usize> = state.active.iter().map(|piece| piece.index).collect();
+
+            // Every block of our pieces is requested by now, which endgame
+            // waits for across all sessions.
+            for piece_index in &active
+            {
+                picker.set_fully_requested(*piece_index);
+            }
+
+            picker
+                .pick(session.bitfield())
+                .or_else(|| picker.pick_endgame(session.bitfield(), &active))
+        };
+
+        match picked
         {
-            return Err(TorrentError::IoError(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Esperava mensagem piece",
-            )));
+            Some(index) => {
+                let size = context.torrent.info().piece_size(index);
+                state.active.push(PieceInProgress::new(index, size));
+            }
+            None => break,
         }
-        offset += block_size;
     }
-    Ok(buffer)
+    Ok(())
 }
 
-async fn read_message(stream: &mut TcpStream) -> Result<Message, TorrentError>
+/// Hands the piece at `position` to the writer once all its blocks are in,
+/// hashing it off the async workers. Returns `false` when the download no
+/// longer needs this session.
+async fn finish_piece(
+    context: &SessionContext,
+    peer: &Peer,
+    state: &mut SessionState,
+    position: usize,
+) -> Result<bool, TorrentError>
 {
-    let mut length_prefix = [0; 4];
-    stream.read_exact(&mut length_prefix).await?;
-    let length_prefix = u32::from_be_bytes(length_prefix);
-
-    let mut buffer = vec![0; length_prefix as usize];
-    stream.read_exact(&mut buffer).await?;
-
-    Message::from_bytes(&buffer).ok_or_else(|| {
-        TorrentError::IoError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Failed to parse message",
-        ))
+    if !state.active[position].is_complete()
+    {
+        return Ok(true);
+    }
+
+    let piece = state.active.remove(position);
+
+    {
+        // Another session finished this piece first during endgame.
+        let mut picker = context.picker.lock().unwrap();
+
+        if picker.is_complete(piece.index)
+        {
+            picker.release(piece.index);
+            return Ok(true);
+        }
+    }
+
+    let torrent = Arc::clone(&context.torrent);
+    let (piece, valid) = spawn_blocking(move || {
+        let valid = torrent.verify_piece(piece.index, &piece.data);
+        (piece, valid)
     })
+    .await
+    .map_err(std::io::Error::other)?;
+
+    if valid
+    {
+        context.picker.lock().unwrap().complete(piece.index);
+        Ok(context.piece_tx.send((piece.index, piece.data)).await.is_ok())
+    }
+    else
+    {
+        eprintln!("Piece {} from peer {} failed the hash check", piece.index, peer);
+        context.picker.lock().unwrap().release(piece.index);
+        Ok(true)
+    }
 }
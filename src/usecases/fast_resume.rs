@@ -0,0 +1,29 @@
+use crate::entities::storage::Storage;
+use crate::entities::torrent::Torrent;
+use crate::usecases::verify_torrent::{print_verify_progress, verify_torrent};
+use crate::utils::errors::TorrentError;
+
+use anyhow::Result;
+
+/// Pieces already present in storage. Saved resume data is used when it is
+/// still valid; otherwise every piece is rechecked against its hash.
+pub async fn load_verified_pieces(
+    torrent: &Torrent,
+    storage: &dyn Storage,
+) -> Result<Vec<bool>, TorrentError>
+{
+    if let Some(pieces) = storage.load_resume(torrent.info_hash()).await?
+    {
+        println!("Resuming with {} verified pieces", pieces.iter().filter(|have| **have).count());
+        return Ok(pieces);
+    }
+
+    let pieces = verify_torrent(torrent, storage, print_verify_progress).await?;
+    let valid = pieces.iter().filter(|have| **have).count();
+
+    if valid > 0
+    {
+        println!("Recheck found {}/{} valid pieces", valid, pieces.len());
+    }
+    Ok(pieces)
+}
@@ -0,0 +1,237 @@
+use crate::entities::extension::{
+    ExtensionHandler, ExtensionHandshake, ExtensionRegistry, EXTENSION_HANDSHAKE_ID,
+    MAX_INCOMING_REQUESTS,
+};
+use crate::entities::handshake::Handshake;
+use crate::entities::magnet::Magnet;
+use crate::entities::message::Message;
+use crate::entities::message_codec::MessageCodec;
+use crate::entities::metadata_message::{MetadataMessage, METADATA_PIECE_SIZE};
+use crate::entities::peer::Peer;
+use crate::entities::torrent::Torrent;
+use crate::usecases::parse_torrent_file::torrent_from_info;
+use crate::usecases::perform_handshake::{connect_to_peer, exchange_handshake};
+use crate::utils::errors::{HandshakeError, MetadataError, TorrentError};
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+use tokio_util::codec::Framed;
+
+const UT_METADATA: &str = "ut_metadata";
+const MAX_METADATA_SIZE: i64 = 8 * 1024 * 1024;
+
+/// Asks `peers` in turn for the info dictionary of `magnet`. A `listen_port`
+/// is told to the peers in our extension handshake.
+pub async fn fetch_metadata(
+    magnet: &Magnet,
+    peers: &[Peer],
+    listen_port: Option<u16>,
+) -> Result<Torrent, TorrentError>
+{
+    let announce = magnet
+        .trackers()
+        .first()
+        .cloned()
+        .ok_or(MetadataError::FieldError("tr".to_string()))?;
+
+    if let Some(name) = magnet.display_name()
+    {
+        println!("Fetching metadata of {}", name);
+    }
+
+    for peer in peers
+    {
+        let fetch = fetch_metadata_from_peer(magnet, peer, listen_port);
+
+        match timeout(Duration::from_secs(30), fetch).await
+        {
+            Ok(Ok(info)) => {
+                println!("Metadata received from peer: {}", peer);
+                let torrent = torrent_from_info(announce, &info, *magnet.info_hash())?;
+
+                // Nothing asks peers for the layers with hash requests, so
+                // such pieces could never be verified.
+                if torrent.info().needs_piece_layers()
+                {
+                    return Err(MetadataError::PieceLayersUnavailable.into());
+                }
+                return Ok(torrent.with_announce_list(magnet.announce_list()));
+            }
+            Ok(Err(e)) => {
+                eprintln!("Metadata fetch failed with peer {} - Error: {}", peer, e);
+            }
+            Err(_) => {
+                eprintln!("Metadata fetch timed out with peer {}", peer);
+            }
+        }
+    }
+    Err(MetadataError::MetadataUnavailable.into())
+}
+
+async fn fetch_metadata_from_peer(
+    magnet: &Magnet,
+    peer: &Peer,
+    listen_port: Option<u16>,
+) -> Result<HashMap<Vec<u8>, Value>, TorrentError>
+{
+    let addr = peer.to_string();
+    let mut stream = connect_to_peer(peer).await?;
+    let handshake = Handshake::new(*magnet.info_hash()).with_extension_protocol();
+    let response = exchange_handshake(&mut stream, &handshake, &addr).await?;
+
+    if !handshake.negotiates_extension_protocol(&response)
+    {
+        return Err(HandshakeError::ExtensionProtocolUnsupported(addr).into());
+    }
+
+    let (tx, mut rx) = oneshot::channel();
+    let mut registry = ExtensionRegistry::new();
+
+    if let Some(listen_port) = listen_port
+    {
+        registry = registry.with_listen_port(listen_port);
+    }
+    registry.register(Box::new(MetadataFetcher::new(*magnet.info_hash(), tx)));
+    let mut frames = Framed::new(stream, MessageCodec::default());
+    frames.send(&registry.handshake_message(MAX_INCOMING_REQUESTS)?).await?;
+
+    let metadata = loop
+    {
+        let message = frames.next().await.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Peer closed the connection")
+        })?;
+        let (id, payload) = match message?
+        {
+            Message::Extended { id, payload } => (id, payload),
+            _ => continue,
+        };
+
+        for reply in registry.handle(id, &payload)?
+        {
+            frames.send(&reply).await?;
+        }
+        let supports_metadata = registry
+            .peer_handshake()
+            .and_then(|handshake| handshake.extension_id(UT_METADATA))
+            .is_some();
+
+        if id == EXTENSION_HANDSHAKE_ID && !supports_metadata
+        {
+            return Err(HandshakeError::ExtensionProtocolUnsupported(addr).into());
+        }
+        if let Ok(metadata) = rx.try_recv()
+        {
+            break metadata?;
+        }
+    };
+
+    match serde_bencode::from_bytes(&metadata).map_err(MetadataError::BencodeError)?
+    {
+        Value::Dict(info) => Ok(info),
+        _ => Err(MetadataError::IncorrectFormatError.into()),
+    }
+}
+
+/// ut_metadata handler that requests every metadata piece once the peer
+/// announces the size and hands back the assembled info dictionary.
+struct MetadataFetcher
+{
+    info_hash: [u8; 20],
+    metadata: Vec<u8>,
+    received: Vec<bool>,
+    result: Option<oneshot::Sender<Result<Vec<u8>, MetadataError>>>,
+}
+
+impl MetadataFetcher
+{
+    fn new(info_hash: [u8; 20], result: oneshot::Sender<Result<Vec<u8>, MetadataError>>) -> Self
+    {
+        Self
+        {
+            info_hash,
+            metadata: Vec::new(),
+            received: Vec::new(),
+            result: Some(result),
+        }
+    }
+
+    fn finish(&mut self, result: Result<Vec<u8>, MetadataError>)
+    {
+        if let Some(sender) = self.result.take()
+        {
+            let _ = sender.send(result);
+        }
+    }
+}
+
+impl ExtensionHandler for MetadataFetcher
+{
+    fn name(&self) -> &'static str
+    {
+        UT_METADATA
+    }
+
+    fn on_handshake(
+        &mut self,
+        handshake: &ExtensionHandshake,
+    ) -> Result<Vec<Vec<u8>>, MetadataError>
+    {
+        let metadata_size = handshake
+            .metadata_size()
+            .filter(|size| (1..=MAX_METADATA_SIZE).contains(size))
+            .ok_or(MetadataError::FieldError("metadata_size".to_string()))? as usize;
+        let num_pieces = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+
+        self.metadata = vec![0; metadata_size];
+        self.received = vec![false; num_pieces];
+
+        (0..num_pieces)
+            .map(|piece| MetadataMessage::Request { piece: piece as u32 }.as_bytes())
+            .collect()
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, MetadataError>
+    {
+        match MetadataMessage::from_bytes(payload)?
+        {
+            MetadataMessage::Data { piece, data, .. } => {
+                let piece = piece as usize;
+                let begin = piece * METADATA_PIECE_SIZE;
+                let end = std::cmp::min(begin + METADATA_PIECE_SIZE, self.metadata.len());
+
+                if piece >= self.received.len() || data.len() != end - begin
+                {
+                    return Err(MetadataError::FieldError("piece".to_string()));
+                }
+                self.metadata[begin..end].copy_from_slice(&data);
+                self.received[piece] = true;
+
+                if !self.received.contains(&false)
+                {
+                    let mut hasher = Sha1::new();
+                    hasher.update(&self.metadata);
+
+                    let result = if hasher.finalize().as_slice() == self.info_hash
+                    {
+                        Ok(std::mem::take(&mut self.metadata))
+                    }
+                    else { Err(MetadataError::InfoHashMismatch) };
+                    self.finish(result);
+                }
+                Ok(Vec::new())
+            }
+            MetadataMessage::Reject { .. } => {
+                self.finish(Err(MetadataError::MetadataUnavailable));
+                Ok(Vec::new())
+            }
+            MetadataMessage::Request { piece } => {
+                Ok(vec![MetadataMessage::Reject { piece }.as_bytes()?])
+            }
+        }
+    }
+}
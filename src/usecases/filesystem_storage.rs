@@ -0,0 +1,284 @@
+use crate::entities::file_layout::FileLayout;
+use crate::entities::resume_data::{ResumeData, ResumeFile};
+use crate::entities::storage::Storage;
+use crate::entities::torrent::TorrentInfo;
+use crate::utils::errors::{MetadataError, TorrentError};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::sync::Mutex;
+
+/// Stores the torrent in its files under a download directory, keeping the
+/// handles it has opened until the storage is dropped. Resume data is kept
+/// next to the download as `<name>.resume`.
+pub struct FilesystemStorage
+{
+    layout: FileLayout,
+    piece_length: u64,
+    num_pieces: usize,
+    resume_path: PathBuf,
+    handles: Mutex<HashMap<usize, File>>,
+}
+
+impl FilesystemStorage
+{
+    pub fn new(info: &TorrentInfo, output_dir: &Path) -> Result<Self, MetadataError>
+    {
+        Ok(Self
+        {
+            layout: FileLayout::new(info, output_dir)?,
+            piece_length: *info.piece_length() as u64,
+            num_pieces: info.num_pieces(),
+            resume_path: output_dir.join(format!("{}.resume", info.name())),
+            handles: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Opens a file of the layout, or returns `None` when it does not exist.
+    async fn open<'a>(
+        &self,
+        handles: &'a mut HashMap<usize, File>,
+        file_index: usize,
+    ) -> Result<Option<&'a mut File>, TorrentError>
+    {
+        if let Entry::Vacant(entry) = handles.entry(file_index)
+        {
+            let path = self.layout.files()[file_index].path();
+
+            match OpenOptions::new().read(true).write(true).open(path).await
+            {
+                Ok(file) => entry.insert(file),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+        }
+        Ok(handles.get_mut(&file_index))
+    }
+
+    /// Current size and modification time of every file, or `None` if one
+    /// of them is missing.
+    async fn file_states(&self) -> Result<Option<Vec<ResumeFile>>, TorrentError>
+    {
+        let mut states = Vec::with_capacity(self.layout.files().len());
+
+        for file in self.layout.files()
+        {
+            let metadata = match tokio::fs::metadata(file.path()).await
+            {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let mtime = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as i64);
+            states.push(ResumeFile::new(metadata.len(), mtime));
+        }
+        Ok(Some(states))
+    }
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage
+{
+    async fn read_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, TorrentError>
+    {
+        let offset = piece_index as u64 * self.piece_length + begin as u64;
+        let mut buffer = vec![0; length];
+        let mut handles = self.handles.lock().await;
+
+        for span in self.layout.spans(offset, length)
+        {
+            let Some(file) = self.open(&mut handles, *span.file_index()).await?
+            else { return Ok(None) };
+
+            file.seek(SeekFrom::Start(*span.file_offset())).await?;
+
+            match file.read_exact(&mut buffer[span.buffer_range().clone()]).await
+            {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Some(buffer))
+    }
+
+    async fn write_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> Result<(), TorrentError>
+    {
+        let offset = piece_index as u64 * self.piece_length + begin as u64;
+        let mut handles = self.handles.lock().await;
+
+        for span in self.layout.spans(offset, data.len())
+        {
+            let path = self.layout.files()[*span.file_index()].path().display().to_string();
+            let file = self
+                .open(&mut handles, *span.file_index())
+                .await?
+                .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, path))?;
+
+            file.seek(SeekFrom::Start(*span.file_offset())).await?;
+            file.write_all(&data[span.buffer_range().clone()]).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), TorrentError>
+    {
+        for file in self.handles.lock().await.values_mut()
+        {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Creates every file, with its directories, at its final size.
+    async fn truncate(&self) -> Result<(), TorrentError>
+    {
+        let mut handles = self.handles.lock().await;
+
+        for (file_index, file) in self.layout.files().iter().enumerate()
+        {
+            if let Some(parent) = file.path().parent()
+            {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let handle = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(false)
+                .open(file.path())
+                .await?;
+
+            // Resizing touches the mtime, which would invalidate resume data.
+            if handle.metadata().await?.len() != *file.length()
+            {
+                handle.set_len(*file.length()).await?;
+            }
+            handles.insert(file_index, handle);
+        }
+        Ok(())
+    }
+
+    async fn load_resume(&self, info_hash: &[u8; 20]) -> Result<Option<Vec<bool>>, TorrentError>
+    {
+        let bytes = match tokio::fs::read(&self.resume_path).await
+        {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let Ok(resume) = ResumeData::from_bytes(&bytes, self.num_pieces)
+        else { return Ok(None) };
+
+        let unchanged = self.file_states().await?.as_ref() == Some(resume.files());
+        Ok((resume.info_hash() == info_hash && unchanged).then(|| resume.pieces().clone()))
+    }
+
+    /// Flushes pending writes first so the recorded mtimes are final.
+    async fn save_resume(&self, info_hash: &[u8; 20], pieces: &[bool]) -> Result<(), TorrentError>
+    {
+        self.flush().await?;
+
+        let Some(files) = self.file_states().await?
+        else { return Ok(()) };
+
+        let resume = ResumeData::new(*info_hash, pieces.to_vec(), files);
+        let temporary = self.resume_path.with_extension("resume.tmp");
+        tokio::fs::write(&temporary, resume.as_bytes()?).await?;
+        tokio::fs::rename(&temporary, &self.resume_path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::entities::memory_storage::MemoryStorage;
+    use crate::entities::torrent::FileInfo;
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+
+    /// Four pieces over a file that ends mid-piece, an empty file and a
+    /// file in a subdirectory.
+    fn multi_file_info() -> TorrentInfo
+    {
+        let files = vec![
+            FileInfo::new(20000, vec!["a.bin".to_string()]),
+            FileInfo::new(0, vec!["empty".to_string()]),
+            FileInfo::new(30001, vec!["dir".to_string(), "b.bin".to_string()]),
+        ];
+        TorrentInfo::new("multi".to_string(), PIECE_LENGTH as i64, vec![0; 4 * 20], 0, files)
+    }
+
+    /// Writes every piece as two uneven blocks, the second one first, then
+    /// reads each piece back whole. Returns the content written.
+    async fn round_trip(storage: &dyn Storage, info: &TorrentInfo) -> Vec<u8>
+    {
+        let data: Vec<u8> = (0..info.total_length() as usize).map(|i| (i % 251) as u8).collect();
+        storage.truncate().await.unwrap();
+
+        for piece_index in 0..info.num_pieces()
+        {
+            let begin = piece_index * PIECE_LENGTH;
+            let piece = &data[begin..begin + info.piece_size(piece_index)];
+            let split = piece.len() / 3;
+            storage.write_block(piece_index, split, &piece[split..]).await.unwrap();
+            storage.write_block(piece_index, 0, &piece[..split]).await.unwrap();
+        }
+
+        for piece_index in 0..info.num_pieces()
+        {
+            let begin = piece_index * PIECE_LENGTH;
+            let length = info.piece_size(piece_index);
+            let piece = storage.read_block(piece_index, 0, length).await.unwrap();
+            assert_eq!(piece.as_deref(), Some(&data[begin..begin + length]));
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn memory_storage_round_trips_pieces()
+    {
+        let info = multi_file_info();
+        let storage = MemoryStorage::new(&info);
+        let data = round_trip(&storage, &info).await;
+
+        assert_eq!(storage.contents(), data);
+    }
+
+    #[tokio::test]
+    async fn filesystem_storage_round_trips_pieces()
+    {
+        let dir = std::env::temp_dir().join(format!("bitcrab-storage-{}", std::process::id()));
+        let info = multi_file_info();
+        let storage = FilesystemStorage::new(&info, &dir).unwrap();
+        let data = round_trip(&storage, &info).await;
+        let root = dir.join("multi");
+
+        assert_eq!(std::fs::read(root.join("a.bin")).unwrap(), data[..20000]);
+        assert_eq!(std::fs::read(root.join("empty")).unwrap(), b"");
+        assert_eq!(std::fs::read(root.join("dir").join("b.bin")).unwrap(), data[20000..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use crate::entities::lsd::{LsdAnnounce, LSD_IPV4_GROUP, LSD_IPV6_GROUP, LSD_PORT};
+use crate::entities::peer::Peer;
+use crate::utils::errors::TorrentError;
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep_until, Duration, Instant};
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// BEP 14 allows at most one announce per minute. Announces from a peer
+/// we heard from within the last minute are dropped as well.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_DATAGRAM_LEN: usize = 1400;
+const COOKIE_LEN: usize = 8;
+
+/// Local Service Discovery (BEP 14): finds peers for our torrents on the
+/// local network through multicast, without any tracker. Every instance
+/// picks a random cookie so it can ignore its own announces, which the
+/// multicast loopback hands back to it.
+pub struct LocalDiscovery
+{
+    cookie: String,
+    ipv4: Option<UdpSocket>,
+    ipv6: Option<UdpSocket>,
+}
+
+impl LocalDiscovery
+{
+    /// Joins the IPv4 group, and the IPv6 group where the host has IPv6.
+    /// Fails only when neither can be joined.
+    pub async fn bind() -> Result<Self, TorrentError>
+    {
+        let ipv6 = bind_ipv6().ok();
+        let ipv4 = match bind_ipv4()
+        {
+            Ok(socket) => Some(socket),
+            Err(e) if ipv6.is_none() => return Err(e.into()),
+            Err(_) => None,
+        };
+
+        Ok(Self
+        {
+            cookie: hex::encode(rand::random::<[u8; COOKIE_LEN / 2]>()),
+            ipv4,
+            ipv6,
+        })
+    }
+
+    /// Announces `info_hashes` on every group we joined. Succeeds if at
+    /// least one group was reached.
+    pub async fn announce(&self, info_hashes: &[[u8; 20]], port: u16) -> Result<(), TorrentError>
+    {
+        let announce = LsdAnnounce::new(port, info_hashes.to_vec()).with_cookie(&self.cookie);
+        let groups = [
+            (&self.ipv4, SocketAddr::from((LSD_IPV4_GROUP, LSD_PORT))),
+            (&self.ipv6, SocketAddr::from((LSD_IPV6_GROUP, LSD_PORT))),
+        ];
+        let mut result = Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+
+        for (socket, group) in groups
+        {
+            if let Some(socket) = socket
+            {
+                let sent = socket.send_to(&announce.as_bytes(group), group).await;
+                result = result.or(sent.map(|_| ()));
+            }
+        }
+        Ok(result?)
+    }
+
+    /// Waits for the next announce from another instance, skipping our own
+    /// and anything that does not parse.
+    pub async fn receive(&self) -> Result<(LsdAnnounce, SocketAddr), TorrentError>
+    {
+        let mut buffer_v4 = [0; MAX_DATAGRAM_LEN];
+        let mut buffer_v6 = [0; MAX_DATAGRAM_LEN];
+
+        loop
+        {
+            let (bytes, from) = tokio::select! {
+                received = receive_from(self.ipv4.as_ref(), &mut buffer_v4) => {
+                    let (len, from) = received?;
+                    (&buffer_v4[..len], from)
+                }
+                received = receive_from(self.ipv6.as_ref(), &mut buffer_v6) => {
+                    let (len, from) = received?;
+                    (&buffer_v6[..len], from)
+                }
+            };
+
+            match LsdAnnounce::from_bytes(bytes)
+            {
+                Ok(announce) if announce.cookie().as_deref() != Some(self.cookie.as_str()) => {
+                    return Ok((announce, from));
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+fn bind_ipv4() -> io::Result<UdpSocket>
+{
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Several clients on one host all listen on the LSD port.
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.join_multicast_v4(LSD_IPV4_GROUP, Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+fn bind_ipv6() -> io::Result<UdpSocket>
+{
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, LSD_PORT)).into())?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.join_multicast_v6(&LSD_IPV6_GROUP, 0)?;
+    Ok(socket)
+}
+
+/// Never completes for a group we did not join.
+async fn receive_from(
+    socket: Option<&UdpSocket>,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr)>
+{
+    match socket
+    {
+        Some(socket) => socket.recv_from(buffer).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Announces the torrent on the local network every five minutes and sends
+/// the local peers that announce it to `peer_tx`. A peer we have not heard
+/// from gets an announce of our own as soon as the rate limit allows, so
+/// it does not have to wait for our next round to learn about us.
+pub async fn announce_locally(
+    lsd: LocalDiscovery,
+    info_hash: [u8; 20],
+    port: u16,
+    peer_tx: mpsc::Sender<Vec<Peer>>,
+)
+{
+    let mut announce_timer = interval(ANNOUNCE_INTERVAL);
+    let mut last_announce: Option<Instant> = None;
+    let mut reply_at: Option<Instant> = None;
+    let mut heard: HashMap<SocketAddr, Instant> = HashMap::new();
+
+    loop
+    {
+        tokio::select! {
+            _ = announce_timer.tick() => {}
+            _ = sleep_until(reply_at.unwrap_or_else(Instant::now)), if reply_at.is_some() => {}
+            received = lsd.receive() => {
+                let (announce, from) = match received
+                {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("Local service discovery stopped - Error: {}", e);
+                        return;
+                    }
+                };
+
+                if !announce.info_hashes().contains(&info_hash)
+                {
+                    continue;
+                }
+                let peer = SocketAddr::new(from.ip(), *announce.port());
+                heard.retain(|_, heard_at| heard_at.elapsed() < MIN_ANNOUNCE_INTERVAL);
+
+                if heard.insert(peer, Instant::now()).is_none()
+                {
+                    println!("Local peer discovered: {}", peer);
+                    let _ = peer_tx.try_send(vec![Peer::new(peer.ip(), peer.port())]);
+                    let allowed_at = last_announce
+                        .map_or_else(Instant::now, |last| last + MIN_ANNOUNCE_INTERVAL);
+                    reply_at = reply_at.or(Some(allowed_at));
+                }
+                continue;
+            }
+        }
+
+        if let Err(e) = lsd.announce(&[info_hash], port).await
+        {
+            eprintln!("Local service discovery announce failed - Error: {}", e);
+        }
+        last_announce = Some(Instant::now());
+        reply_at = None;
+    }
+}
@@ -1,4 +1,17 @@
 pub mod parse_torrent_file;
 pub mod peer_tracker;
+pub mod udp_tracker;
 pub mod perform_handshake;
-pub mod download_torrent;
\ No newline at end of file
+pub mod peer_session;
+pub mod filesystem_storage;
+pub mod download_torrent;
+pub mod seeder;
+pub mod choke_manager;
+pub mod peer_exchange;
+pub mod dht;
+pub mod local_discovery;
+pub mod verify_torrent;
+pub mod create_torrent;
+pub mod fast_resume;
+pub mod fetch_metadata;
+pub mod announcer;
\ No newline at end of file
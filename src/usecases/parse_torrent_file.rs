@@ -1,10 +1,9 @@
-use crate::entities::torrent::{Torrent, TorrentInfo};
-use crate::usecases::download_torrent::download_torrent;
-use crate::usecases::peer_tracker::discover_peers;
-use crate::usecases::perform_handshake::perform_handshake;
+use crate::entities::merkle::{layer_root, sha256, Sha256Hash, MERKLE_BLOCK_SIZE};
+use crate::entities::torrent::{FileInfo, Torrent, TorrentInfo};
 use crate::utils::errors::{FileError, MetadataError, TorrentError};
 use crate::utils::extract_torrent_metadata::{
-    extract_bytes, extract_dict, extract_files, extract_int, extract_string,
+    extract_announce_list, extract_bytes, extract_dict, extract_file_tree, extract_files,
+    extract_int, extract_string, extract_web_seeds,
 };
 
 use anyhow::Result;
@@ -21,23 +20,48 @@ pub async fn parse_torrent_file<T: Into<PathBuf>>(file_path: T) -> Result<Torren
 
     if let Value::Dict(d) = value
     {
-        let announce = extract_string("announce", &d)?;
+        let announce_list = extract_announce_list(&d).ok();
+        let announce = match extract_string("announce", &d)
+        {
+            Ok(announce) => Url::parse(&announce)?,
+            Err(e) => announce_list
+                .as_ref()
+                .and_then(|list| list.trackers().next().cloned())
+                .ok_or(e)?,
+        };
         let info = extract_dict("info", &d)?;
-        let info_hash = calculate_info_hash(&info)?;
-        let length = extract_int("length", &info).unwrap_or(0);
-        let files = extract_files(&info).unwrap_or_default();
-
-        Ok(Torrent::new(
-            Url::parse(&announce)?,
-            TorrentInfo::new(
-                extract_string("name", &info)?,
-                extract_int("piece length", &info)?,
-                extract_bytes("pieces", &info)?,
-                length,
-                files,
-            ),
-            info_hash,
-        ))
+        let info_hash_v2 = match extract_int("meta version", &info)
+        {
+            Ok(2) => Some(calculate_info_hash_v2(&info)?),
+            _ => None,
+        };
+        // v2 torrents without v1 pieces go by the truncated SHA-256 hash.
+        let info_hash = match info_hash_v2
+        {
+            Some(hash) if !info.contains_key(b"pieces".as_slice()) => {
+                hash[..20].try_into().unwrap()
+            }
+            _ => calculate_info_hash(&info)?,
+        };
+        let mut torrent = torrent_from_info(announce, &info, info_hash)?;
+
+        if let Some(info_hash_v2) = info_hash_v2
+        {
+            let piece_layers = extract_piece_layers(&d, torrent.info())?;
+            torrent = torrent.with_v2(info_hash_v2, piece_layers);
+        }
+
+        let torrent = torrent
+            .with_comment(extract_string("comment", &d).ok())
+            .with_created_by(extract_string("created by", &d).ok())
+            .with_creation_date(extract_int("creation date", &d).ok())
+            .with_web_seeds(extract_web_seeds(&d));
+
+        Ok(match announce_list
+        {
+            Some(announce_list) => torrent.with_announce_list(announce_list),
+            None => torrent,
+        })
     }
     else
     {
@@ -47,13 +71,193 @@ pub async fn parse_torrent_file<T: Into<PathBuf>>(file_path: T) -> Result<Torren
     }
 }
 
+pub fn torrent_from_info(
+    announce: Url,
+    info: &HashMap<Vec<u8>, Value>,
+    info_hash: [u8; 20],
+) -> Result<Torrent, MetadataError>
+{
+    let name = extract_string("name", info)?;
+    let piece_length = extract_int("piece length", info)?;
+    let mut length = extract_int("length", info).unwrap_or(0);
+    let mut files = extract_files(info).unwrap_or_default();
+
+    let torrent_info = match extract_int("meta version", info).unwrap_or(1)
+    {
+        1 => TorrentInfo::new(name, piece_length, extract_bytes("pieces", info)?, length, files),
+        2 => {
+            // BEP 52 pieces are whole subtrees of 16 KiB blocks.
+            if piece_length < MERKLE_BLOCK_SIZE as i64 || piece_length.count_ones() != 1
+            {
+                return Err(MetadataError::FieldError("piece length".to_string()));
+            }
+            let file_tree = extract_file_tree(info)?;
+            let pieces = extract_bytes("pieces", info).unwrap_or_default();
+
+            if pieces.is_empty()
+            {
+                (length, files) = aligned_files(&name, &file_tree, piece_length);
+            }
+            else if v1_files(&name, length, &files) != tree_files(&file_tree, piece_length)
+            {
+                // A hybrid must describe the same files under both schemes.
+                return Err(MetadataError::FieldError("file tree".to_string()));
+            }
+            TorrentInfo::new(name, piece_length, pieces, length, files).with_file_tree(file_tree)
+        }
+        _ => return Err(MetadataError::FieldError("meta version".to_string())),
+    };
+    let private = extract_int("private", info).is_ok_and(|private| private == 1);
+    Ok(Torrent::new(announce, torrent_info.with_private(private), info_hash))
+}
+
+/// The v1 view of a v2 torrent: a single file stays a single-file torrent,
+/// several files get padding files that start each one on a piece boundary.
+pub fn aligned_files(name: &str, file_tree: &[FileInfo], piece_length: i64) -> (i64, Vec<FileInfo>)
+{
+    if let [file] = file_tree
+    {
+        if file.path().as_slice() == [name]
+        {
+            return (*file.length(), Vec::new());
+        }
+    }
+
+    let mut files = Vec::new();
+
+    for (index, file) in file_tree.iter().enumerate()
+    {
+        files.push(file.clone());
+        let tail = file.length() % piece_length;
+
+        if tail != 0 && index + 1 < file_tree.len()
+        {
+            let path = vec![".pad".to_string(), (piece_length - tail).to_string()];
+            files.push(FileInfo::new(piece_length - tail, path).with_padding(true));
+        }
+    }
+    (0, files)
+}
+
+/// Path, length and offset in the piece stream of every v1 file except
+/// the padding ones.
+fn v1_files(name: &str, length: i64, files: &[FileInfo]) -> Vec<(Vec<String>, i64, i64)>
+{
+    if files.is_empty()
+    {
+        return vec![(vec![name.to_string()], length, 0)];
+    }
+
+    let mut offset = 0;
+    let mut entries = Vec::new();
+
+    for file in files
+    {
+        if !file.padding()
+        {
+            entries.push((file.path().clone(), *file.length(), offset));
+        }
+        offset += file.length();
+    }
+    entries
+}
+
+/// Path, length and offset in the piece stream of every file of the tree.
+fn tree_files(file_tree: &[FileInfo], piece_length: i64) -> Vec<(Vec<String>, i64, i64)>
+{
+    let mut offset = 0;
+    let mut entries = Vec::new();
+
+    for file in file_tree
+    {
+        entries.push((file.path().clone(), *file.length(), offset));
+        offset += (*file.length() as u64).div_ceil(piece_length as u64) as i64 * piece_length;
+    }
+    entries
+}
+
+/// Checks the piece layer of every file longer than one piece against the
+/// file's `pieces root`. Smaller files are verified against the root alone.
+fn extract_piece_layers(
+    torrent: &HashMap<Vec<u8>, Value>,
+    info: &TorrentInfo,
+) -> Result<HashMap<Sha256Hash, Vec<Sha256Hash>>, MetadataError>
+{
+    let layers = extract_dict("piece layers", torrent).unwrap_or_default();
+    let piece_length = *info.piece_length();
+    let mut piece_layers = HashMap::new();
+
+    for file in info.file_tree()
+    {
+        let Some(pieces_root) = file.pieces_root() else { continue };
+
+        if *file.length() <= piece_length
+        {
+            continue;
+        }
+
+        let expected = (*file.length() as u64).div_ceil(piece_length as u64) as usize;
+        let layer: Vec<Sha256Hash> = match layers.get(pieces_root.as_slice())
+        {
+            Some(Value::Bytes(bytes)) if bytes.len() == expected * 32 => bytes
+                .chunks(32)
+                .map(|chunk| chunk.try_into().unwrap())
+                .collect(),
+            _ => return Err(MetadataError::FieldError("piece layers".to_string())),
+        };
+
+        if layer_root(&layer, piece_length as usize) != *pieces_root
+        {
+            return Err(MetadataError::FieldError("piece layers".to_string()));
+        }
+        piece_layers.insert(*pieces_root, layer);
+    }
+    Ok(piece_layers)
+}
+
 pub async fn print_torrent_info(torrent: &Torrent)
 {
     println!("Tracker URL: {}", torrent.announce());
+
+    for (tier, trackers) in torrent.announce_list().tiers().iter().enumerate()
+    {
+        for tracker in trackers
+        {
+            println!("Tier {}: {}", tier, tracker);
+        }
+    }
     println!("Name: {}", torrent.info().name());
     println!("Piece Length: {}", torrent.info().piece_length());
-    println!("Total Length: {}", torrent.info().length());
+    println!("Total Length: {}", torrent.info().total_length());
+
+    for file in torrent.info().files()
+    {
+        println!("File: {} ({} bytes)", file.path().join("/"), file.length());
+    }
+    if let Some(comment) = torrent.comment()
+    {
+        println!("Comment: {}", comment);
+    }
+    if let Some(created_by) = torrent.created_by()
+    {
+        println!("Created By: {}", created_by);
+    }
+    if let Some(creation_date) = torrent.creation_date()
+    {
+        println!("Creation Date: {}", creation_date);
+    }
+    for web_seed in torrent.web_seeds()
+    {
+        println!("Web Seed: {}", web_seed);
+    }
+    println!("Private: {}", torrent.info().private());
+    println!("Meta Version: {}", torrent.info().meta_version());
     println!("Info Hash: {}", hex::encode(torrent.info_hash()));
+
+    if let Some(info_hash_v2) = torrent.info_hash_v2()
+    {
+        println!("Info Hash v2: {}", hex::encode(info_hash_v2));
+    }
     println!("Piece Hashes:");
 
     for hash in torrent.info().piece_hashes()
@@ -62,48 +266,177 @@ pub async fn print_torrent_info(torrent: &Torrent)
     }
 }
 
-pub async fn process_torrent(torrent: &Torrent)
+pub fn calculate_info_hash(info_dict: &HashMap<Vec<u8>, Value>) -> Result<[u8; 20], MetadataError>
+{
+    let info_bencode = serde_bencode::to_bytes(&Value::Dict(info_dict.clone()))?;
+
+    let mut hasher = Sha1::new();
+    hasher.update(&info_bencode);
+    let info_hash = hasher.finalize();
+    Ok(info_hash.into())
+}
+
+pub fn calculate_info_hash_v2(
+    info_dict: &HashMap<Vec<u8>, Value>,
+) -> Result<Sha256Hash, MetadataError>
 {
-    match discover_peers(torrent).await
+    let info_bencode = serde_bencode::to_bytes(&Value::Dict(info_dict.clone()))?;
+    Ok(sha256(&info_bencode))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::entities::merkle::{file_root, piece_root};
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+
+    fn dict(entries: Vec<(&str, Value)>) -> HashMap<Vec<u8>, Value>
     {
-        Ok(tracker_response) => {
-            println!("Interval: {}", tracker_response.interval());
-            println!("Peers:");
+        entries.into_iter().map(|(key, value)| (key.as_bytes().to_vec(), value)).collect()
+    }
 
-            for peer in tracker_response.peers()
-            {
-                println!("{}:{}", peer.ip(), peer.port());
-            }
+    fn tree_entry(data: &[u8]) -> Value
+    {
+        let root = if data.len() > PIECE_LENGTH
+        {
+            let layer: Vec<Sha256Hash> =
+                data.chunks(PIECE_LENGTH).map(|piece| piece_root(piece, PIECE_LENGTH)).collect();
+            layer_root(&layer, PIECE_LENGTH)
+        }
+        else { file_root(data) };
 
-            match perform_handshake(torrent, tracker_response.peers()).await
-            {
-                Ok(connected_peers) => {
-                    println!("Connected Peers:");
-
-                    for peer in &connected_peers
-                    {
-                        println!("{}:{}", peer.ip(), peer.port());
-                    }
-                    if let Err(e) = download_torrent(torrent, &connected_peers).await
-                    {
-                        eprintln!("Failed to download torrent: {}", e);
-                    }
-                }
-                Err(e) => {
-                    println!("Handshake process encountered an error: {}", e);
-                }
-            }
+        let file = dict(vec![
+            ("length", Value::Int(data.len() as i64)),
+            ("pieces root", Value::Bytes(root.to_vec())),
+        ]);
+        Value::Dict(dict(vec![("", Value::Dict(file))]))
+    }
+
+    /// Info of a torrent holding `a` and `b`, with v1 pieces over
+    /// `v1_files` when given.
+    fn info(a: &[u8], b: &[u8], v1_files: Option<Vec<Value>>) -> HashMap<Vec<u8>, Value>
+    {
+        let file_tree = dict(vec![("a", tree_entry(a)), ("b", tree_entry(b))]);
+        let mut info = dict(vec![
+            ("name", Value::Bytes(b"multi".to_vec())),
+            ("piece length", Value::Int(PIECE_LENGTH as i64)),
+            ("meta version", Value::Int(2)),
+            ("file tree", Value::Dict(file_tree)),
+        ]);
+
+        if let Some(files) = v1_files
+        {
+            info.insert(b"files".to_vec(), Value::List(files));
+            info.insert(b"pieces".to_vec(), Value::Bytes(vec![0; 20 * 3]));
         }
-        Err(e) => { println!("Failed to discover peers: {}", e); }
+        info
     }
-}
 
-fn calculate_info_hash(info_dict: &HashMap<Vec<u8>, Value>) -> Result<[u8; 20], MetadataError>
-{
-    let info_bencode = serde_bencode::to_bytes(&Value::Dict(info_dict.clone()))?;
+    fn v1_file(path: &str, length: usize, padding: bool) -> Value
+    {
+        let mut file = dict(vec![
+            ("length", Value::Int(length as i64)),
+            ("path", Value::List(vec![Value::Bytes(path.as_bytes().to_vec())])),
+        ]);
 
-    let mut hasher = Sha1::new();
-    hasher.update(&info_bencode);
-    let info_hash = hasher.finalize();
-    Ok(info_hash.into())
+        if padding
+        {
+            file.insert(b"attr".to_vec(), Value::Bytes(b"p".to_vec()));
+        }
+        Value::Dict(file)
+    }
+
+    fn piece_layers(data: &[u8]) -> HashMap<Vec<u8>, Value>
+    {
+        let layer: Vec<Sha256Hash> =
+            data.chunks(PIECE_LENGTH).map(|piece| piece_root(piece, PIECE_LENGTH)).collect();
+        let root = layer_root(&layer, PIECE_LENGTH).to_vec();
+        let layers = HashMap::from([(root, Value::Bytes(layer.concat()))]);
+        dict(vec![("piece layers", Value::Dict(layers))])
+    }
+
+    fn announce() -> Url
+    {
+        Url::parse("http://127.0.0.1/announce").unwrap()
+    }
+
+    #[test]
+    fn v2_pieces_verify_against_the_piece_layer()
+    {
+        let a: Vec<u8> = (0..PIECE_LENGTH + 3000).map(|i| i as u8).collect();
+        let b = vec![5; 100];
+        let torrent = torrent_from_info(announce(), &info(&a, &b, None), [0; 20]).unwrap();
+        let layers = extract_piece_layers(&piece_layers(&a), torrent.info()).unwrap();
+        let torrent = torrent.with_v2([0; 32], layers);
+
+        assert!(torrent.info().needs_piece_layers());
+        assert_eq!(torrent.info().num_pieces(), 3);
+        assert!(torrent.verify_piece(0, &a[..PIECE_LENGTH]));
+        assert!(torrent.verify_piece(1, &a[PIECE_LENGTH..]));
+        assert!(torrent.verify_piece(2, &b));
+        assert!(!torrent.verify_piece(1, &[9; 3000]));
+        assert!(!torrent.verify_piece(2, &[6; 100]));
+    }
+
+    #[test]
+    fn missing_or_wrong_piece_layer_is_rejected()
+    {
+        let a = vec![1; 2 * PIECE_LENGTH];
+        let torrent = torrent_from_info(announce(), &info(&a, &[2], None), [0; 20]).unwrap();
+
+        assert!(extract_piece_layers(&dict(vec![]), torrent.info()).is_err());
+        assert!(extract_piece_layers(&piece_layers(&[3; 2 * PIECE_LENGTH]), torrent.info())
+            .is_err());
+    }
+
+    #[test]
+    fn v2_files_are_padded_to_piece_boundaries()
+    {
+        let a = vec![1; PIECE_LENGTH + 10];
+        let torrent = torrent_from_info(announce(), &info(&a, &[2; 10], None), [0; 20]).unwrap();
+        let files: Vec<(String, i64, bool)> = torrent
+            .info()
+            .files()
+            .iter()
+            .map(|file| (file.path().join("/"), *file.length(), *file.padding()))
+            .collect();
+
+        assert_eq!(
+            files,
+            vec![
+                ("a".to_string(), PIECE_LENGTH as i64 + 10, false),
+                (format!(".pad/{}", PIECE_LENGTH - 10), PIECE_LENGTH as i64 - 10, true),
+                ("b".to_string(), 10, false),
+            ]
+        );
+        assert_eq!(torrent.info().piece_size(1), 10);
+    }
+
+    #[test]
+    fn hybrid_needs_the_same_files_under_both_schemes()
+    {
+        let a = vec![1; PIECE_LENGTH + 10];
+        let b = vec![2; 10];
+        let padded = vec![
+            v1_file("a", a.len(), false),
+            v1_file("pad", PIECE_LENGTH - 10, true),
+            v1_file("b", b.len(), false),
+        ];
+        let torrent = torrent_from_info(announce(), &info(&a, &b, Some(padded)), [0; 20]);
+        assert!(torrent.is_ok_and(|torrent| !torrent.info().needs_piece_layers()));
+
+        // Without the padding file `b` starts mid-piece in v1.
+        let unpadded = vec![v1_file("a", a.len(), false), v1_file("b", b.len(), false)];
+        let torrent = torrent_from_info(announce(), &info(&a, &b, Some(unpadded)), [0; 20]);
+        assert!(matches!(torrent, Err(MetadataError::FieldError(field)) if field == "file tree"));
+
+        let renamed = vec![
+            v1_file("a", a.len(), false),
+            v1_file("pad", PIECE_LENGTH - 10, true),
+            v1_file("c", b.len(), false),
+        ];
+        assert!(torrent_from_info(announce(), &info(&a, &b, Some(renamed)), [0; 20]).is_err());
+    }
 }
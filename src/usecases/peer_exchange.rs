@@ -0,0 +1,168 @@
+use crate::entities::extension::ExtensionHandler;
+use crate::entities::peer::Peer;
+use crate::entities::pex::{PexHistory, PexMessage, MAX_PEX_PEERS, UT_PEX};
+use crate::utils::errors::MetadataError;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+/// BEP 11 limits each peer to one message per minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages arriving sooner than this after the previous one are ignored,
+/// leaving some slack for timer jitter on the sender's side.
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+/// Peer exchange for the sessions of one torrent. Sessions join with the
+/// listen address of their peer, tell their peer about the others every
+/// minute and pass on the peers their peer tells them about.
+#[derive(Clone)]
+pub struct PeerExchange
+{
+    swarm: Arc<Mutex<HashMap<SocketAddr, u8>>>,
+    found_tx: mpsc::Sender<Vec<Peer>>,
+}
+
+impl PeerExchange
+{
+    pub fn new(found_tx: mpsc::Sender<Vec<Peer>>) -> Self
+    {
+        Self
+        {
+            swarm: Arc::new(Mutex::new(HashMap::new())),
+            found_tx,
+        }
+    }
+
+    /// ut_pex handler for the connection to `peer`, registered before the
+    /// extension handshake is sent.
+    pub fn handler(&self, peer: SocketAddr) -> Box<dyn ExtensionHandler>
+    {
+        Box::new(PexReceiver {
+            peer,
+            last_received: None,
+            found_tx: self.found_tx.clone(),
+        })
+    }
+
+    /// Advertises `addr` to the other sessions until the handle is dropped.
+    pub fn join(&self, addr: SocketAddr, flags: u8) -> PexHandle
+    {
+        self.swarm.lock().unwrap().insert(addr, flags);
+
+        PexHandle {
+            addr,
+            flags,
+            swarm: Arc::clone(&self.swarm),
+            history: PexHistory::new(),
+        }
+    }
+}
+
+/// A session's membership in the exchange.
+pub struct PexHandle
+{
+    addr: SocketAddr,
+    flags: u8,
+    swarm: Arc<Mutex<HashMap<SocketAddr, u8>>>,
+    history: PexHistory,
+}
+
+impl PexHandle
+{
+    pub fn set_flags(&mut self, flags: u8)
+    {
+        if flags != self.flags
+        {
+            self.flags = flags;
+            self.swarm.lock().unwrap().insert(self.addr, flags);
+        }
+    }
+
+    /// The changes to the swarm since the last message sent to this peer,
+    /// leaving the peer itself out.
+    pub fn next_message(&mut self) -> Option<PexMessage>
+    {
+        let mut swarm = self.swarm.lock().unwrap().clone();
+        swarm.remove(&self.addr);
+        self.history.update(&swarm)
+    }
+}
+
+impl Drop for PexHandle
+{
+    fn drop(&mut self)
+    {
+        self.swarm.lock().unwrap().remove(&self.addr);
+    }
+}
+
+/// Receives the peer lists of one connection. Peers sending too often or
+/// too much are throttled, and addresses nobody could connect to are
+/// dropped before they reach the download.
+struct PexReceiver
+{
+    peer: SocketAddr,
+    last_received: Option<Instant>,
+    found_tx: mpsc::Sender<Vec<Peer>>,
+}
+
+impl PexReceiver
+{
+    fn is_plausible(&self, addr: &SocketAddr) -> bool
+    {
+        let ip = addr.ip();
+        let reserved = match ip
+        {
+            IpAddr::V4(ip) => ip.is_broadcast() || ip.octets()[0] == 0,
+            IpAddr::V6(_) => false,
+        };
+
+        // Only a peer on this machine can point us at other local peers.
+        addr.port() != 0
+            && *addr != self.peer
+            && !ip.is_unspecified()
+            && !ip.is_multicast()
+            && !reserved
+            && (!ip.is_loopback() || self.peer.ip().is_loopback())
+    }
+}
+
+impl ExtensionHandler for PexReceiver
+{
+    fn name(&self) -> &'static str
+    {
+        UT_PEX
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, MetadataError>
+    {
+        if self
+            .last_received
+            .is_some_and(|last_received| last_received.elapsed() < MIN_RECEIVE_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_received = Some(Instant::now());
+
+        let message = PexMessage::from_bytes(payload)?;
+        let peers: Vec<Peer> = message
+            .added()
+            .iter()
+            .map(|(addr, _)| *addr)
+            .filter(|addr| self.is_plausible(addr))
+            .take(MAX_PEX_PEERS)
+            .map(|addr| Peer::new(addr.ip(), addr.port()))
+            .collect();
+
+        if !peers.is_empty()
+        {
+            // The download is busy if its queue is full; these peers are
+            // not worth waiting for.
+            let _ = self.found_tx.try_send(peers);
+        }
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,300 @@
+use crate::entities::extension::{ExtensionRegistry, MAX_INCOMING_REQUESTS};
+use crate::entities::handshake::Handshake;
+use crate::entities::message::Message;
+use crate::entities::message_codec::{MessageCodec, DEFAULT_MAX_FRAME_LEN};
+use crate::entities::peer::Peer;
+use crate::entities::torrent::Torrent;
+use crate::usecases::perform_handshake::{connect_to_peer, exchange_handshake};
+use crate::utils::errors::{MessageError, TorrentError};
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Peers drop connections that stay silent for two minutes.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(120);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+const INCOMING_CHANNEL_SIZE: usize = 64;
+
+/// A connection to one peer that stays open across pieces. Messages are read
+/// by a background task, so `receive` can be used inside `select!` without
+/// losing partially read frames.
+pub struct PeerSession
+{
+    peer: Peer,
+    writer: FramedWrite<OwnedWriteHalf, MessageCodec>,
+    incoming: mpsc::Receiver<Result<Message, TorrentError>>,
+    reader: JoinHandle<()>,
+    registry: ExtensionRegistry,
+    /// Extension replies waiting for the next `receive` to send them.
+    replies: VecDeque<Message>,
+    bitfield: Vec<bool>,
+    peer_choking: bool,
+    am_interested: bool,
+    am_choking: bool,
+    peer_interested: bool,
+}
+
+impl PeerSession
+{
+    /// Connects and handshakes with `peer`, advertising the extension
+    /// protocol so the peer can tell us its `reqq`, the extensions in
+    /// `registry` and the pieces in `have`. With a `dht_port` we also
+    /// advertise our DHT node.
+    pub async fn connect(
+        torrent: &Torrent,
+        peer: &Peer,
+        have: &[bool],
+        dht_port: Option<u16>,
+        registry: ExtensionRegistry,
+    ) -> Result<Self, TorrentError>
+    {
+        let mut stream = connect_to_peer(peer).await?;
+        let mut handshake = Handshake::new(*torrent.info_hash()).with_extension_protocol();
+
+        if dht_port.is_some()
+        {
+            handshake = handshake.with_dht();
+        }
+        let response = exchange_handshake(&mut stream, &handshake, &peer.to_string()).await?;
+
+        let extensions = handshake.negotiates_extension_protocol(&response);
+        let dht_port = dht_port.filter(|_| handshake.negotiates_dht(&response));
+        Self::from_stream(stream, peer.clone(), have, extensions, dht_port, registry).await
+    }
+
+    /// Starts a session over a connection whose handshake is already done.
+    /// Our bitfield goes first, as the protocol requires, unless `have` is
+    /// empty; our extension handshake follows when both sides negotiated it,
+    /// and a `Port` message when a `dht_port` is given.
+    pub async fn from_stream(
+        stream: TcpStream,
+        peer: Peer,
+        have: &[bool],
+        extensions: bool,
+        dht_port: Option<u16>,
+        registry: ExtensionRegistry,
+    ) -> Result<Self, TorrentError>
+    {
+        let mut session = Self::new(stream, peer, have.len(), registry);
+
+        if have.contains(&true)
+        {
+            session.send(&Message::bitfield(have)).await?;
+        }
+
+        if extensions
+        {
+            let message = session.registry.handshake_message(MAX_INCOMING_REQUESTS)?;
+            session.send(&message).await?;
+        }
+
+        if let Some(listen_port) = dht_port
+        {
+            session.send(&Message::Port { listen_port }).await?;
+        }
+        Ok(session)
+    }
+
+    fn new(stream: TcpStream, peer: Peer, num_pieces: usize, registry: ExtensionRegistry) -> Self
+    {
+        // The bitfield is the largest message whose size depends on the torrent.
+        let codec = MessageCodec::default()
+            .with_max_frame_len(DEFAULT_MAX_FRAME_LEN.max(1 + num_pieces.div_ceil(8)));
+        let (reader, writer) = stream.into_split();
+        let mut reader = FramedRead::new(reader, codec);
+        let (tx, incoming) = mpsc::channel(INCOMING_CHANNEL_SIZE);
+
+        let reader = tokio::spawn(async move {
+            loop
+            {
+                let message = match timeout(MESSAGE_TIMEOUT, reader.next()).await
+                {
+                    Ok(Some(Ok(Message::KeepAlive))) => continue,
+                    Ok(Some(message)) => message,
+                    Ok(None) => Err(connection_closed()),
+                    Err(e) => Err(e.into()),
+                };
+                let failed = message.is_err();
+
+                if tx.send(message).await.is_err() || failed
+                {
+                    break;
+                }
+            }
+        });
+
+        Self
+        {
+            peer,
+            writer: FramedWrite::new(writer, codec),
+            incoming,
+            reader,
+            registry,
+            replies: VecDeque::new(),
+            bitfield: vec![false; num_pieces],
+            peer_choking: true,
+            am_interested: false,
+            am_choking: true,
+            peer_interested: false,
+        }
+    }
+
+    pub fn peer(&self) -> &Peer
+    {
+        &self.peer
+    }
+
+    pub fn bitfield(&self) -> &[bool]
+    {
+        &self.bitfield
+    }
+
+    pub fn has_piece(&self, piece_index: usize) -> bool
+    {
+        self.bitfield.get(piece_index).copied().unwrap_or(false)
+    }
+
+    pub fn is_choked(&self) -> bool
+    {
+        self.peer_choking
+    }
+
+    pub fn is_choking_peer(&self) -> bool
+    {
+        self.am_choking
+    }
+
+    pub fn is_peer_interested(&self) -> bool
+    {
+        self.peer_interested
+    }
+
+    /// Number of block requests to keep outstanding: `limit`, lowered to the
+    /// `reqq` the peer advertised in its extension handshake.
+    pub fn request_queue_len(&self, limit: usize) -> usize
+    {
+        self.registry
+            .peer_handshake()
+            .and_then(|handshake| *handshake.request_queue())
+            .map_or(limit, |reqq| limit.min(reqq.max(1) as usize))
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<(), TorrentError>
+    {
+        self.writer.send(message).await
+    }
+
+    /// Whether the peer's extension handshake enabled the extension `name`.
+    pub fn supports_extension(&self, name: &str) -> bool
+    {
+        self.registry
+            .peer_handshake()
+            .and_then(|handshake| handshake.extension_id(name))
+            .is_some()
+    }
+
+    /// Sends `payload` to the peer's handler for `name`, doing nothing when
+    /// the peer does not support that extension.
+    pub async fn send_extension(&mut self, name: &str, payload: Vec<u8>) -> Result<(), TorrentError>
+    {
+        match self.registry.message(name, payload)
+        {
+            Some(message) => self.send(&message).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sends `Interested` or `NotInterested` when our interest changes.
+    pub async fn set_interested(&mut self, interested: bool) -> Result<(), TorrentError>
+    {
+        if interested != self.am_interested
+        {
+            let message = if interested { Message::Interested } else { Message::NotInterested };
+            self.send(&message).await?;
+            self.am_interested = interested;
+        }
+        Ok(())
+    }
+
+    /// Sends `Choke` or `Unchoke` when our choke state for the peer changes.
+    pub async fn set_choking(&mut self, choking: bool) -> Result<(), TorrentError>
+    {
+        if choking != self.am_choking
+        {
+            let message = if choking { Message::Choke } else { Message::Unchoke };
+            self.send(&message).await?;
+            self.am_choking = choking;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next message and updates the choke and interest state
+    /// and the peer's bitfield from it. Extended messages are handled by the
+    /// registry, whose replies go out at the start of the next call; nothing
+    /// is awaited once a message has been taken, so the call is cancel-safe.
+    pub async fn receive(&mut self) -> Result<Message, TorrentError>
+    {
+        while let Some(reply) = self.replies.front()
+        {
+            self.writer.send(reply).await?;
+            self.replies.pop_front();
+        }
+
+        let message = loop
+        {
+            match timeout(KEEP_ALIVE_INTERVAL, self.incoming.recv()).await
+            {
+                Ok(Some(message)) => break message?,
+                Ok(None) => return Err(connection_closed()),
+                Err(_) => self.send(&Message::KeepAlive).await?,
+            }
+        };
+
+        match &message
+        {
+            Message::Choke => self.peer_choking = true,
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            Message::Have { piece_index } => {
+                let have = self
+                    .bitfield
+                    .get_mut(*piece_index as usize)
+                    .ok_or(MessageError::InvalidPieceIndex(*piece_index))?;
+                *have = true;
+            }
+            Message::Bitfield { bitfield } => {
+                Message::check_bitfield(bitfield, self.bitfield.len())?;
+                self.bitfield = Message::decode_bitfield(bitfield, self.bitfield.len());
+            }
+            Message::Extended { id, payload } => {
+                self.replies.extend(self.registry.handle(*id, payload)?);
+            }
+            _ => {}
+        }
+        Ok(message)
+    }
+}
+
+impl Drop for PeerSession
+{
+    fn drop(&mut self)
+    {
+        self.reader.abort();
+    }
+}
+
+fn connection_closed() -> TorrentError
+{
+    TorrentError::IoError(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "Peer closed the connection",
+    ))
+}
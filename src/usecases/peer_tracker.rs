@@ -1,30 +1,240 @@
+use crate::entities::announce_list::AnnounceList;
+use crate::entities::magnet::Magnet;
 use crate::entities::peer::{Peer, TrackerRequest, TrackerResponse};
+use crate::entities::scrape::ScrapeResponse;
 use crate::entities::torrent::Torrent;
-use crate::utils::errors::{MetadataError, TorrentError};
-use crate::utils::extract_torrent_metadata::extract_int;
+use crate::usecases::udp_tracker::{UdpTrackerClient, UDP_SCRAPE_MAX_HASHES};
+use crate::utils::errors::{MetadataError, TorrentError, TrackerError};
+use crate::utils::extract_torrent_metadata::{
+    extract_bytes, extract_dict, extract_int, extract_string,
+};
+use crate::utils::local_address::local_addresses;
 
 use anyhow::Result;
 use serde_bencode::value::Value;
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::str;
-use tokio::net::UdpSocket;
-use tokio::time::{timeout, Duration};
+use std::net::IpAddr;
+use tokio::net::lookup_host;
 use url::Url;
+use urlencoding::encode_binary;
 
+/// One-off announce for commands that do not keep the torrent around. The
+/// trackers are promoted in a copy of the announce list, so the order is not
+/// remembered; long-running transfers go through the `Announcer`, which
+/// keeps its list.
 pub async fn discover_peers(torrent: &Torrent) -> Result<TrackerResponse, TorrentError>
 {
-    let tracker_request = TrackerRequest::new(torrent);
+    let mut announce_list = torrent.announce_list().clone();
+    let tracker_request = TrackerRequest::new(torrent).with_local_addresses(&local_addresses());
+    announce_to_tiers(&mut announce_list, &tracker_request).await
+}
+
+pub async fn discover_peers_for_magnet(magnet: &Magnet) -> Result<TrackerResponse, TorrentError>
+{
+    let mut announce_list = magnet.announce_list();
+    let tracker_url = announce_list
+        .trackers()
+        .next()
+        .cloned()
+        .ok_or(MetadataError::FieldError("tr".to_string()))?;
+
+    let tracker_request =
+        TrackerRequest::from_magnet(magnet, tracker_url).with_local_addresses(&local_addresses());
+    announce_to_tiers(&mut announce_list, &tracker_request).await
+}
+
+/// Walks the tiers in order. Within a tier trackers are tried in order and
+/// the first one to answer is promoted to the front of its tier, as BEP 12
+/// describes; the peers of every tier that answered are merged.
+pub async fn announce_to_tiers(
+    announce_list: &mut AnnounceList,
+    tracker_request: &TrackerRequest,
+) -> Result<TrackerResponse, TorrentError>
+{
+    let mut responses = Vec::new();
+    let mut last_error = MetadataError::FieldError("announce".to_string()).into();
+
+    for tier in 0..announce_list.tiers().len()
+    {
+        let trackers = announce_list.tiers()[tier].clone();
+
+        match announce_to_tier(&trackers, tracker_request).await
+        {
+            Ok((index, tracker_response)) => {
+                announce_list.promote(tier, index);
+                responses.push(tracker_response);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+
+    if responses.is_empty()
+    {
+        return Err(last_error);
+    }
+    Ok(TrackerResponse::merge(responses))
+}
+
+async fn announce_to_tier(
+    trackers: &[Url],
+    tracker_request: &TrackerRequest,
+) -> Result<(usize, TrackerResponse), TorrentError>
+{
+    let mut last_error = MetadataError::FieldError("announce".to_string()).into();
+
+    for (index, tracker_url) in trackers.iter().enumerate()
+    {
+        let request = tracker_request.clone().with_tracker_url(tracker_url.clone());
+
+        match announce(&request).await
+        {
+            Ok(tracker_response) => return Ok((index, tracker_response)),
+            Err(e) => {
+                eprintln!("Announce to {} failed - Error: {}", tracker_url, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+async fn announce(tracker_request: &TrackerRequest) -> Result<TrackerResponse, TorrentError>
+{
     let url = tracker_request.build_url();
 
     if url.starts_with("udp://")
     {
-        discover_peers_udp(&tracker_request).await
+        discover_peers_udp(tracker_request).await
     }
     else
     {
-        discover_peers_http(&tracker_request).await
+        discover_peers_http(tracker_request).await
+    }
+}
+
+/// Scrapes the torrents from every tier of `announce_list`. The trackers of
+/// a tier are asked in order and the first one to answer is promoted, like
+/// for announces. Fails only when no tier answered.
+pub async fn scrape_tiers(
+    announce_list: &mut AnnounceList,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<(Url, Vec<ScrapeResponse>)>, TorrentError>
+{
+    let mut scrapes = Vec::new();
+    let mut last_error = MetadataError::FieldError("announce".to_string()).into();
+
+    for tier in 0..announce_list.tiers().len()
+    {
+        let trackers = announce_list.tiers()[tier].clone();
+
+        for (index, tracker_url) in trackers.iter().enumerate()
+        {
+            match scrape_many(tracker_url, info_hashes).await
+            {
+                Ok(responses) => {
+                    announce_list.promote(tier, index);
+                    scrapes.push((tracker_url.clone(), responses));
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Scrape of {} failed - Error: {}", tracker_url, e);
+                    last_error = e;
+                }
+            }
+        }
+    }
+
+    if scrapes.is_empty()
+    {
+        return Err(last_error);
+    }
+    Ok(scrapes)
+}
+
+/// Scrapes several torrents hosted by the same tracker in as few requests as
+/// the protocol allows.
+pub async fn scrape_many(
+    tracker_url: &Url,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeResponse>, TorrentError>
+{
+    if tracker_url.scheme() == "udp"
+    {
+        // One client, so every chunk goes out on the same connection id.
+        let client = UdpTrackerClient::new(tracker_url).await?;
+        let mut responses = Vec::new();
+
+        for chunk in info_hashes.chunks(UDP_SCRAPE_MAX_HASHES)
+        {
+            responses.extend(client.scrape(chunk).await?);
+        }
+        Ok(responses)
+    }
+    else { scrape_http(tracker_url, info_hashes).await }
+}
+
+/// Derives the scrape URL by replacing the `announce` prefix of the last
+/// path segment, which is the only form trackers are required to support.
+fn scrape_url(announce: &Url) -> Result<Url, MetadataError>
+{
+    let path = announce.path();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", path));
+
+    let suffix = last
+        .strip_prefix("announce")
+        .ok_or(MetadataError::ScrapeUnsupported(announce.to_string()))?;
+
+    let mut url = announce.clone();
+    url.set_path(&format!("{}/scrape{}", dir, suffix));
+    Ok(url)
+}
+
+async fn scrape_http(
+    tracker_url: &Url,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeResponse>, TorrentError>
+{
+    let url = scrape_url(tracker_url)?;
+    let separator = if url.query().is_some() { '&' } else { '?' };
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", encode_binary(info_hash)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let response = reqwest::get(format!("{}{}{}", url, separator, query)).await?.bytes().await?;
+    let value: Value = serde_bencode::from_bytes(&response)?;
+
+    if let Value::Dict(dict) = value
+    {
+        if let Some(failure_reason) = dict.get(&b"failure reason"[..])
+        {
+            let reason = decode_failure_reason(failure_reason)?;
+            return Err(TrackerError::Failure(reason).into());
+        }
+
+        extract_dict("files", &dict)?
+            .into_iter()
+            .map(|(info_hash, stats)| {
+                let info_hash: [u8; 20] = info_hash
+                    .try_into()
+                    .map_err(|_| MetadataError::FieldError("files".to_string()))?;
+
+                if let Value::Dict(stats) = stats
+                {
+                    Ok(ScrapeResponse::new(
+                        info_hash,
+                        extract_int("complete", &stats)?,
+                        extract_int("downloaded", &stats)?,
+                        extract_int("incomplete", &stats)?,
+                    )
+                    .with_name(extract_string("name", &stats).ok()))
+                }
+                else { Err(MetadataError::IncorrectFormatError.into()) }
+            })
+            .collect()
     }
+    else { Err(MetadataError::IncorrectFormatError.into()) }
 }
 
 async fn discover_peers_http(
@@ -40,13 +250,14 @@ async fn discover_peers_http(
         if let Some(failure_reason) = dict.get(&b"failure reason"[..])
         {
             let reason = decode_failure_reason(failure_reason)?;
-            return Err(MetadataError::FieldError(reason).into());
+            return Err(TrackerError::Failure(reason).into());
         }
         log_tracker_response(&dict);
         let interval = extract_int("interval", &dict)?;
-        let peers = extract_peers("peers", &dict)?;
+        let min_interval = extract_int("min interval", &dict).ok();
+        let peers = extract_peers(&dict).await?;
 
-        Ok(TrackerResponse::new(interval, peers))
+        Ok(TrackerResponse::new(interval, peers).with_min_interval(min_interval))
     }
     else { Err(MetadataError::IncorrectFormatError.into()) }
 }
@@ -55,118 +266,66 @@ async fn discover_peers_udp(
     tracker_request: &TrackerRequest,
 ) -> Result<TrackerResponse, TorrentError>
 {
-    let url = Url::parse(tracker_request.tracker_url().as_str())?;
-    let addr = format!(
-        "{}:{}",
-        url.host_str()
-            .ok_or(MetadataError::InvalidUrl(url.to_string()))?,
-        url.port_or_known_default()
-            .ok_or(MetadataError::InvalidUrl(url.to_string()))?
-    );
-
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    let mut buf = [0; 4096];
-
-    let connection_id: u64 = 0x41727101980;
-    let transaction_id: u32 = rand::random::<u32>();
-
-    let mut request = Vec::new();
-    request.extend(&connection_id.to_be_bytes());
-    request.extend(&0_u32.to_be_bytes());
-    request.extend(&transaction_id.to_be_bytes());
-
-    socket.send_to(&request, &addr).await?;
-
-    let n = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await??;
-
-    if n < 16
-    {
-        return Err(MetadataError::IncorrectFormatError.into());
-    }
+    UdpTrackerClient::new(tracker_request.tracker_url())
+        .await?
+        .announce(tracker_request)
+        .await
+}
 
-    let action = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-    let response_transaction_id = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+/// Collects the `peers` and IPv6 `peers6` lists; either may be missing as
+/// long as the other is present. `peers` is either a compact byte string or,
+/// for trackers using the dictionary model, a list of dictionaries.
+async fn extract_peers(dict: &HashMap<Vec<u8>, Value>) -> Result<Vec<Peer>, MetadataError>
+{
+    let peers = dict.get(&b"peers"[..]);
+    let peers6 = dict.get(&b"peers6"[..]);
 
-    if action != 0 || response_transaction_id != transaction_id
+    if peers.is_none() && peers6.is_none()
     {
-        return Err(MetadataError::IncorrectFormatError.into());
+        return Err(MetadataError::FieldError("peers".to_string()));
     }
 
-    let connection_id = u64::from_be_bytes([
-        buf[8], buf[9], buf[10], buf[11], buf[12], buf[13], buf[14], buf[15],
-    ]);
-
-    let mut request = Vec::new();
-    request.extend(&connection_id.to_be_bytes());
-    request.extend(&1_u32.to_be_bytes());
-    request.extend(&transaction_id.to_be_bytes());
-    request.extend(tracker_request.info_hash().iter());
-    request.extend(tracker_request.peer_id().as_bytes());
-    request.extend(&0_u64.to_be_bytes());
-    request.extend(&tracker_request.left().to_be_bytes());
-    request.extend(&0_u64.to_be_bytes());
-    request.extend(&2_u32.to_be_bytes());
-    request.extend(&0_u32.to_be_bytes());
-    request.extend(&0_u32.to_be_bytes());
-    request.extend(&u32::MAX.to_be_bytes());
-    request.extend(&tracker_request.port().to_be_bytes());
-
-    socket.send_to(&request, &addr).await?;
+    let mut found = Vec::new();
 
-    let n = timeout(Duration::from_secs(2), socket.recv(&mut buf)).await??;
-
-    if n < 20
+    match peers
     {
-        return Err(MetadataError::IncorrectFormatError.into());
+        Some(Value::Bytes(bytes)) => found.extend(Peer::from_compact(bytes)),
+        Some(Value::List(entries)) => {
+            for entry in entries
+            {
+                if let Value::Dict(entry) = entry
+                {
+                    if let Some(peer) = extract_dictionary_peer(entry).await
+                    {
+                        found.push(peer);
+                    }
+                }
+            }
+        }
+        Some(_) => return Err(MetadataError::FieldError("peers".to_string())),
+        None => {}
     }
-
-    let action = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-    let response_transaction_id = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
-
-    if action != 1 || response_transaction_id != transaction_id
+    if let Some(Value::Bytes(bytes)) = peers6
     {
-        return Err(MetadataError::IncorrectFormatError.into());
-    }
-
-    let interval = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]) as i64;
-    let mut peers = Vec::new();
-    let mut offset = 20;
-
-    while offset < n
-    {
-        let ip = IpAddr::V4(Ipv4Addr::new(
-            buf[offset],
-            buf[offset + 1],
-            buf[offset + 2],
-            buf[offset + 3],
-        ));
-        let port = u16::from_be_bytes([buf[offset + 4], buf[offset + 5]]);
-        peers.push(Peer::new(ip, port));
-        offset += 6;
+        found.extend(Peer::from_compact_v6(bytes));
     }
-    Ok(TrackerResponse::new(interval, peers))
+    Ok(found)
 }
 
-fn extract_peers(key: &str, dict: &HashMap<Vec<u8>, Value>) -> Result<Vec<Peer>, MetadataError>
+/// Reads one entry of the dictionary model, resolving `ip` when the tracker
+/// sent a hostname. Malformed or unresolvable entries are skipped.
+async fn extract_dictionary_peer(entry: &HashMap<Vec<u8>, Value>) -> Option<Peer>
 {
-    let peers_bytes = dict
-        .get(key.as_bytes())
-        .and_then(|v| match v {
-            Value::Bytes(b) => Some(b),
-            _ => None,
-        })
-        .ok_or(MetadataError::FieldError(key.to_string()))?;
-
-    let peers = peers_bytes
-        .chunks(6)
-        .map(|chunk| {
-            let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
-            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-            Peer::new(ip, port)
-        })
-        .collect();
-
-    Ok(peers)
+    let host = extract_string("ip", entry).ok()?;
+    let port = u16::try_from(extract_int("port", entry).ok()?).ok()?;
+    let peer_id = extract_bytes("peer id", entry).ok();
+
+    let ip = match host.parse::<IpAddr>()
+    {
+        Ok(ip) => ip,
+        Err(_) => lookup_host((host.as_str(), port)).await.ok()?.next()?.ip(),
+    };
+    Some(Peer::new(ip, port).with_peer_id(peer_id))
 }
 
 fn log_tracker_response(dict: &HashMap<Vec<u8>, Value>)
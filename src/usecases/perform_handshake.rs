@@ -1,7 +1,8 @@
-use crate::entities::handshake::Handshake;
+use crate::entities::handshake::{Handshake, HANDSHAKE_LEN};
 use crate::entities::peer::Peer;
 use crate::entities::torrent::Torrent;
 use crate::utils::errors::{HandshakeError, TorrentError};
+use crate::utils::local_address::local_addresses;
 
 use anyhow::Result;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -18,31 +19,17 @@ pub async fn perform_handshake(
 
     for peer in peers
     {
-        format!("{}:{}", peer.ip(), peer.port());
         match timeout(Duration::from_secs(5), try_handshake(&handshake, peer)).await
         {
             Ok(Ok(())) => {
-                println!(
-                    "Handshake successfully performed with peer: {}:{}",
-                    peer.ip(),
-                    peer.port()
-                );
+                println!("Handshake successfully performed with peer: {}", peer);
                 connected_peers.push(peer.clone());
             }
             Ok(Err(e)) => {
-                eprintln!(
-                    "Handshake failed with peer {}:{} - Error: {}",
-                    peer.ip(),
-                    peer.port(),
-                    e
-                );
+                eprintln!("Handshake failed with peer {} - Error: {}", peer, e);
             }
             Err(_) => {
-                eprintln!(
-                    "Handshake timed out with peer {}:{}",
-                    peer.ip(),
-                    peer.port()
-                );
+                eprintln!("Handshake timed out with peer {}", peer);
             }
         }
     }
@@ -51,25 +38,47 @@ pub async fn perform_handshake(
 
 async fn try_handshake(handshake: &Handshake, peer: &Peer) -> Result<(), HandshakeError>
 {
-    let addr = format!("{}:{}", peer.ip(), peer.port());
-    let mut stream = TcpStream::connect(&addr)
+    let mut stream = connect_to_peer(peer).await?;
+    exchange_handshake(&mut stream, handshake, &peer.to_string()).await?;
+    Ok(())
+}
+
+/// Opens a TCP connection to a peer over whichever family its address uses,
+/// failing fast when this host has no route for that family.
+pub async fn connect_to_peer(peer: &Peer) -> Result<TcpStream, HandshakeError>
+{
+    if !local_addresses().supports(peer.ip())
+    {
+        return Err(HandshakeError::ConnectionError(peer.to_string()));
+    }
+
+    TcpStream::connect(peer.addr())
         .await
-        .map_err(|_| HandshakeError::ConnectionError(addr.clone()))?;
+        .map_err(|_| HandshakeError::ConnectionError(peer.to_string()))
+}
+
+/// Sends our handshake over an open connection and validates the peer's
+/// reply, returning the raw response so callers can inspect its reserved bits.
+pub async fn exchange_handshake(
+    stream: &mut TcpStream,
+    handshake: &Handshake,
+    addr: &str,
+) -> Result<Vec<u8>, HandshakeError>
+{
     stream
         .write_all(&handshake.as_bytes())
         .await
-        .map_err(|_| HandshakeError::HandshakeSendError(addr.clone()))?;
+        .map_err(|_| HandshakeError::HandshakeSendError(addr.to_string()))?;
 
-    let mut response = vec![0; 68];
+    let mut response = vec![0; HANDSHAKE_LEN];
     stream
         .read_exact(&mut response)
         .await
-        .map_err(|_| HandshakeError::HandshakeReceiveError(addr.clone()))?;
+        .map_err(|_| HandshakeError::HandshakeReceiveError(addr.to_string()))?;
 
-    if &response[1..20] != handshake.protocol_str().as_bytes()
-        || &response[28..48] != handshake.info_hash()
+    if !handshake.is_valid_response(&response)
     {
-        return Err(HandshakeError::InvalidHandshakeResponse(addr));
+        return Err(HandshakeError::InvalidHandshakeResponse(addr.to_string()));
     }
-    Ok(())
+    Ok(response)
 }
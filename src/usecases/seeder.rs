@@ -0,0 +1,285 @@
+use crate::entities::choker::Choker;
+use crate::entities::extension::ExtensionRegistry;
+use crate::entities::handshake::{Handshake, HANDSHAKE_LEN};
+use crate::entities::message::Message;
+use crate::entities::peer::Peer;
+use crate::entities::storage::Storage;
+use crate::entities::torrent::Torrent;
+use crate::entities::transfer_stats::TransferStats;
+use crate::usecases::choke_manager::ChokeManager;
+use crate::usecases::dht::Dht;
+use crate::usecases::peer_session::PeerSession;
+use crate::utils::errors::{HandshakeError, TorrentError};
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+/// Requests for more than this are refused; clients ask for 16 KiB blocks.
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A torrent we can serve, with the pieces its storage holds.
+#[derive(Clone)]
+pub struct SeededTorrent
+{
+    torrent: Torrent,
+    storage: Arc<dyn Storage>,
+    pieces: Arc<Vec<bool>>,
+    stats: Arc<TransferStats>,
+    choke: ChokeManager,
+}
+
+impl SeededTorrent
+{
+    pub fn new(
+        torrent: Torrent,
+        storage: Arc<dyn Storage>,
+        pieces: Vec<bool>,
+        stats: Arc<TransferStats>,
+    ) -> Self
+    {
+        Self
+        {
+            torrent,
+            storage,
+            pieces: Arc::new(pieces),
+            stats,
+            choke: ChokeManager::new(),
+        }
+    }
+}
+
+/// Accepts incoming peer connections and serves the torrents added to it.
+pub struct Seeder
+{
+    listener: TcpListener,
+    torrents: Arc<RwLock<HashMap<[u8; 20], SeededTorrent>>>,
+    rechokers: Mutex<Vec<JoinHandle<()>>>,
+    dht: Option<Arc<Dht>>,
+}
+
+impl Seeder
+{
+    pub async fn bind(port: u16) -> Result<Self, TorrentError>
+    {
+        Ok(Self
+        {
+            listener: bind_listener(port).await?,
+            torrents: Arc::new(RwLock::new(HashMap::new())),
+            rechokers: Mutex::new(Vec::new()),
+            dht: None,
+        })
+    }
+
+    /// Exchanges DHT ports with the peers that run a DHT node too, adding
+    /// theirs to `dht`.
+    pub fn with_dht(mut self, dht: Option<Arc<Dht>>) -> Self
+    {
+        self.dht = dht;
+        self
+    }
+
+    pub fn local_port(&self) -> Result<u16, TorrentError>
+    {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Starts serving `seeded`, with `choker` deciding which of its peers
+    /// are unchoked.
+    pub fn add(&self, seeded: SeededTorrent, choker: Choker)
+    {
+        let info_hash = *seeded.torrent.info_hash();
+        let choke = seeded.choke.clone();
+        let stats = Arc::clone(&seeded.stats);
+        let rechoker = tokio::spawn(async move { choke.run(choker, stats).await });
+
+        self.rechokers.lock().unwrap().push(rechoker);
+        self.torrents.write().unwrap().insert(info_hash, seeded);
+    }
+
+    /// Accepts connections until the listener fails, serving each peer on
+    /// its own task.
+    pub async fn run(&self) -> Result<(), TorrentError>
+    {
+        let listen_port = self.local_port()?;
+
+        loop
+        {
+            let (stream, addr) = self.listener.accept().await?;
+            let torrents = Arc::clone(&self.torrents);
+            let dht = self.dht.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = serve_peer(stream, addr, listen_port, torrents, dht).await
+                {
+                    eprintln!("Inbound peer {} disconnected - Error: {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Seeder
+{
+    fn drop(&mut self)
+    {
+        for rechoker in self.rechokers.lock().unwrap().iter()
+        {
+            rechoker.abort();
+        }
+    }
+}
+
+/// Answers the peer's handshake if it asks for a torrent we hold, then
+/// serves its requests while the choker lets it.
+async fn serve_peer(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    listen_port: u16,
+    torrents: Arc<RwLock<HashMap<[u8; 20], SeededTorrent>>>,
+    dht: Option<Arc<Dht>>,
+) -> Result<(), TorrentError>
+{
+    let peer = Peer::new(addr.ip().to_canonical(), addr.port());
+    let dht_port = dht.as_ref().and_then(|dht| dht.local_addr().ok()).map(|addr| addr.port());
+    let (seeded, extensions, dht_port) =
+        accept_handshake(&mut stream, &peer, dht_port, |info_hash| {
+            torrents.read().unwrap().get(info_hash).cloned()
+        })
+        .await?;
+    let mut session = PeerSession::from_stream(
+        stream,
+        peer.clone(),
+        &seeded.pieces,
+        extensions,
+        dht_port,
+        ExtensionRegistry::new().with_listen_port(listen_port),
+    )
+    .await?;
+    let mut choke = seeded.choke.register();
+
+    loop
+    {
+        tokio::select! {
+            message = session.receive() => {
+                let message = message?;
+                choke.set_interested(session.is_peer_interested());
+
+                match message
+                {
+                    Message::Request { index, begin, length } => {
+                        let has_piece =
+                            seeded.pieces.get(index as usize).copied().unwrap_or(false);
+                        let sent = serve_request(
+                            &mut session,
+                            seeded.storage.as_ref(),
+                            &seeded.torrent,
+                            has_piece,
+                            index,
+                            begin,
+                            length,
+                        )
+                        .await?;
+                        seeded.stats.add_uploaded(sent as i64);
+                        choke.add_uploaded(sent);
+                    }
+                    Message::HashRequest(request) => {
+                        session.send(&seeded.torrent.answer_hash_request(&request)).await?;
+                    }
+                    Message::Port { listen_port } => {
+                        if let Some(dht) = &dht
+                        {
+                            dht.add_node(SocketAddr::new(*peer.ip(), listen_port));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            choking = choke.changed() => session.set_choking(choking).await?,
+        }
+    }
+}
+
+/// Listens on `port` over IPv6 when available, which on most systems also
+/// accepts IPv4 connections, and over IPv4 otherwise.
+pub async fn bind_listener(port: u16) -> Result<TcpListener, TorrentError>
+{
+    match TcpListener::bind(("::", port)).await
+    {
+        Ok(listener) => Ok(listener),
+        Err(_) => Ok(TcpListener::bind(("0.0.0.0", port)).await?),
+    }
+}
+
+/// Reads the handshake an inbound peer opens with and answers it if `lookup`
+/// knows the torrent asked for, advertising our DHT node with a `dht_port`.
+/// Returns what `lookup` found, whether both sides negotiated the extension
+/// protocol, and `dht_port` if the peer runs a DHT node as well.
+pub async fn accept_handshake<T>(
+    stream: &mut TcpStream,
+    peer: &Peer,
+    dht_port: Option<u16>,
+    lookup: impl FnOnce(&[u8; 20]) -> Option<T>,
+) -> Result<(T, bool, Option<u16>), TorrentError>
+{
+    let mut request = vec![0; HANDSHAKE_LEN];
+    timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut request)).await??;
+
+    let info_hash = Handshake::from_bytes(&request)
+        .map(|handshake| *handshake.info_hash())
+        .ok_or_else(|| HandshakeError::InvalidHandshakeResponse(peer.to_string()))?;
+    let found = lookup(&info_hash)
+        .ok_or_else(|| HandshakeError::InvalidHandshakeResponse(peer.to_string()))?;
+
+    let mut handshake = Handshake::new(info_hash).with_extension_protocol();
+
+    if dht_port.is_some()
+    {
+        handshake = handshake.with_dht();
+    }
+    stream.write_all(&handshake.as_bytes()).await?;
+
+    let extensions = handshake.negotiates_extension_protocol(&request);
+    Ok((found, extensions, dht_port.filter(|_| handshake.negotiates_dht(&request))))
+}
+
+/// Sends the requested block if we are not choking the peer and hold the
+/// piece; other requests are dropped, as the protocol allows. Returns the
+/// number of bytes sent.
+pub async fn serve_request(
+    session: &mut PeerSession,
+    storage: &dyn Storage,
+    torrent: &Torrent,
+    has_piece: bool,
+    index: u32,
+    begin: u32,
+    length: u32,
+) -> Result<usize, TorrentError>
+{
+    let piece_index = index as usize;
+
+    if session.is_choking_peer()
+        || !has_piece
+        || length > MAX_REQUEST_LENGTH
+        || begin as usize + length as usize > torrent.info().piece_size(piece_index)
+    {
+        return Ok(0);
+    }
+
+    match storage.read_block(piece_index, begin as usize, length as usize).await?
+    {
+        Some(block) => {
+            let sent = block.len();
+            let block = block.into();
+            session.send(&Message::Piece { index, begin, block }).await?;
+            Ok(sent)
+        }
+        None => Ok(0),
+    }
+}
@@ -0,0 +1,267 @@
+use crate::entities::peer::{Peer, TrackerEvent, TrackerRequest, TrackerResponse};
+use crate::entities::scrape::ScrapeResponse;
+use crate::utils::errors::{MetadataError, TorrentError, TrackerError};
+use crate::utils::local_address::local_addresses;
+
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use url::Url;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+const BASE_TIMEOUT_SECS: u64 = 15;
+/// BEP 15 allows up to 8 retransmissions (over an hour); we stop earlier so a
+/// dead tracker does not hold up the other tiers.
+const MAX_RETRANSMISSIONS: u32 = 2;
+/// Bounds one announce or scrape, connecting included, whatever the
+/// retransmissions add up to.
+const MAX_TRACKER_TIME: Duration = Duration::from_secs(60);
+/// Large enough for any datagram, however many peers the tracker returns.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+const OPTION_URL_DATA: u8 = 2;
+
+/// BEP 15 caps a UDP scrape at 74 info hashes per request.
+pub const UDP_SCRAPE_MAX_HASHES: usize = 74;
+
+pub struct UdpTrackerClient
+{
+    url: Url,
+    addr: SocketAddr,
+    socket: UdpSocket,
+    /// Shared by every request sent through this client while it remains
+    /// valid, and when it was obtained.
+    connection_id: Mutex<Option<(u64, Instant)>>,
+}
+
+impl UdpTrackerClient
+{
+    pub async fn new(url: &Url) -> Result<Self, TorrentError>
+    {
+        let host = url
+            .host_str()
+            .ok_or(MetadataError::InvalidUrl(url.to_string()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = url
+            .port_or_known_default()
+            .ok_or(MetadataError::InvalidUrl(url.to_string()))?;
+
+        let local_addresses = local_addresses();
+        let mut candidates: Vec<SocketAddr> = lookup_host((host, port))
+            .await?
+            .filter(|addr| local_addresses.supports(&addr.ip()))
+            .collect();
+        candidates.sort_by_key(|addr| addr.is_ipv6());
+
+        let addr = *candidates
+            .first()
+            .ok_or(TrackerError::UnresolvedAddress(url.to_string()))?;
+        let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self
+        {
+            url: url.clone(),
+            addr,
+            socket,
+            connection_id: Mutex::new(None),
+        })
+    }
+
+    pub async fn announce(
+        &self,
+        tracker_request: &TrackerRequest,
+    ) -> Result<TrackerResponse, TorrentError>
+    {
+        let mut body = Vec::new();
+        body.extend(tracker_request.info_hash());
+        body.extend(tracker_request.peer_id().as_bytes());
+        body.extend(&tracker_request.downloaded().to_be_bytes());
+        body.extend(&tracker_request.left().to_be_bytes());
+        body.extend(&tracker_request.uploaded().to_be_bytes());
+        body.extend(&TrackerEvent::udp_code(*tracker_request.event()).to_be_bytes());
+        body.extend(&0_u32.to_be_bytes());
+        body.extend(&tracker_request.key().to_be_bytes());
+        body.extend(&tracker_request.num_want().to_be_bytes());
+        body.extend(&tracker_request.port().to_be_bytes());
+        body.extend(url_data_options(&self.url));
+
+        let response = self.bounded_request(ACTION_ANNOUNCE, &body).await?;
+
+        if response.len() < 12
+        {
+            return Err(TrackerError::InvalidResponse(self.url.to_string()).into());
+        }
+
+        let interval = u32::from_be_bytes([response[0], response[1], response[2], response[3]]);
+        let peers = if self.addr.is_ipv4()
+        {
+            Peer::from_compact(&response[12..])
+        }
+        else { Peer::from_compact_v6(&response[12..]) };
+        Ok(TrackerResponse::new(interval as i64, peers))
+    }
+
+    pub async fn scrape(
+        &self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeResponse>, TorrentError>
+    {
+        let body: Vec<u8> = info_hashes.iter().flatten().copied().collect();
+        let response = self.bounded_request(ACTION_SCRAPE, &body).await?;
+
+        if response.len() < 12 * info_hashes.len()
+        {
+            return Err(TrackerError::InvalidResponse(self.url.to_string()).into());
+        }
+
+        Ok(info_hashes
+            .iter()
+            .zip(response.chunks_exact(12))
+            .map(|(info_hash, chunk)| {
+                let seeders = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                let completed = u32::from_be_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+                let leechers = u32::from_be_bytes([chunk[8], chunk[9], chunk[10], chunk[11]]);
+                ScrapeResponse::new(*info_hash, seeders as i64, completed as i64, leechers as i64)
+            })
+            .collect())
+    }
+
+    async fn connection_id(&self) -> Result<u64, TorrentError>
+    {
+        if let Some((connection_id, obtained)) = self.cached_connection_id()
+        {
+            if obtained.elapsed() < CONNECTION_ID_LIFETIME
+            {
+                return Ok(connection_id);
+            }
+        }
+
+        let response = Box::pin(self.request(ACTION_CONNECT, &[])).await?;
+        let connection_id = response
+            .get(..8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or(TrackerError::InvalidResponse(self.url.to_string()))?;
+
+        *self.connection_id.lock().unwrap() = Some((connection_id, Instant::now()));
+        Ok(connection_id)
+    }
+
+    fn cached_connection_id(&self) -> Option<(u64, Instant)>
+    {
+        *self.connection_id.lock().unwrap()
+    }
+
+    fn forget_connection_id(&self)
+    {
+        *self.connection_id.lock().unwrap() = None;
+    }
+
+    /// `request` given up on after `MAX_TRACKER_TIME`.
+    async fn bounded_request(&self, action: u32, body: &[u8]) -> Result<Vec<u8>, TorrentError>
+    {
+        timeout(MAX_TRACKER_TIME, self.request(action, body))
+            .await
+            .map_err(|_| TrackerError::Timeout(self.url.to_string()))?
+    }
+
+    /// Sends a request and returns the payload following the action and
+    /// transaction id, retransmitting after 15 * 2^n seconds as BEP 15 asks.
+    async fn request(&self, action: u32, body: &[u8]) -> Result<Vec<u8>, TorrentError>
+    {
+        for attempt in 0..=MAX_RETRANSMISSIONS
+        {
+            let connection_id = if action == ACTION_CONNECT
+            {
+                PROTOCOL_ID
+            }
+            else { self.connection_id().await? };
+            let transaction_id: u32 = rand::random();
+
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.extend(&connection_id.to_be_bytes());
+            packet.extend(&action.to_be_bytes());
+            packet.extend(&transaction_id.to_be_bytes());
+            packet.extend(body);
+            self.socket.send(&packet).await?;
+
+            let deadline = Instant::now() + Duration::from_secs(BASE_TIMEOUT_SECS << attempt);
+
+            if let Ok(response) = timeout_at(deadline, self.receive(transaction_id)).await
+            {
+                let (response_action, payload) = response?;
+
+                if response_action == ACTION_ERROR
+                {
+                    self.forget_connection_id();
+                    let message = String::from_utf8_lossy(&payload).into_owned();
+                    return Err(TrackerError::Failure(message).into());
+                }
+                if response_action != action
+                {
+                    return Err(TrackerError::InvalidResponse(self.url.to_string()).into());
+                }
+                return Ok(payload);
+            }
+        }
+        Err(TrackerError::Timeout(self.url.to_string()).into())
+    }
+
+    /// Waits for the datagram answering `transaction_id`, dropping stale or
+    /// unrelated ones.
+    async fn receive(&self, transaction_id: u32) -> Result<(u32, Vec<u8>), TorrentError>
+    {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+        loop
+        {
+            let n = self.socket.recv(&mut buf).await?;
+
+            if n < 8
+            {
+                continue;
+            }
+
+            let action = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let response_transaction_id = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+
+            if response_transaction_id == transaction_id
+            {
+                return Ok((action, buf[8..n].to_vec()));
+            }
+        }
+    }
+}
+
+/// BEP 41 URLData options carrying the path and query of the tracker URL,
+/// split into chunks of at most 255 bytes.
+fn url_data_options(url: &Url) -> Vec<u8>
+{
+    let mut url_data = url.path().to_string();
+
+    if let Some(query) = url.query()
+    {
+        url_data.push('?');
+        url_data.push_str(query);
+    }
+
+    let mut options = Vec::new();
+
+    for chunk in url_data.as_bytes().chunks(u8::MAX as usize)
+    {
+        options.push(OPTION_URL_DATA);
+        options.push(chunk.len() as u8);
+        options.extend_from_slice(chunk);
+    }
+    options
+}
@@ -0,0 +1,77 @@
+use crate::entities::storage::Storage;
+use crate::entities::torrent::Torrent;
+use crate::utils::errors::TorrentError;
+
+use anyhow::Result;
+use std::sync::Arc;
+use std::thread::available_parallelism;
+use tokio::task::JoinSet;
+
+/// Hashes every stored piece against the torrent and returns which ones are
+/// valid. Pieces are read in order while up to one hashing job per core runs
+/// in the background; missing or short files leave their pieces invalid.
+/// `progress` is called with the number of pieces checked so far and the
+/// total.
+pub async fn verify_torrent<F>(
+    torrent: &Torrent,
+    storage: &dyn Storage,
+    mut progress: F,
+) -> Result<Vec<bool>, TorrentError>
+where
+    F: FnMut(usize, usize),
+{
+    let num_pieces = torrent.info().num_pieces();
+    let shared = Arc::new(torrent.clone());
+    let workers = available_parallelism().map_or(1, |workers| workers.get());
+    let mut valid_pieces = vec![false; num_pieces];
+    let mut jobs = JoinSet::new();
+    let mut checked = 0;
+
+    for piece_index in 0..num_pieces
+    {
+        if jobs.len() >= workers
+        {
+            if let Some(job) = jobs.join_next().await
+            {
+                let (index, valid) = job.map_err(std::io::Error::other)?;
+                valid_pieces[index] = valid;
+                checked += 1;
+                progress(checked, num_pieces);
+            }
+        }
+
+        let length = torrent.info().piece_size(piece_index);
+
+        match storage.read_block(piece_index, 0, length).await?
+        {
+            Some(data) => {
+                let torrent = Arc::clone(&shared);
+                jobs.spawn_blocking(move || {
+                    (piece_index, torrent.verify_piece(piece_index, &data))
+                });
+            }
+            None => {
+                checked += 1;
+                progress(checked, num_pieces);
+            }
+        }
+    }
+
+    while let Some(job) = jobs.join_next().await
+    {
+        let (index, valid) = job.map_err(std::io::Error::other)?;
+        valid_pieces[index] = valid;
+        checked += 1;
+        progress(checked, num_pieces);
+    }
+    Ok(valid_pieces)
+}
+
+/// Progress callback for `verify_torrent` that prints each whole percent.
+pub fn print_verify_progress(checked: usize, total: usize)
+{
+    if checked * 100 / total != (checked - 1) * 100 / total
+    {
+        println!("Verified {}/{} pieces ({}%)", checked, total, checked * 100 / total);
+    }
+}
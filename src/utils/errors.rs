@@ -1,7 +1,7 @@
 use reqwest::Error as ReqwestError;
 use serde_bencode::Error as BencodeError;
 use std::io::Error as IoError;
-use std::net::AddrParseError;
+use std::net::{AddrParseError, SocketAddr};
 use std::path::PathBuf;
 use thiserror::Error;
 use tokio::time::error::Elapsed;
@@ -19,6 +19,9 @@ pub enum FileError
     #[error("Failed to read file: {0}")]
     FileReadError(PathBuf),
 
+    #[error("Torrent has no file {0}")]
+    NotInTorrent(String),
+
     #[error(transparent)]
     IoError(#[from] IoError),
 }
@@ -38,6 +41,9 @@ pub enum MetadataError
     #[error("Invalid announce URL: {0}")]
     InvalidUrl(String),
 
+    #[error("Tracker does not support scrape: {0}")]
+    ScrapeUnsupported(String),
+
     #[error("Invalid value: {0} must be positive")]
     InvalidPositiveValue(i64),
 
@@ -50,6 +56,18 @@ pub enum MetadataError
     #[error("'pieces' field cannot be empty")]
     EmptyPiecesField,
 
+    #[error("Invalid magnet link: {0}")]
+    InvalidMagnetLink(String),
+
+    #[error("Metadata received from peers does not match the info hash")]
+    InfoHashMismatch,
+
+    #[error("Could not fetch metadata from any peer")]
+    MetadataUnavailable,
+
+    #[error("Peers do not send the piece layers of v2-only torrents; use the .torrent file")]
+    PieceLayersUnavailable,
+
     #[error(transparent)]
     BencodeError(#[from] BencodeError),
 
@@ -75,6 +93,12 @@ pub enum HandshakeError
     #[error("Handshake timed out with peer at {0}")]
     HandshakeTimeout(String),
 
+    #[error("Peer at {0} does not support the extension protocol")]
+    ExtensionProtocolUnsupported(String),
+
+    #[error("No peer connected for {0} seconds")]
+    IdleTimeout(u64),
+
     #[error(transparent)]
     AddrParseError(#[from] AddrParseError),
 
@@ -82,6 +106,66 @@ pub enum HandshakeError
     Elapsed(#[from] Elapsed),
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MessageError
+{
+    #[error("Empty message frame")]
+    Empty,
+
+    #[error("Unknown message id {0}")]
+    UnknownId(u8),
+
+    #[error("Message with id {0} is truncated")]
+    Truncated(u8),
+
+    #[error("Message with id {0} has trailing bytes")]
+    TrailingBytes(u8),
+
+    #[error("Frame of {0} bytes exceeds the {1} byte limit")]
+    Oversized(usize, usize),
+
+    #[error("Bitfield of {0} bytes does not fit a torrent of {1} pieces")]
+    InvalidBitfieldLength(usize, usize),
+
+    #[error("Bitfield sets bits past the last piece")]
+    SpareBitsSet,
+
+    #[error("Piece index {0} is out of range")]
+    InvalidPieceIndex(u32),
+
+    #[error("Block of {2} bytes at offset {1} of piece {0} matches no block of the piece")]
+    InvalidBlock(u32, u32, usize),
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerError
+{
+    #[error("Tracker returned an error: {0}")]
+    Failure(String),
+
+    #[error("Tracker at {0} did not respond")]
+    Timeout(String),
+
+    #[error("Tracker at {0} sent a malformed response")]
+    InvalidResponse(String),
+
+    #[error("Could not resolve tracker address: {0}")]
+    UnresolvedAddress(String),
+}
+
+#[derive(Debug, Error)]
+pub enum DhtError
+{
+    #[error("DHT node at {0} did not respond")]
+    Timeout(SocketAddr),
+
+    #[error("DHT node at {0} returned error {1}: {2}")]
+    RemoteError(SocketAddr, i64, String),
+
+    #[error("No DHT node answered the bootstrap")]
+    NoNodes,
+}
+
 #[derive(Debug, Error)]
 pub enum TorrentError
 {
@@ -94,6 +178,15 @@ pub enum TorrentError
     #[error(transparent)]
     HandshakeError(#[from] HandshakeError),
 
+    #[error(transparent)]
+    TrackerError(#[from] TrackerError),
+
+    #[error(transparent)]
+    MessageError(#[from] MessageError),
+
+    #[error(transparent)]
+    DhtError(#[from] DhtError),
+
     #[error(transparent)]
     ReqwestError(#[from] ReqwestError),
 
@@ -109,3 +202,23 @@ pub enum TorrentError
     #[error(transparent)]
     Elapsed(#[from] Elapsed),
 }
+
+impl TorrentError
+{
+    pub fn exit_code(&self) -> u8
+    {
+        match self
+        {
+            TorrentError::FileError(_) => 2,
+            TorrentError::MetadataError(_)
+            | TorrentError::BencodeError(_)
+            | TorrentError::ParseError(_) => 3,
+            TorrentError::ReqwestError(_)
+            | TorrentError::TrackerError(_)
+            | TorrentError::DhtError(_) => 4,
+            TorrentError::HandshakeError(_) | TorrentError::MessageError(_) => 5,
+            TorrentError::Elapsed(_) => 6,
+            TorrentError::IoError(_) => 7,
+        }
+    }
+}
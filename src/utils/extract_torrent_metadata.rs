@@ -1,9 +1,11 @@
+use crate::entities::announce_list::AnnounceList;
 use crate::entities::torrent::FileInfo;
 use crate::utils::errors::MetadataError;
 
 use anyhow::Result;
 use rand::distributions::{Alphanumeric, DistString};
 use rand::thread_rng;
+use reqwest::Url;
 use serde_bencode::value::Value;
 use std::collections::HashMap;
 
@@ -75,14 +77,153 @@ pub fn extract_files(info: &BencodeDict) -> Result<Vec<FileInfo>, MetadataError>
                         else { None }
                     })
                     .collect();
-                Ok(FileInfo::new(length, path))
+                // BEP 47 marks padding files with a `p` in their attributes.
+                let padding = extract_string("attr", &file_info)
+                    .is_ok_and(|attr| attr.contains('p'));
+                Ok(FileInfo::new(length, path).with_padding(padding))
             }
             else { Err(MetadataError::IncorrectFormatError) }
         })
         .collect()
 }
 
+/// Flattens the BEP 52 `file tree` into its files, in path order. Every
+/// file is a dict under an empty key holding its `length` and, unless the
+/// file is empty, its `pieces root`.
+pub fn extract_file_tree(info: &BencodeDict) -> Result<Vec<FileInfo>, MetadataError>
+{
+    let mut files = Vec::new();
+    walk_file_tree(&extract_dict("file tree", info)?, &mut Vec::new(), &mut files)?;
+    Ok(files)
+}
+
+fn walk_file_tree(
+    node: &BencodeDict,
+    path: &mut Vec<String>,
+    files: &mut Vec<FileInfo>,
+) -> Result<(), MetadataError>
+{
+    let error = || MetadataError::FieldError("file tree".to_string());
+    // Bencoded dicts are sorted by key, which the map does not keep.
+    let mut names: Vec<&Vec<u8>> = node.keys().collect();
+    names.sort();
+
+    for name in names
+    {
+        let Some(Value::Dict(child)) = node.get(name) else { return Err(error()) };
+
+        if name.is_empty()
+        {
+            let length = extract_int("length", child)?;
+            let pieces_root = match extract_bytes("pieces root", child)
+            {
+                Ok(root) => Some(root.try_into().map_err(|_| error())?),
+                Err(_) => None,
+            };
+
+            if length < 0 || path.is_empty() || (length > 0 && pieces_root.is_none())
+            {
+                return Err(error());
+            }
+            files.push(FileInfo::new(length, path.clone()).with_pieces_root(pieces_root));
+        }
+        else
+        {
+            path.push(String::from_utf8(name.clone()).map_err(|_| error())?);
+            walk_file_tree(child, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+pub fn extract_announce_list(dict: &BencodeDict) -> Result<AnnounceList, MetadataError>
+{
+    let tiers = extract_list("announce-list", dict)?
+        .into_iter()
+        .map(|tier| match tier {
+            Value::List(trackers) => trackers
+                .into_iter()
+                .filter_map(|tracker| match tracker {
+                    Value::Bytes(b) => Url::parse(&String::from_utf8(b).ok()?).ok(),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        })
+        .collect();
+
+    Ok(AnnounceList::new(tiers).shuffled())
+}
+
+/// BEP 19 allows `url-list` to be a single URL as well as a list of them.
+/// URLs that do not parse are skipped.
+pub fn extract_web_seeds(dict: &BencodeDict) -> Vec<Url>
+{
+    let urls = match dict.get(b"url-list".as_slice())
+    {
+        Some(Value::Bytes(url)) => vec![url.clone()],
+        Some(Value::List(urls)) => urls
+            .iter()
+            .filter_map(|url| match url {
+                Value::Bytes(b) => Some(b.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    urls.into_iter()
+        .filter_map(|url| Url::parse(&String::from_utf8(url).ok()?).ok())
+        .collect()
+}
+
 pub fn generate_peer_id() -> String
 {
     Alphanumeric.sample_string(&mut thread_rng(), 20)
 }
+
+/// Returns the length of the bencoded value at the start of `bytes`,
+/// which lets callers split a bencoded header from trailing raw data.
+/// The bytes come from peers, so nesting is tracked with a counter rather
+/// than recursion and lengths are checked for overflow.
+pub fn bencode_value_len(bytes: &[u8]) -> Option<usize>
+{
+    let mut offset = 0;
+    // Lists and dicts opened and not yet closed.
+    let mut depth = 0usize;
+
+    loop
+    {
+        match bytes.get(offset)?
+        {
+            b'i' => offset += bytes[offset..].iter().position(|b| *b == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                offset += 1;
+                continue;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                offset += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = offset + bytes[offset..].iter().position(|b| *b == b':')?;
+                let length: usize = std::str::from_utf8(&bytes[offset..colon]).ok()?.parse().ok()?;
+                let end = colon.checked_add(1)?.checked_add(length)?;
+
+                if end > bytes.len()
+                {
+                    return None;
+                }
+                offset = end;
+            }
+            _ => return None,
+        }
+
+        if depth == 0
+        {
+            return Some(offset);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::LazyLock;
+
+/// Well-known public resolvers used only to ask the OS which source address
+/// it would pick for each family; no packet is ever sent to them.
+const IPV4_PROBE: &str = "8.8.8.8:53";
+const IPV6_PROBE: &str = "[2001:4860:4860::8888]:53";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalAddresses
+{
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl LocalAddresses
+{
+    /// Without a default route for either family (e.g. an isolated LAN) we
+    /// cannot tell, so every address is worth trying.
+    pub fn supports(&self, ip: &IpAddr) -> bool
+    {
+        if self.ipv4.is_none() && self.ipv6.is_none()
+        {
+            return true;
+        }
+
+        match ip
+        {
+            IpAddr::V4(ip) => ip.is_loopback() || self.ipv4.is_some(),
+            IpAddr::V6(ip) => ip.is_loopback() || self.ipv6.is_some(),
+        }
+    }
+
+    /// Address to report in the `ipv4=` announce parameter, if it is public.
+    pub fn public_ipv4(&self) -> Option<Ipv4Addr>
+    {
+        self.ipv4
+            .filter(|ip| !ip.is_private() && !ip.is_loopback() && !ip.is_link_local())
+    }
+
+    /// Address to report in the `ipv6=` announce parameter, if it is global.
+    pub fn public_ipv6(&self) -> Option<Ipv6Addr>
+    {
+        self.ipv6.filter(|ip| {
+            !ip.is_loopback()
+                && !ip.is_unicast_link_local()
+                && !ip.is_unique_local()
+                && !ip.is_unspecified()
+        })
+    }
+}
+
+/// The probes use blocking sockets, so they run once per process rather
+/// than on every connection.
+static LOCAL_ADDRESSES: LazyLock<LocalAddresses> = LazyLock::new(probe_local_addresses);
+
+/// Finds the addresses this host would use to reach the internet over IPv4
+/// and IPv6, which tells us which peer families we can connect to.
+pub fn local_addresses() -> LocalAddresses
+{
+    *LOCAL_ADDRESSES
+}
+
+fn probe_local_addresses() -> LocalAddresses
+{
+    LocalAddresses
+    {
+        ipv4: match probe("0.0.0.0:0", IPV4_PROBE)
+        {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => None,
+        },
+        ipv6: match probe("[::]:0", IPV6_PROBE)
+        {
+            Some(IpAddr::V6(ip)) => Some(ip),
+            _ => None,
+        },
+    }
+}
+
+fn probe(bind: &str, target: &str) -> Option<IpAddr>
+{
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(target).ok()?;
+    socket.local_addr().ok().map(|addr: SocketAddr| addr.ip())
+}
@@ -1,2 +1,3 @@
 pub mod errors;
-pub mod extract_torrent_metadata;
\ No newline at end of file
+pub mod extract_torrent_metadata;
+pub mod local_address;
\ No newline at end of file